@@ -0,0 +1,135 @@
+use crate::BoxClient;
+use reqwest::header::ACCEPT;
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tool_config::AuthMethod;
+
+/// Tokens are refreshed this long before `expires_in` runs out
+const EXPIRY_SKEW: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct CachedToken {
+  access_token: String,
+  refresh_token: Option<String>,
+  expires_at: Option<Instant>,
+}
+
+impl CachedToken {
+  fn is_valid(&self) -> bool {
+    match self.expires_at {
+      Some(expires_at) => Instant::now() + EXPIRY_SKEW < expires_at,
+      None => true,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  access_token: String,
+  expires_in: Option<u64>,
+  refresh_token: Option<String>,
+}
+
+impl BoxClient {
+  /// Attach credentials of the configured auth strategy to the request
+  pub async fn authorize(&self, builder: RequestBuilder) -> Result<RequestBuilder, String> {
+    match self.auth {
+      AuthMethod::Basic => Ok(builder.basic_auth(&self.client, Some(&self.password))),
+      AuthMethod::Bearer => match &self.token {
+        Some(token) => Ok(builder.bearer_auth(token)),
+        None => Err("Bearer auth is configured without a token".to_string()),
+      },
+      AuthMethod::ClientCredentials | AuthMethod::Password => {
+        Ok(builder.bearer_auth(self.access_token().await?))
+      },
+    }
+  }
+
+  /// Cached OAuth2 access token. Requests a new one on expiry, using the refresh token when present
+  pub async fn access_token(&self) -> Result<String, String> {
+    let cached = self.token_cache.lock().unwrap().clone();
+
+    if let Some(token) = &cached {
+      if token.is_valid() {
+        return Ok(token.access_token.clone());
+      }
+    }
+
+    let refreshed = match cached.and_then(|it| it.refresh_token) {
+      Some(refresh_token) => self
+        .request_token(json!({
+          "grant_type": "refresh_token",
+          "client_id": self.client,
+          "client_secret": self.password,
+          "refresh_token": refresh_token,
+        }))
+        .await
+        .ok(),
+      None => None,
+    };
+
+    let token = match refreshed {
+      Some(it) => it,
+      None => self.request_token(self.grant_params()?).await?,
+    };
+
+    let access_token = token.access_token.clone();
+    *self.token_cache.lock().unwrap() = Some(token);
+    Ok(access_token)
+  }
+
+  fn grant_params(&self) -> Result<Value, String> {
+    match self.auth {
+      AuthMethod::ClientCredentials => Ok(json!({
+        "grant_type": "client_credentials",
+        "client_id": self.client,
+        "client_secret": self.password,
+      })),
+      AuthMethod::Password => match (&self.username, &self.user_password) {
+        (Some(username), Some(password)) => Ok(json!({
+          "grant_type": "password",
+          "client_id": self.client,
+          "client_secret": self.password,
+          "username": username,
+          "password": password,
+        })),
+        _ => Err("Password grant is configured without username or password".to_string()),
+      },
+      _ => Err("Auth method doesn't use /auth/token".to_string()),
+    }
+  }
+
+  async fn request_token(&self, params: Value) -> Result<CachedToken, String> {
+    let response = match self
+      .instance
+      .post(format!("{}/auth/token", &self.url))
+      .json(&params)
+      .header(ACCEPT, "application/json")
+      .send()
+      .await
+    {
+      Ok(it) => it,
+      Err(error) => return Err(error.to_string()),
+    };
+
+    match response.status().as_u16() {
+      200..=299 => match response.json::<TokenResponse>().await {
+        Ok(token) => Ok(CachedToken {
+          access_token: token.access_token,
+          refresh_token: token.refresh_token,
+          expires_at: token
+            .expires_in
+            .map(|it| Instant::now() + Duration::from_secs(it)),
+        }),
+        Err(error) => Err(format!("Unexpected /auth/token response: {}", error)),
+      },
+      status => Err(format!(
+        "Token request failed with {}: {}",
+        status,
+        response.text().await.unwrap_or_default()
+      )),
+    }
+  }
+}
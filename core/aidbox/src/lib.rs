@@ -1,16 +1,20 @@
-use regex::RegexSet;
-use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use regex::{Regex, RegexSet};
+use reqwest::header::ACCEPT;
 use reqwest::{Client, Response};
+use rpc::RpcParams;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs;
 use std::future::Future;
 use std::path::PathBuf;
-use tool_config::{BoxConfig, ExcludeConfig};
+use std::sync::{Arc, Mutex};
+use tool_config::{AuthMethod, BoxConfig, ExcludeConfig};
 
-type RpcModel = HashMap<String, Value>;
+mod auth;
+pub mod rest;
+pub mod rpc;
 
 #[derive(Clone)]
 pub struct BoxClient {
@@ -18,24 +22,37 @@ pub struct BoxClient {
   url: String,
   client: String,
   password: String,
+  auth: AuthMethod,
+  token: Option<String>,
+  username: Option<String>,
+  user_password: Option<String>,
+  token_cache: Arc<Mutex<Option<auth::CachedToken>>>,
 }
-#[derive(Deserialize)]
-pub struct RpcResultModel {
-  model: RpcModel,
-}
-#[derive(Deserialize)]
-pub struct RpcResult {
-  result: RpcResultModel,
-}
-
 #[derive(Deserialize, Debug)]
 pub struct RpcNamespaces {
   result: Vec<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Debug)]
 pub struct RpcNamespaceItem {
-  name: String,
+  pub name: String,
+  /// Kept as is, so an unexpected shape can't break symbol loading
+  #[serde(default)]
+  pub tags: Value,
+}
+
+impl RpcNamespaceItem {
+  pub fn tag_names(&self) -> Vec<String> {
+    match &self.tags {
+      Value::Array(tags) => tags
+        .iter()
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect(),
+      Value::String(tag) => vec![tag.to_string()],
+      _ => vec![],
+    }
+  }
 }
 
 #[derive(Deserialize)]
@@ -43,6 +60,27 @@ pub struct RpcNamespace {
   result: Vec<RpcNamespaceItem>,
 }
 
+/// Namespaces of Aidbox internals, FHIR packages and value sets. Skipped by type generation
+pub fn internal_namespaces() -> RegexSet {
+  RegexSet::new([
+    r"^zenbox",
+    r"^lisp",
+    r"aidbox.metrics",
+    r"aidbox.ftr",
+    r"fhir$",
+    r"^zen$",
+    r"^zen.fhir",
+    r"\.value-set\.",
+    r"\.search\.",
+    r"^aidbox.sdc",
+    r"^aidbox.notebooks",
+    r"^aidbox.mock",
+    r"^aidbox.product",
+    r"^aidbox.pg",
+  ])
+  .unwrap()
+}
+
 impl BoxClient {
   pub fn new(config: BoxConfig) -> BoxClient {
     BoxClient {
@@ -50,6 +88,11 @@ impl BoxClient {
       url: config.url,
       client: config.client,
       password: config.secret,
+      auth: config.auth,
+      token: config.token,
+      username: config.username,
+      user_password: config.password,
+      token_cache: Arc::new(Mutex::new(None)),
     }
   }
   pub async fn load_all_symbols(
@@ -68,23 +111,17 @@ impl BoxClient {
       }
     }
 
-    let excluded_namespaces = RegexSet::new([
-      r"^zenbox",
-      r"^lisp",
-      r"aidbox.metrics",
-      r"aidbox.ftr",
-      r"fhir$",
-      r"^zen$",
-      r"^zen.fhir",
-      r"\.value-set\.",
-      r"\.search\.",
-      r"^aidbox.sdc",
-      r"^aidbox.notebooks",
-      r"^aidbox.mock",
-      r"^aidbox.product",
-      r"^aidbox.pg",
-    ])
-    .unwrap();
+    let symbols = self.read_all_symbols(exclude).await?;
+    let _ = serde_json::to_writer(&fs::File::create(target_path.to_str().unwrap())?, &symbols);
+    Ok(symbols)
+  }
+
+  /// Symbols of every non internal namespace, read from the box without the cache
+  pub async fn read_all_symbols(
+    &self,
+    exclude: &ExcludeConfig,
+  ) -> Result<Vec<String>, Box<dyn Error>> {
+    let excluded_namespaces = internal_namespaces();
 
     let excluded_symbols: Vec<String> = vec![
       "aidbox/Configuration".to_string(),
@@ -115,70 +152,115 @@ impl BoxClient {
       None => vec![],
     };
 
-    let req = self
-      .instance
-      .post(format!("{}/rpc", &self.url))
-      .basic_auth(&self.client, Some(&self.password))
-      .body("{:method aidbox.zen/namespaces :params {}}")
-      .header(CONTENT_TYPE, "application/edn")
-      .header(ACCEPT, "application/json")
-      .send();
+    let mut symbols: Vec<String> = Vec::new();
 
-    let source_str = match req.await {
-      Ok(it) => it.text().await?,
-      Err(_) => "Error".to_string(),
+    for item in self
+      .get_namespaces()
+      .await?
+      .into_iter()
+      .filter(|item| !excluded_namespaces.is_match(item))
+      .filter(|item| !user_exclude_ns.contains(item))
+    {
+      for symbol_name in self.get_namespace_symbols(&item).await? {
+        if !excluded_symbols.contains(&symbol_name) && !user_exclude_symbols.contains(&symbol_name)
+        {
+          symbols.push(symbol_name);
+        }
+      }
+    }
+    Ok(symbols)
+  }
+
+  pub async fn load_search_symbols(
+    &self,
+    cache_path: PathBuf,
+    exclude: &ExcludeConfig,
+  ) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut target_path = cache_path.clone();
+    target_path.push("search_symbols.json");
+
+    if target_path.exists() {
+      let json = fs::read_to_string(target_path.to_str().unwrap())?;
+      let data: Vec<String> = serde_json::from_str(&json)?;
+      if !data.is_empty() {
+        return Ok(data);
+      }
+    }
+
+    let search_namespaces = Regex::new(r"\.search\.").unwrap();
+
+    let user_exclude_ns = match &exclude.ns {
+      Some(ns) => ns.to_owned(),
+      None => vec![],
     };
 
-    let namespaces: RpcNamespaces = serde_json::from_str(&source_str)?;
+    let user_exclude_symbols = match &exclude.symbols {
+      Some(sym) => sym.to_owned(),
+      None => vec![],
+    };
 
     let mut symbols: Vec<String> = Vec::new();
 
-    for item in namespaces
-      .result
+    for item in self
+      .get_namespaces()
+      .await?
       .into_iter()
-      .filter(|item| !excluded_namespaces.is_match(item))
+      .filter(|item| search_namespaces.is_match(item))
       .filter(|item| !user_exclude_ns.contains(item))
     {
-      let namespace_req = self
-        .instance
-        .post(format!("{}/rpc", &self.url))
-        .basic_auth(&self.client, Some(&self.password))
-        .body(format!(
-          "{{:method aidbox.zen/symbols :params {{:ns {}}}}}",
-          item
-        ))
-        .header(CONTENT_TYPE, "application/edn")
-        .header(ACCEPT, "application/json")
-        .send();
-
-      let namespace_str = match namespace_req.await {
-        Ok(it) => it.text().await?,
-        Err(..) => "Error".to_string(),
-      };
-
-      if !namespace_str.contains("OperationOutcome") {
-        let namespace_items: RpcNamespace = serde_json::from_str(&namespace_str)?;
-        for sym in namespace_items.result.into_iter() {
-          let symbol_name = format!("{}/{}", item, sym.name);
-          if !excluded_symbols.contains(&symbol_name)
-            && !user_exclude_symbols.contains(&symbol_name)
-          {
-            symbols.push(symbol_name);
-          }
+      for symbol_name in self.get_namespace_symbols(&item).await? {
+        if !user_exclude_symbols.contains(&symbol_name) {
+          symbols.push(symbol_name);
         }
       }
     }
-    if let Ok(..) =
-      serde_json::to_writer(&fs::File::create(target_path.to_str().unwrap())?, &symbols)
-    {};
+    let _ = serde_json::to_writer(&fs::File::create(target_path.to_str().unwrap())?, &symbols);
     Ok(symbols)
   }
 
+  pub async fn get_namespaces(&self) -> Result<Vec<String>, Box<dyn Error>> {
+    let response = self
+      .rpc("aidbox.zen/namespaces", RpcParams::Edn("{}".to_string()))
+      .await?;
+
+    let namespaces: RpcNamespaces = serde_json::from_value(response.body)?;
+    Ok(namespaces.result)
+  }
+
+  async fn get_namespace_symbols(&self, namespace: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(
+      self
+        .get_namespace_items(namespace)
+        .await?
+        .into_iter()
+        .map(|sym| format!("{}/{}", namespace, sym.name))
+        .collect(),
+    )
+  }
+
+  /// Symbols of a namespace with their tags. Empty for unknown namespaces
+  pub async fn get_namespace_items(
+    &self,
+    namespace: &str,
+  ) -> Result<Vec<RpcNamespaceItem>, Box<dyn Error>> {
+    let response = self
+      .rpc(
+        "aidbox.zen/symbols",
+        RpcParams::Edn(format!("{{:ns {}}}", namespace)),
+      )
+      .await?;
+
+    if !response.is_success() || response.is_operation_outcome() {
+      return Ok(vec![]);
+    }
+    let namespace_items: RpcNamespace = serde_json::from_value(response.body)?;
+    Ok(namespace_items.result)
+  }
+
   pub async fn health_check(&self) -> Result<(), String> {
     match self
-      .instance
-      .get(format!("{}/health", &self.url))
-      .basic_auth(&self.client, Some(&self.password))
+      .authorize(self.instance.get(format!("{}/health", &self.url)))
+      .await?
       .send()
       .await
     {
@@ -196,9 +278,8 @@ impl BoxClient {
 
   pub async fn get_user_info(&self) -> Result<Value, String> {
     let result = self
-      .instance
-      .get(format!("{}/auth/userinfo", &self.url))
-      .basic_auth(&self.client, Some(&self.password))
+      .authorize(self.instance.get(format!("{}/auth/userinfo", &self.url)))
+      .await?
       .send();
 
     result_process(result).await
@@ -206,9 +287,8 @@ impl BoxClient {
 
   pub async fn get_box_version(&self) -> Result<Value, String> {
     let result = self
-      .instance
-      .get(format!("{}/$version", &self.url))
-      .basic_auth(&self.client, Some(&self.password))
+      .authorize(self.instance.get(format!("{}/$version", &self.url)))
+      .await?
       .send();
 
     match result.await {
@@ -227,25 +307,21 @@ impl BoxClient {
   }
 
   pub async fn get_symbol(&self, symbol: &str) -> Result<HashMap<String, Value>, Box<dyn Error>> {
-    let req = self
-      .instance
-      .post(format!("{}/rpc", &self.url))
-      .basic_auth(&self.client, Some(&self.password))
-      .body(format!(
-        "{{:method aidbox.zen/symbol :params {{ :name {}}}}}",
-        symbol
-      ))
-      .header(CONTENT_TYPE, "application/edn")
-      .header(ACCEPT, "application/json")
-      .send();
-
-    let source_str = match req.await {
-      Ok(it) => it.text().await?,
-      _ => "Error".to_string(),
-    };
-    let result: RpcResult = serde_json::from_str(&source_str)?;
+    Ok(self.read_symbol(symbol).await?.into_iter().collect())
+  }
 
-    Ok(result.result.model)
+  /// Symbol definition with keys in the order the box returned them
+  pub async fn read_symbol(&self, symbol: &str) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let response = self
+      .rpc(
+        "aidbox.zen/symbol",
+        RpcParams::Edn(format!("{{:name {}}}", symbol)),
+      )
+      .await?;
+    match response.body.pointer("/result/model") {
+      Some(Value::Object(model)) => Ok(model.clone()),
+      _ => Err(format!("Unexpected aidbox.zen/symbol response: {}", response.body).into()),
+    }
   }
   pub async fn get_concept(&self, symbol: &str) -> Result<Vec<String>, Box<dyn Error>> {
     let definition = self.get_symbol(symbol).await?;
@@ -260,9 +336,8 @@ impl BoxClient {
     );
 
     let concept_req = self
-      .instance
-      .post(format!("{}/$psql", &self.url,))
-      .basic_auth(&self.client, Some(&self.password))
+      .authorize(self.instance.post(format!("{}/$psql", &self.url,)))
+      .await?
       .json(&map)
       .header(ACCEPT, "application/json")
       .send();
@@ -276,7 +351,7 @@ impl BoxClient {
       return Ok(result);
     }
     let concept_result: Value = serde_json::from_str(&concept_str)?;
-    let inner = concept_result.as_array().unwrap().get(0).unwrap();
+    let inner = concept_result.as_array().unwrap().first().unwrap();
 
     if inner.get("result").is_some() {
       let result: Vec<_> = inner
@@ -0,0 +1,548 @@
+use crate::BoxClient;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, ETAG, IF_MATCH};
+use reqwest::{Method, RequestBuilder};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// Which REST API of the box to talk to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ApiFormat {
+  /// Aidbox native format on `/`
+  Aidbox,
+  /// FHIR format on `/fhir`
+  Fhir,
+}
+
+impl ApiFormat {
+  pub fn base(&self) -> &'static str {
+    match self {
+      ApiFormat::Aidbox => "",
+      ApiFormat::Fhir => "/fhir",
+    }
+  }
+
+  /// FHIR expects a weak ETag in `If-Match`, Aidbox takes the bare version id
+  pub fn if_match(&self, version: &str) -> String {
+    match self {
+      ApiFormat::Fhir if !version.starts_with("W/") => {
+        format!("W/\"{}\"", version.trim_matches('"'))
+      },
+      _ => version.to_string(),
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatchKind {
+  JsonPatch,
+  MergePatch,
+}
+
+#[derive(Clone, Debug)]
+pub struct RestResponse {
+  pub status: u16,
+  pub etag: Option<String>,
+  pub body: Value,
+}
+
+impl RestResponse {
+  pub fn is_success(&self) -> bool {
+    (200..300).contains(&self.status)
+  }
+
+  pub fn is_operation_outcome(&self) -> bool {
+    self.body.get("resourceType").and_then(Value::as_str) == Some("OperationOutcome")
+  }
+}
+
+/// `link` with `relation: next` of a search Bundle
+pub fn next_link(bundle: &Value) -> Option<String> {
+  bundle
+    .get("link")
+    .and_then(Value::as_array)?
+    .iter()
+    .find(|link| link.get("relation").and_then(Value::as_str) == Some("next"))
+    .and_then(|link| link.get("url"))
+    .and_then(Value::as_str)
+    .map(str::to_string)
+}
+
+/// `entry[].resource` of a Bundle
+pub fn bundle_resources(bundle: &Value) -> Vec<Value> {
+  match bundle.get("entry").and_then(Value::as_array) {
+    Some(entries) => entries
+      .iter()
+      .filter_map(|entry| entry.get("resource"))
+      .cloned()
+      .collect(),
+    None => vec![],
+  }
+}
+
+/// Bundle `entry.request` for a resource: `PUT Type/id` when it has an id, `POST Type` otherwise
+pub fn entry_request(resource: &Value, default_type: Option<&str>) -> Result<Value, String> {
+  let resource_type = match resource
+    .get("resourceType")
+    .and_then(Value::as_str)
+    .or(default_type)
+  {
+    Some(it) => it,
+    None => return Err("resourceType is missing".to_string()),
+  };
+  Ok(match resource.get("id").and_then(Value::as_str) {
+    Some(id) => serde_json::json!({"method": "PUT", "url": format!("{}/{}", resource_type, id)}),
+    None => serde_json::json!({"method": "POST", "url": resource_type}),
+  })
+}
+
+/// Response entry status from `entry.response.status` ("201 Created") or Aidbox `entry.status`
+pub fn entry_status(entry: &Value) -> Option<u16> {
+  let status = entry
+    .get("response")
+    .and_then(|it| it.get("status"))
+    .or_else(|| entry.get("status"))?;
+  match status {
+    Value::Number(number) => number.as_u64().map(|it| it as u16),
+    Value::String(text) => text.split_whitespace().next()?.parse().ok(),
+    _ => None,
+  }
+}
+
+/// `Patient/123` -> (`Patient`, `123`)
+pub fn parse_reference(reference: &str) -> Result<(String, String), String> {
+  match reference.trim_matches('/').split_once('/') {
+    Some((resource_type, id))
+      if !resource_type.is_empty() && !id.is_empty() && !id.contains('/') =>
+    {
+      Ok((resource_type.to_string(), id.to_string()))
+    },
+    _ => Err(format!(
+      "Expected reference in form <ResourceType>/<id>, got '{}'",
+      reference
+    )),
+  }
+}
+
+impl BoxClient {
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  /// Authorized request to a path relative to the box url
+  pub async fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, String> {
+    self
+      .authorize(self.instance.request(
+        method,
+        format!("{}{}", self.url.trim_end_matches('/'), path),
+      ))
+      .await
+  }
+
+  /// Send a request and read the JSON body. Empty bodies become `null`, non JSON bodies a string
+  pub async fn send(&self, builder: RequestBuilder) -> Result<RestResponse, String> {
+    let response = match builder.header(ACCEPT, "application/json").send().await {
+      Ok(it) => it,
+      Err(error) => return Err(error.to_string()),
+    };
+    let status = response.status().as_u16();
+    let etag = response
+      .headers()
+      .get(ETAG)
+      .and_then(|it| it.to_str().ok())
+      .map(str::to_string);
+    let text = match response.text().await {
+      Ok(it) => it,
+      Err(error) => return Err(error.to_string()),
+    };
+    let body = match text.trim().is_empty() {
+      true => Value::Null,
+      false => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+    };
+
+    Ok(RestResponse { status, etag, body })
+  }
+
+  pub async fn search(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    params: &[(String, String)],
+  ) -> Result<RestResponse, String> {
+    let path = format!("{}/{}", format.base(), resource_type);
+    self
+      .send(self.request(Method::GET, &path).await?.query(params))
+      .await
+  }
+
+  /// Runs a search and passes every page body to `on_page`, following `next` links or Aidbox
+  /// `_page` while pages are full. Stops when `on_page` returns `false`. A failed page is
+  /// returned as is
+  pub async fn search_pages<F>(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    params: &[(String, String)],
+    count: usize,
+    mut on_page: F,
+  ) -> Result<Option<RestResponse>, String>
+  where
+    F: FnMut(&Value) -> bool,
+  {
+    let mut seen_links = HashSet::new();
+    let mut page = 1;
+    let mut response = self.search(format, resource_type, params).await?;
+    loop {
+      if !response.is_success() {
+        return Ok(Some(response));
+      }
+      let page_size = bundle_resources(&response.body).len();
+      if !on_page(&response.body) {
+        break;
+      }
+
+      page += 1;
+      response = match next_link(&response.body) {
+        Some(link) if !seen_links.insert(link.clone()) => break,
+        Some(link) => self.follow(&link).await?,
+        None if format == ApiFormat::Aidbox && page_size >= count && page_size > 0 => {
+          let mut page_params = params.to_vec();
+          page_params.retain(|(key, _)| key != "_page");
+          page_params.push(("_page".to_string(), page.to_string()));
+          self.search(format, resource_type, &page_params).await?
+        },
+        None => break,
+      };
+    }
+    Ok(None)
+  }
+
+  /// Every resource of a type, following `next` links or Aidbox `_page` while pages are full
+  pub async fn search_all(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    count: usize,
+  ) -> Result<Vec<Value>, String> {
+    let params = vec![("_count".to_string(), count.to_string())];
+    let mut resources = vec![];
+    let failed = self
+      .search_pages(format, resource_type, &params, count, |body| {
+        resources.extend(bundle_resources(body));
+        true
+      })
+      .await?;
+    match failed {
+      Some(response) => Err(format!(
+        "Search {} failed with HTTP {}: {}",
+        resource_type, response.status, response.body
+      )),
+      None => Ok(resources),
+    }
+  }
+
+  /// Whether an absolute url has the scheme, host and port of the configured url. A prefix
+  /// check would also accept `https://box.example.com.evil.io` for `https://box.example.com`
+  pub fn is_box_url(&self, link: &str) -> bool {
+    match (reqwest::Url::parse(link), reqwest::Url::parse(&self.url)) {
+      (Ok(url), Ok(base)) => {
+        url.scheme() == base.scheme()
+          && url.host_str() == base.host_str()
+          && url.port_or_known_default() == base.port_or_known_default()
+      },
+      _ => false,
+    }
+  }
+
+  /// Authorized request to a link returned by the box. Absolute links to another host
+  /// (e.g. behind a proxy) are resolved against the configured url
+  pub async fn request_link(&self, method: Method, link: &str) -> Result<RequestBuilder, String> {
+    match reqwest::Url::parse(link) {
+      Ok(url) if !self.is_box_url(link) => {
+        let path = match url.query() {
+          Some(query) => format!("{}?{}", url.path(), query),
+          None => url.path().to_string(),
+        };
+        self.request(method, &path).await
+      },
+      Ok(..) => self.authorize(self.instance.request(method, link)).await,
+      Err(..) => {
+        self
+          .request(method, &format!("/{}", link.trim_start_matches('/')))
+          .await
+      },
+    }
+  }
+
+  /// Follow a Bundle link
+  pub async fn follow(&self, link: &str) -> Result<RestResponse, String> {
+    self.send(self.request_link(Method::GET, link).await?).await
+  }
+
+  pub async fn read_resource(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    id: &str,
+  ) -> Result<RestResponse, String> {
+    let path = format!("{}/{}/{}", format.base(), resource_type, id);
+    self.send(self.request(Method::GET, &path).await?).await
+  }
+
+  pub async fn create_resource(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    resource: &Value,
+  ) -> Result<RestResponse, String> {
+    let path = format!("{}/{}", format.base(), resource_type);
+    self
+      .send(self.request(Method::POST, &path).await?.json(resource))
+      .await
+  }
+
+  pub async fn update_resource(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    id: &str,
+    resource: &Value,
+    if_match: Option<&str>,
+  ) -> Result<RestResponse, String> {
+    let path = format!("{}/{}/{}", format.base(), resource_type, id);
+    let mut builder = self.request(Method::PUT, &path).await?.json(resource);
+    if let Some(version) = if_match {
+      builder = builder.header(IF_MATCH, format.if_match(version));
+    }
+    self.send(builder).await
+  }
+
+  pub async fn patch_resource(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    id: &str,
+    patch: &Value,
+    kind: PatchKind,
+    if_match: Option<&str>,
+  ) -> Result<RestResponse, String> {
+    let path = match (format, kind) {
+      // Aidbox picks the patch flavour from `_method`, FHIR from the content type
+      (ApiFormat::Aidbox, PatchKind::JsonPatch) => {
+        format!("/{}/{}?_method=json-patch", resource_type, id)
+      },
+      _ => format!("{}/{}/{}", format.base(), resource_type, id),
+    };
+    let content_type = match kind {
+      PatchKind::JsonPatch => "application/json-patch+json",
+      PatchKind::MergePatch => "application/merge-patch+json",
+    };
+    let mut builder = self
+      .request(Method::PATCH, &path)
+      .await?
+      .header(CONTENT_TYPE, content_type)
+      .body(patch.to_string());
+    if let Some(version) = if_match {
+      builder = builder.header(IF_MATCH, format.if_match(version));
+    }
+    self.send(builder).await
+  }
+
+  pub async fn delete_resource(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    id: &str,
+    if_match: Option<&str>,
+  ) -> Result<RestResponse, String> {
+    let path = format!("{}/{}/{}", format.base(), resource_type, id);
+    let mut builder = self.request(Method::DELETE, &path).await?;
+    if let Some(version) = if_match {
+      builder = builder.header(IF_MATCH, format.if_match(version));
+    }
+    self.send(builder).await
+  }
+
+  /// POST a `transaction` or `batch` Bundle to the base endpoint
+  pub async fn transaction(
+    &self,
+    format: ApiFormat,
+    bundle: &Value,
+  ) -> Result<RestResponse, String> {
+    let path = match format {
+      ApiFormat::Aidbox => "/",
+      ApiFormat::Fhir => "/fhir",
+    };
+    self
+      .send(self.request(Method::POST, path).await?.json(bundle))
+      .await
+  }
+
+  /// `$validate` a resource without storing it. The body is an OperationOutcome
+  pub async fn validate_resource(
+    &self,
+    format: ApiFormat,
+    resource_type: &str,
+    resource: &Value,
+  ) -> Result<RestResponse, String> {
+    let path = format!("{}/{}/$validate", format.base(), resource_type);
+    self
+      .send(self.request(Method::POST, &path).await?.json(resource))
+      .await
+  }
+
+  /// Evaluate AccessPolicies for a simulated request with `$policy-debug`
+  pub async fn policy_debug(&self, request: &Value) -> Result<RestResponse, String> {
+    self
+      .send(
+        self
+          .request(Method::POST, "/$policy-debug")
+          .await?
+          .json(&serde_json::json!({ "request": request })),
+      )
+      .await
+  }
+
+  /// Run SQL with `$psql`. The body is a list with one result per statement
+  pub async fn psql(&self, query: &str) -> Result<RestResponse, String> {
+    self
+      .send(
+        self
+          .request(Method::POST, "/$psql")
+          .await?
+          .json(&serde_json::json!({ "query": query })),
+      )
+      .await
+  }
+
+  /// Aidbox `$load` of an NDJSON(.gz) file reachable by the box. Without a resource type
+  /// every line must have `resourceType`
+  pub async fn load(
+    &self,
+    source: &str,
+    resource_type: Option<&str>,
+  ) -> Result<RestResponse, String> {
+    let path = match resource_type {
+      Some(resource_type) => format!("/{}/$load", resource_type),
+      None => "/$load".to_string(),
+    };
+    self
+      .send(
+        self
+          .request(Method::POST, &path)
+          .await?
+          .json(&serde_json::json!({ "source": source })),
+      )
+      .await
+  }
+
+  /// FHIR `$import` of NDJSON files reachable by the box. Inputs are (resource type, url)
+  pub async fn bulk_import(
+    &self,
+    id: &str,
+    inputs: &[(String, String)],
+  ) -> Result<RestResponse, String> {
+    let gzip = inputs.iter().all(|(_, url)| url.ends_with(".gz"));
+    let mut body = serde_json::json!({
+      "id": id,
+      "inputs": inputs
+        .iter()
+        .map(|(resource_type, url)| serde_json::json!({"resourceType": resource_type, "url": url}))
+        .collect::<Vec<_>>(),
+    });
+    if gzip {
+      body["contentEncoding"] = Value::String("gzip".to_string());
+    }
+    self
+      .send(
+        self
+          .request(Method::POST, "/fhir/$import")
+          .await?
+          .json(&body),
+      )
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_reference() {
+    assert_eq!(
+      parse_reference("Patient/123").unwrap(),
+      ("Patient".to_string(), "123".to_string())
+    );
+    assert!(parse_reference("Patient").is_err());
+    assert!(parse_reference("Patient/123/_history/1").is_err());
+  }
+
+  #[test]
+  fn test_next_link() {
+    let bundle = serde_json::json!({
+      "resourceType": "Bundle",
+      "entry": [{"resource": {"id": "1"}}, {"fullUrl": "Patient/2"}],
+      "link": [
+        {"relation": "self", "url": "/Patient?_page=1"},
+        {"relation": "next", "url": "/Patient?_page=2"}
+      ]
+    });
+    assert_eq!(next_link(&bundle), Some("/Patient?_page=2".to_string()));
+    assert_eq!(bundle_resources(&bundle).len(), 1);
+    assert_eq!(next_link(&serde_json::json!({"link": []})), None);
+  }
+
+  #[test]
+  fn test_entry_request() {
+    assert_eq!(
+      entry_request(
+        &serde_json::json!({"resourceType": "Patient", "id": "1"}),
+        None
+      )
+      .unwrap(),
+      serde_json::json!({"method": "PUT", "url": "Patient/1"})
+    );
+    assert_eq!(
+      entry_request(&serde_json::json!({}), Some("Patient")).unwrap(),
+      serde_json::json!({"method": "POST", "url": "Patient"})
+    );
+    assert!(entry_request(&serde_json::json!({"id": "1"}), None).is_err());
+  }
+
+  #[test]
+  fn test_entry_status() {
+    assert_eq!(
+      entry_status(&serde_json::json!({"response": {"status": "201 Created"}})),
+      Some(201)
+    );
+    assert_eq!(entry_status(&serde_json::json!({"status": 422})), Some(422));
+    assert_eq!(entry_status(&serde_json::json!({})), None);
+  }
+
+  #[test]
+  fn test_if_match() {
+    assert_eq!(ApiFormat::Fhir.if_match("2"), "W/\"2\"");
+    assert_eq!(ApiFormat::Fhir.if_match("W/\"2\""), "W/\"2\"");
+    assert_eq!(ApiFormat::Aidbox.if_match("2"), "2");
+  }
+
+  #[test]
+  fn test_is_box_url() {
+    let client = BoxClient::new(tool_config::BoxConfig {
+      key: "dev".to_string(),
+      url: "https://box.example.com".to_string(),
+      client: "root".to_string(),
+      secret: "secret".to_string(),
+      auth: Default::default(),
+      token: None,
+      username: None,
+      password: None,
+      tags: None,
+    });
+    assert!(client.is_box_url("https://box.example.com/fhir/Binary/1"));
+    assert!(client.is_box_url("https://box.example.com:443/fhir/Binary/1"));
+    assert!(!client.is_box_url("https://box.example.com.evil.io/fhir/Binary/1"));
+    assert!(!client.is_box_url("http://box.example.com/fhir/Binary/1"));
+    assert!(!client.is_box_url("Binary/1"));
+  }
+}
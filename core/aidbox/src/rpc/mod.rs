@@ -0,0 +1,56 @@
+use crate::rest::RestResponse;
+use crate::BoxClient;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Method;
+use serde_json::Value;
+
+/// Parameters of an RPC call. EDN is sent as is, so symbols and keywords survive
+#[derive(Clone, Debug)]
+pub enum RpcParams {
+  Edn(String),
+  Json(Value),
+}
+
+impl RpcParams {
+  /// JSON when the source parses as JSON, EDN otherwise
+  pub fn parse(source: &str) -> RpcParams {
+    match serde_json::from_str::<Value>(source) {
+      Ok(value) => RpcParams::Json(value),
+      Err(..) => RpcParams::Edn(source.trim().to_string()),
+    }
+  }
+}
+
+impl BoxClient {
+  /// Call `/rpc`. The body holds `result` on success and `error` otherwise
+  pub async fn rpc(&self, method: &str, params: RpcParams) -> Result<RestResponse, String> {
+    let builder = self.request(Method::POST, "/rpc").await?;
+    let builder = match params {
+      RpcParams::Edn(params) => builder
+        .header(CONTENT_TYPE, "application/edn")
+        .body(format!("{{:method {} :params {}}}", method, params)),
+      RpcParams::Json(params) => builder.json(&serde_json::json!({
+        "method": method,
+        "params": params,
+      })),
+    };
+    self.send(builder).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_params() {
+    assert!(matches!(
+      RpcParams::parse("{\"ns\": \"aidbox\"}"),
+      RpcParams::Json(..)
+    ));
+    assert!(matches!(
+      RpcParams::parse("{:ns aidbox}"),
+      RpcParams::Edn(..)
+    ));
+  }
+}
@@ -11,11 +11,54 @@ pub struct ExcludeConfig {
   pub tags: Option<Vec<String>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+  /// HTTP basic auth with client id and secret
+  #[default]
+  Basic,
+  /// Static bearer token
+  Bearer,
+  /// OAuth2 `client_credentials` grant against `/auth/token`
+  ClientCredentials,
+  /// OAuth2 `password` grant against `/auth/token`
+  Password,
+}
+
+impl AuthMethod {
+  pub const ALL: [AuthMethod; 4] = [
+    AuthMethod::Basic,
+    AuthMethod::Bearer,
+    AuthMethod::ClientCredentials,
+    AuthMethod::Password,
+  ];
+
+  #[must_use]
+  pub fn title(&self) -> &'static str {
+    match self {
+      AuthMethod::Basic => "Basic (client id + secret)",
+      AuthMethod::Bearer => "Static bearer token",
+      AuthMethod::ClientCredentials => "OAuth2 client_credentials",
+      AuthMethod::Password => "OAuth2 password grant",
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct BoxInstance {
   pub url: String,
   pub client: String,
   pub secret: String,
+  #[serde(default)]
+  pub auth: AuthMethod,
+  /// Token for `bearer` auth
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub token: Option<String>,
+  /// User credentials for `password` auth
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub username: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub password: Option<String>,
   pub status: bool,
   #[serde(default = "default_status_message")]
   pub status_message: String,
@@ -41,6 +84,14 @@ pub struct BoxConfig {
   pub url: String,
   pub client: String,
   pub secret: String,
+  #[serde(default)]
+  pub auth: AuthMethod,
+  #[serde(default)]
+  pub token: Option<String>,
+  #[serde(default)]
+  pub username: Option<String>,
+  #[serde(default)]
+  pub password: Option<String>,
   pub tags: Option<Vec<String>>,
 }
 
@@ -52,6 +103,10 @@ impl BoxInstance {
       url: self.url,
       client: self.client,
       secret: self.secret,
+      auth: self.auth,
+      token: self.token,
+      username: self.username,
+      password: self.password,
       tags: self.tags,
     }
   }
@@ -159,7 +214,7 @@ pub fn get_config_or_error(instance: &str) -> Result<(Config, &str), String> {
       return Err(err);
     },
   };
-  if config.boxes.get(instance).is_none() {
+  if !config.boxes.contains_key(instance) {
     Err(format!("Instance '{}' doesn't exist", instance))
   } else {
     Ok((config, instance))
@@ -220,6 +275,21 @@ mod tests {
       Err(e) => println!("Error wtf: {}", e),
     }
   }
+  #[test]
+  fn auth_method_defaults_to_basic() {
+    let boxes: HashMap<String, BoxInstance> = toml::from_str(
+      "[dev]\nurl = \"http://localhost:8888\"\nclient = \"root\"\nsecret = \"secret\"\nstatus = true\n",
+    )
+    .unwrap();
+    assert_eq!(boxes["dev"].auth, AuthMethod::Basic);
+
+    let boxes: HashMap<String, BoxInstance> = toml::from_str(
+      "[dev]\nurl = \"http://localhost:8888\"\nclient = \"root\"\nsecret = \"secret\"\nauth = \"client_credentials\"\nstatus = true\n",
+    )
+    .unwrap();
+    assert_eq!(boxes["dev"].auth, AuthMethod::ClientCredentials);
+  }
+
   #[test]
   fn add_box_into_config() {
     let config = Config::new(Some("/tmp/aidbox-tool2".to_string()));
@@ -236,6 +306,10 @@ mod tests {
         url: "test".to_string(),
         client: "test".to_string(),
         secret: "test".to_string(),
+        auth: AuthMethod::Basic,
+        token: None,
+        username: None,
+        password: None,
         status: true,
         status_message: "".to_string(),
         last_checked: None,
@@ -243,10 +317,10 @@ mod tests {
       },
       "".to_string(),
     );
-    assert!(config_ok.boxes.get(&"test".to_string()).is_some());
-    assert!(config_ok.boxes.get(&"test2".to_string()).is_none());
+    assert!(config_ok.boxes.contains_key("test"));
+    assert!(!config_ok.boxes.contains_key("test2"));
 
-    match fs::remove_dir_all(PathBuf::from("/tmp/aidbox-tool-test2")) {
+    match fs::remove_dir_all(PathBuf::from("/tmp/aidbox-tool2")) {
       Ok(_) => println!("cleared!"),
       Err(e) => println!("Error wtf: {}", e),
     }
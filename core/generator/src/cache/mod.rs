@@ -14,6 +14,8 @@ pub struct Cache {
   pub confirms: HashMap<String, Value>,
   pub value_sets: HashMap<String, Vec<String>>,
   pub schema: HashMap<String, HashMap<String, Value>>,
+  /// Symbol to type name overrides. Not persisted
+  pub renames: HashMap<String, String>,
   pub cache_path: PathBuf,
 }
 
@@ -57,6 +59,7 @@ impl Cache {
       confirms: HashMap::new(),
       value_sets: HashMap::new(),
       schema: HashMap::new(),
+      renames: HashMap::new(),
       cache_path,
     })
   }
@@ -0,0 +1,122 @@
+use itertools::Itertools;
+use std::collections::HashMap;
+use tool_common::{capitalize, kebab_to_camel};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CollisionStrategy {
+  Merge,
+  Prefix,
+  Error,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NameOrigin {
+  pub symbol: String,
+  /// Name comes from `zen.fhir/type`, `resourceType` or the rename map rather than the symbol path
+  pub explicit: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Collision {
+  pub name: String,
+  pub origins: Vec<NameOrigin>,
+}
+
+impl Collision {
+  pub fn report(&self) -> String {
+    format!(
+      "Name collision `{}`: {}",
+      self.name,
+      self.origins.iter().map(|it| it.symbol.as_str()).join(", ")
+    )
+  }
+}
+
+/// Names shared by several symbols where at least one of them got the name from its path.
+/// Symbols which all declare the same type explicitly (profiles of one resource) are related
+/// and are expected to be merged.
+pub fn find_collisions(origins: &HashMap<String, Vec<NameOrigin>>) -> Vec<Collision> {
+  origins
+    .iter()
+    .filter(|(_, items)| {
+      items.iter().map(|it| &it.symbol).unique().count() > 1 && items.iter().any(|it| !it.explicit)
+    })
+    .map(|(name, items)| Collision {
+      name: name.to_string(),
+      origins: items.iter().unique_by(|it| &it.symbol).cloned().collect(),
+    })
+    .sorted_by(|a, b| a.name.cmp(&b.name))
+    .collect()
+}
+
+/// `aidbox.repository.v1/schema` -> `AidboxRepositoryV1`, `my.app/invoice-line` -> `MyAppInvoiceLine`
+pub fn namespace_name(symbol: &str) -> String {
+  let (ns, name) = symbol.split_once('/').unwrap_or((symbol, "schema"));
+  let mut parts: Vec<String> = ns
+    .split('.')
+    .map(|it| capitalize(&kebab_to_camel(it)))
+    .collect();
+  if name != "schema" {
+    parts.push(capitalize(&kebab_to_camel(name)));
+  }
+  parts.join("")
+}
+
+/// Renames for the prefix strategy: every symbol with a path derived name gets its namespace name
+pub fn prefix_renames(collisions: &[Collision]) -> HashMap<String, String> {
+  collisions
+    .iter()
+    .flat_map(|it| it.origins.iter())
+    .filter(|it| !it.explicit)
+    .map(|it| (it.symbol.clone(), namespace_name(&it.symbol)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn origin(symbol: &str, explicit: bool) -> NameOrigin {
+    NameOrigin {
+      symbol: symbol.to_string(),
+      explicit,
+    }
+  }
+
+  #[test]
+  fn test_namespace_name() {
+    assert_eq!(
+      namespace_name("aidbox.repository.v1/schema"),
+      "AidboxRepositoryV1"
+    );
+    assert_eq!(namespace_name("my.app/invoice-line"), "MyAppInvoiceLine");
+  }
+
+  #[test]
+  fn test_find_collisions() {
+    let mut origins = HashMap::new();
+    origins.insert(
+      "Patient".to_string(),
+      vec![
+        origin("hl7-fhir-r4-core.Patient/schema", true),
+        origin("hl7-fhir-us-core.us-core-patient/schema", true),
+      ],
+    );
+    origins.insert(
+      "schema".to_string(),
+      vec![
+        origin("foo.schema/schema", false),
+        origin("bar.schema/schema", false),
+      ],
+    );
+    origins.insert("Single".to_string(), vec![origin("single/schema", false)]);
+
+    let collisions = find_collisions(&origins);
+    assert_eq!(collisions.len(), 1);
+    assert_eq!(collisions[0].name, "schema");
+
+    let renames = prefix_renames(&collisions);
+    assert_eq!(renames.get("foo.schema/schema").unwrap(), "FooSchema");
+    assert_eq!(renames.get("bar.schema/schema").unwrap(), "BarSchema");
+  }
+}
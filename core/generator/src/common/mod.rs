@@ -15,6 +15,32 @@ impl ElementWrapper {
   }
 }
 
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct ElementSource {
+  pub symbol: String,
+  pub profile_url: Option<String>,
+  pub deprecated: bool,
+  /// Key path inside the symbol for nested fields, `name.given`
+  #[serde(default)]
+  pub path: Option<String>,
+}
+
+/// `min..max` of a key. `max` is `None` when the key is unbounded
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Cardinality {
+  pub min: u64,
+  pub max: Option<u64>,
+}
+
+impl std::fmt::Display for Cardinality {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.max {
+      Some(max) => write!(f, "{}..{}", self.min, max),
+      None => write!(f, "{}..*", self.min),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
 pub struct Element {
   pub is_rpc: bool,
@@ -25,6 +51,16 @@ pub struct Element {
   pub plain: Option<String>,
   pub schema: Option<HashMap<String, ElementSchema>>,
   pub values: Option<Vec<String>>,
+  #[serde(default)]
+  pub source: Option<ElementSource>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct SearchParam {
+  pub name: String,
+  pub kind: String,
+  pub description: Option<String>,
+  pub resources: Vec<String>,
 }
 
 #[derive(Serialize, Clone, Eq, PartialEq, Deserialize, Debug)]
@@ -37,6 +73,10 @@ pub struct ElementSchema {
   pub sub_type: Option<HashMap<String, ElementSchema>>,
   pub plain_type: Option<String>,
   pub values: Option<Vec<String>>,
+  #[serde(default)]
+  pub cardinality: Option<Cardinality>,
+  #[serde(default)]
+  pub source: Option<ElementSource>,
 }
 
 pub fn deep_merge_element_schema(
@@ -57,7 +97,7 @@ pub fn deep_merge_element_schema(
       .collect();
 
     for (key, value) in left {
-      match right.get(key.as_str()).is_some() {
+      match right.contains_key(key.as_str()) {
         true => {
           let element = right.get(key.as_str()).unwrap().to_owned();
           if value == element {
@@ -99,6 +139,8 @@ pub fn deep_merge_element_schema(
                   },
                   None => element.values,
                 },
+                cardinality: element.cardinality,
+                source: element.source,
               },
             );
           }
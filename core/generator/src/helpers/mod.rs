@@ -1,4 +1,5 @@
 use crate::cache::Cache;
+use crate::common::{Cardinality, ElementSchema, ElementSource};
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
@@ -11,8 +12,8 @@ pub async fn get_symbol(
   symbol: &String,
 ) -> Result<HashMap<String, Value>, String> {
   let exist = cache.schema.get(symbol);
-  if exist.is_some() {
-    Ok(exist.unwrap().clone())
+  if let Some(exist) = exist {
+    Ok(exist.clone())
   } else {
     let definition = match box_instance.get_symbol(symbol).await {
       Ok(it) => it,
@@ -29,8 +30,8 @@ pub async fn get_value_set(
   symbol: &str,
 ) -> Result<Vec<String>, Box<dyn Error>> {
   let exist = cache.value_sets.get(symbol);
-  if exist.is_some() {
-    Ok(exist.unwrap().to_owned())
+  if let Some(exist) = exist {
+    Ok(exist.to_owned())
   } else {
     let definition = box_instance.get_concept(symbol).await?;
     cache
@@ -68,8 +69,10 @@ async fn base_confirm_iter(
 
   for confirm in confirms.into_iter() {
     let exist = cache.confirms.get(confirm);
-    if exist.is_some() {
-      result.insert(exist.unwrap().as_str().unwrap().to_string());
+    if let Some(rename) = cache.renames.get(confirm) {
+      result.insert(rename.to_string());
+    } else if let Some(exist) = exist {
+      result.insert(exist.as_str().unwrap().to_string());
     } else {
       let element = match cache.schema.get(confirm) {
         None => {
@@ -80,7 +83,7 @@ async fn base_confirm_iter(
         Some(it) => it.to_owned(),
       };
 
-      if element.get("fhir/polymorphic").is_none() {
+      if !element.contains_key("fhir/polymorphic") {
         let name = get_name(&element);
         cache
           .confirms
@@ -123,8 +126,19 @@ pub fn convert_primitive(val: &str) -> String {
   }
 }
 
+/// Alias used for a FHIR primitive when primitives are not collapsed to `string`/`number`.
+pub fn refined_primitive(name: &str) -> Option<&'static str> {
+  match name {
+    "date" => Some("FhirDate"),
+    "dateTime" | "instant" => Some("FhirDateTime"),
+    "integer" | "positiveInt" | "unsignedInt" => Some("FhirInteger"),
+    "id" => Some("Id"),
+    _ => None,
+  }
+}
+
 pub fn is_persistent_any(definition: &HashMap<String, Value>) -> bool {
-  return (definition.get("validation-type").is_some()
+  (definition.get("validation-type").is_some()
     && definition.get("validation-type").unwrap().as_str().unwrap() == "open")
     || (definition.get("values").is_some()
       && definition.get("values").unwrap().get("type").is_some()
@@ -135,12 +149,11 @@ pub fn is_persistent_any(definition: &HashMap<String, Value>) -> bool {
         .unwrap()
         .as_str()
         .unwrap()
-        == "zen/any");
+        == "zen/any")
 }
 
 pub fn is_type_and_not_map(definition: &HashMap<String, Value>) -> bool {
-  return definition.get("type").is_some()
-    && definition.get("type").unwrap().as_str().unwrap() != "zen/map";
+  definition.get("type").is_some() && definition.get("type").unwrap().as_str().unwrap() != "zen/map"
 }
 
 #[macro_export]
@@ -225,7 +238,7 @@ pub async fn init_confirms_value(
 }
 
 pub fn normalize_confirms(confirms: &[String], resource_name: &str) -> Option<Vec<String>> {
-  return if confirms.is_empty() || (confirms.len() == 1 && confirms[0].as_str() == resource_name) {
+  if confirms.is_empty() || (confirms.len() == 1 && confirms[0].as_str() == resource_name) {
     None
   } else {
     let filtered: Vec<String> = confirms
@@ -238,7 +251,7 @@ pub fn normalize_confirms(confirms: &[String], resource_name: &str) -> Option<Ve
       true => None,
       false => Some(filtered),
     }
-  };
+  }
 }
 
 pub fn zen_path_to_name(def: &Value) -> String {
@@ -247,12 +260,18 @@ pub fn zen_path_to_name(def: &Value) -> String {
   if v[1] != "schema" {
     return kebab_to_camel(v[1]);
   }
-  return if !v[0].is_empty() {
+  if !v[0].is_empty() {
     let ns_parts: Vec<_> = v[0].split('.').collect();
     kebab_to_camel(ns_parts.last().unwrap())
   } else {
     "unknown-name".to_string()
-  };
+  }
+}
+
+pub fn has_explicit_name(cache: &Cache, symbol: &str, element: &HashMap<String, Value>) -> bool {
+  cache.renames.contains_key(symbol)
+    || element.contains_key("zen.fhir/type")
+    || element.contains_key("resourceType")
 }
 
 pub fn get_name(element: &HashMap<String, Value>) -> String {
@@ -265,6 +284,67 @@ pub fn get_name(element: &HashMap<String, Value>) -> String {
   zen_path_to_name(&element["zen/name"])
 }
 
+pub fn get_source(symbol: &str, definition: &HashMap<String, Value>) -> ElementSource {
+  ElementSource {
+    symbol: symbol.to_string(),
+    profile_url: definition
+      .get("zen.fhir/profileUri")
+      .and_then(Value::as_str)
+      .map(str::to_string),
+    deprecated: match definition.get("zen/deprecated") {
+      Some(Value::Bool(it)) => *it,
+      Some(Value::Null) | None => false,
+      Some(..) => true,
+    },
+    path: None,
+  }
+}
+
+/// Nested keys point back to the symbol they were read from
+pub fn set_nested_sources(
+  schema: &mut HashMap<String, ElementSchema>,
+  source: &ElementSource,
+  parent: Option<&str>,
+) {
+  for (key, value) in schema.iter_mut() {
+    if key == "__" {
+      continue;
+    }
+    let key = key.trim_matches('\'');
+    let path = match parent {
+      Some(parent) => format!("{}.{}", parent, key),
+      None => key.to_string(),
+    };
+    if let Some(sub_type) = value.sub_type.as_mut() {
+      set_nested_sources(sub_type, source, Some(&path));
+    }
+    value.source = Some(ElementSource {
+      symbol: source.symbol.clone(),
+      profile_url: None,
+      deprecated: false,
+      path: Some(path),
+    });
+  }
+}
+
+/// `require`, `minItems` and `maxItems` of a key as `min..max`.
+/// `minItems` only counts for required keys, an optional vector may be absent
+pub fn get_cardinality(value: &Value, require: bool, is_array: bool) -> Cardinality {
+  let min = match require {
+    true => value
+      .get("minItems")
+      .and_then(Value::as_u64)
+      .filter(|_| is_array)
+      .map_or(1, |it| it.max(1)),
+    false => 0,
+  };
+  let max = match is_array {
+    true => value.get("maxItems").and_then(Value::as_u64),
+    false => Some(1),
+  };
+  Cardinality { min, max }
+}
+
 pub fn key_required(key: String, require: bool) -> String {
   if key.as_str() == "[key: string]" {
     return key;
@@ -278,6 +358,22 @@ pub fn key_required(key: String, require: bool) -> String {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_get_cardinality() {
+    let vector = json!({"type": "zen/vector", "minItems": 1, "maxItems": 3});
+    assert_eq!(get_cardinality(&vector, false, true).to_string(), "0..3");
+    assert_eq!(get_cardinality(&vector, true, true).to_string(), "1..3");
+    assert_eq!(
+      get_cardinality(&json!({"type": "zen/vector"}), true, true).to_string(),
+      "1..*"
+    );
+    assert_eq!(
+      get_cardinality(&json!({"type": "zen/string"}), false, false).to_string(),
+      "0..1"
+    );
+  }
 
   #[test]
   fn test_capitalize() {
@@ -1,4 +1,5 @@
 pub mod cache;
+pub mod collisions;
 pub mod common;
 pub mod helpers;
 pub mod reader;
@@ -1,9 +1,10 @@
 use crate::cache::Cache;
 use crate::get_description;
 use crate::helpers::{
-  convert_primitive, get_name, get_symbol, get_value_set, init_confirms, init_confirms_value,
-  init_reference_confirms_value, is_persistent_any, is_type_and_not_map, normalize_confirms,
-  wrap_key, zen_path_to_name,
+  convert_primitive, get_cardinality, get_name, get_source, get_symbol, get_value_set,
+  has_explicit_name, init_confirms, init_confirms_value, init_reference_confirms_value,
+  is_persistent_any, is_type_and_not_map, normalize_confirms, set_nested_sources, wrap_key,
+  zen_path_to_name,
 };
 use async_recursion::async_recursion;
 use async_stream::stream;
@@ -16,7 +17,7 @@ use tool_aidbox::BoxClient;
 use tool_common::capitalize;
 use tool_config::ExcludeConfig;
 
-use super::common::{Element, ElementSchema, ElementWrapper};
+use super::common::{Element, ElementSchema, ElementWrapper, SearchParam};
 
 #[async_recursion]
 async fn read_vector(
@@ -38,6 +39,8 @@ async fn read_vector(
       sub_type: None,
       plain_type: None,
       values: None,
+      cardinality: None,
+      source: None,
     });
   }
 
@@ -72,9 +75,8 @@ async fn read_vector(
         Err(e) => return Err(e.to_string()),
       };
 
-      let single_confirm = confirm.get(0);
-      if let Some(..) = single_confirm {
-        if single_confirm.unwrap() == "code" {
+      if let Some(single_confirm) = confirm.first() {
+        if single_confirm == "code" {
           if values.is_empty() {
             Ok(ElementSchema {
               extends: None,
@@ -85,6 +87,8 @@ async fn read_vector(
               sub_type: None,
               plain_type: Some("code".to_string()),
               values: None,
+              cardinality: None,
+              source: None,
             })
           } else {
             Ok(ElementSchema {
@@ -96,9 +100,11 @@ async fn read_vector(
               sub_type: None,
               plain_type: None,
               values: Some(values),
+              cardinality: None,
+              source: None,
             })
           }
-        } else if single_confirm.unwrap() == "CodeableConcept" {
+        } else if single_confirm == "CodeableConcept" {
           if values.is_empty() {
             Ok(ElementSchema {
               extends: None,
@@ -109,6 +115,8 @@ async fn read_vector(
               sub_type: None,
               plain_type: Some("CodeableConcept".to_string()),
               values: None,
+              cardinality: None,
+              source: None,
             })
           } else {
             Ok(ElementSchema {
@@ -120,9 +128,11 @@ async fn read_vector(
               sub_type: None,
               plain_type: Some("CodeableConcept".to_string()),
               values: Some(values),
+              cardinality: None,
+              source: None,
             })
           }
-        } else if single_confirm.unwrap() == "Coding" {
+        } else if single_confirm == "Coding" {
           if values.is_empty() {
             Ok(ElementSchema {
               extends: None,
@@ -133,6 +143,8 @@ async fn read_vector(
               sub_type: None,
               plain_type: Some("Coding".to_string()),
               values: None,
+              cardinality: None,
+              source: None,
             })
           } else {
             Ok(ElementSchema {
@@ -144,6 +156,8 @@ async fn read_vector(
               sub_type: None,
               plain_type: Some("Coding".to_string()),
               values: Some(values),
+              cardinality: None,
+              source: None,
             })
           }
         } else {
@@ -164,6 +178,8 @@ async fn read_vector(
         sub_type: None,
         plain_type: None,
         values: None,
+        cardinality: None,
+        source: None,
       })
     }
   } else if every
@@ -191,6 +207,8 @@ async fn read_vector(
       sub_type: None,
       plain_type,
       values,
+      cardinality: None,
+      source: None,
     })
   } else if every.get("type").is_some() {
     let vector_type = every.get("type").unwrap().as_str().unwrap();
@@ -212,6 +230,8 @@ async fn read_vector(
             sub_type: None,
             plain_type: None,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
         Ok(ElementSchema {
@@ -223,6 +243,8 @@ async fn read_vector(
           sub_type: Some(sub),
           plain_type: None,
           values: None,
+          cardinality: None,
+          source: None,
         })
       } else if every.get("keys").is_some() {
         let sub_type = match read_map(
@@ -248,6 +270,8 @@ async fn read_vector(
           sub_type: Some(sub_type),
           plain_type: None,
           values: None,
+          cardinality: None,
+          source: None,
         })
       } else {
         let mut sub = HashMap::new();
@@ -263,6 +287,8 @@ async fn read_vector(
             sub_type: None,
             plain_type: None,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
         Ok(ElementSchema {
@@ -274,6 +300,8 @@ async fn read_vector(
           sub_type: Some(sub),
           plain_type: None,
           values: None,
+          cardinality: None,
+          source: None,
         })
       }
     } else if vector_type == "zen/string" || vector_type == "zen/keyword" {
@@ -302,6 +330,8 @@ async fn read_vector(
         sub_type: None,
         plain_type,
         values,
+        cardinality: None,
+        source: None,
       })
     } else if vector_type == "zen/vector" {
       match read_vector(box_instance, cache, resource_name, every, log_handler).await {
@@ -318,6 +348,8 @@ async fn read_vector(
         sub_type: None,
         plain_type: Some("integer".to_string()),
         values: None,
+        cardinality: None,
+        source: None,
       })
     } else if vector_type == "zen/keyword" {
       Ok(ElementSchema {
@@ -329,6 +361,8 @@ async fn read_vector(
         sub_type: None,
         plain_type: Some("string".to_string()),
         values: None,
+        cardinality: None,
+        source: None,
       })
     } else {
       println!("Vector nested unparsed type {}", every);
@@ -344,6 +378,8 @@ async fn read_vector(
       sub_type: None,
       plain_type: None,
       values: None,
+      cardinality: None,
+      source: None,
     })
   }
 }
@@ -416,6 +452,8 @@ async fn read_map(
           sub_type: None,
           plain_type,
           values,
+          cardinality: None,
+          source: None,
         },
       );
     } else if value
@@ -446,6 +484,8 @@ async fn read_map(
           sub_type: None,
           plain_type,
           values,
+          cardinality: None,
+          source: None,
         },
       );
     } else if value.get("type").is_none() && value.get("confirms").is_some() {
@@ -465,6 +505,8 @@ async fn read_map(
           sub_type: None,
           plain_type: None,
           values: None,
+          cardinality: None,
+          source: None,
         },
       );
     } else if value.get("type").is_some() {
@@ -499,6 +541,8 @@ async fn read_map(
               sub_type: None,
               plain_type: None,
               values: None,
+              cardinality: None,
+              source: None,
             },
           );
         } else if value.get("keys").is_some() {
@@ -527,6 +571,8 @@ async fn read_map(
               sub_type: Some(sub_type),
               plain_type: None,
               values: None,
+              cardinality: None,
+              source: None,
             },
           );
         } else {
@@ -543,6 +589,8 @@ async fn read_map(
               sub_type: None,
               plain_type: None,
               values: None,
+              cardinality: None,
+              source: None,
             },
           );
           result_map.insert(
@@ -556,6 +604,8 @@ async fn read_map(
               sub_type: Some(sub),
               plain_type: None,
               values: None,
+              cardinality: None,
+              source: None,
             },
           );
         }
@@ -587,6 +637,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/number" {
@@ -601,6 +653,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/datetime" {
@@ -615,6 +669,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/boolean" {
@@ -629,6 +685,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/integer" {
@@ -643,6 +701,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/date" {
@@ -657,6 +717,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/any" {
@@ -671,6 +733,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/symbol" {
@@ -685,6 +749,8 @@ async fn read_map(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else if source_type == "zen/set" {
@@ -699,6 +765,8 @@ async fn read_map(
             is_array: true,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       } else {
@@ -717,6 +785,8 @@ async fn read_map(
             sub_type: None,
             plain_type: None,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
       }
@@ -732,9 +802,19 @@ async fn read_map(
           sub_type: None,
           plain_type: None,
           values: None,
+          cardinality: None,
+          source: None,
         },
       );
     }
+
+    if let Some(schema) = result_map.get_mut(&wrap_key(key)) {
+      schema.cardinality = Some(get_cardinality(
+        value,
+        required.contains(key),
+        schema.is_array,
+      ));
+    }
   }
 
   Ok(result_map)
@@ -777,6 +857,8 @@ async fn read_keys(
           is_array: false,
           is_reference: false,
           values: None,
+          cardinality: None,
+          source: None,
         },
       );
       res
@@ -798,7 +880,6 @@ async fn read_rpc(
     .unwrap()
     .as_str()
     .unwrap()
-    .to_string()
     .split('/')
     .map(|item| match item.contains('.') {
       true => item
@@ -829,6 +910,8 @@ async fn read_rpc(
           sub_type: None,
           plain_type: None,
           values: None,
+          cardinality: None,
+          source: None,
         },
       );
 
@@ -843,6 +926,7 @@ async fn read_rpc(
           plain: None,
           schema: Some(sub),
           values: None,
+          source: None,
         },
       })
     } else if value.get("keys").is_some() {
@@ -874,6 +958,7 @@ async fn read_rpc(
             false => Some(sub_type),
           },
           values: None,
+          source: None,
         },
       })
     } else {
@@ -890,6 +975,8 @@ async fn read_rpc(
           sub_type: None,
           plain_type: None,
           values: None,
+          cardinality: None,
+          source: None,
         },
       );
       Ok(ElementWrapper {
@@ -903,6 +990,7 @@ async fn read_rpc(
           plain: None,
           schema: Some(sub),
           values: None,
+          source: None,
         },
       })
     }
@@ -918,11 +1006,54 @@ async fn read_rpc(
         plain: None,
         schema: None,
         values: None,
+        source: None,
       },
     })
   }
 }
 
+pub async fn search_read(
+  box_instance: &BoxClient,
+  cache: &mut Cache,
+  symbol: &String,
+) -> Result<Option<SearchParam>, String> {
+  let definition = get_symbol(box_instance, cache, symbol).await?;
+
+  let is_search = match definition.get("zen/tags").and_then(Value::as_array) {
+    Some(tags) => tags
+      .iter()
+      .any(|tag| tag.as_str() == Some("zen.fhir/search")),
+    None => false,
+  };
+  if !is_search {
+    return Ok(None);
+  }
+
+  let (name, kind) = match (
+    definition.get("name").and_then(Value::as_str),
+    definition.get("type").and_then(Value::as_str),
+  ) {
+    (Some(name), Some(kind)) => (name.to_string(), kind.to_string()),
+    _ => return Ok(None),
+  };
+
+  let resources: Vec<String> = match definition.get("expr").and_then(Value::as_object) {
+    Some(expr) => expr.keys().sorted().map(String::to_string).collect(),
+    None => vec![],
+  };
+
+  if resources.is_empty() {
+    return Ok(None);
+  }
+
+  Ok(Some(SearchParam {
+    name,
+    kind,
+    description: get_description!(&definition),
+    resources,
+  }))
+}
+
 pub async fn symbol_read(
   box_instance: &BoxClient,
   cache: &mut Cache,
@@ -936,7 +1067,7 @@ pub async fn symbol_read(
     Err(e) => return Err(e),
   };
 
-  if definition.get("zen/tags").is_some() {
+  if definition.contains_key("zen/tags") {
     let tags: Vec<_> = definition["zen/tags"]
       .as_array()
       .unwrap()
@@ -964,44 +1095,31 @@ pub async fn symbol_read(
       "aidbox/service".to_string(),
     ];
 
-    let user_excluded_tags = match exclude.tags {
-      Some(t) => t,
-      None => vec![],
-    };
+    let user_excluded_tags = exclude.tags.unwrap_or_default();
 
     if tags.contains(&"zen.fhir/profile-schema") {
-      if include_profile.is_some() {}
-      match include_profile.clone() {
+      let allowed = match include_profile.clone() {
         Some(profile) => {
-          match symbol
+          symbol
             .as_str()
             .starts_with(format!("{}.", profile).as_str())
             || symbol.as_str().starts_with("hl7-fhir-r4-core.")
-          {
-            false => return Ok(None),
-            true => {},
-          }
-        },
-        None => match symbol.as_str().starts_with("hl7-fhir-r4-core.") {
-          false => return Ok(None),
-          true => {},
         },
+        None => symbol.as_str().starts_with("hl7-fhir-r4-core."),
       };
+      if !allowed {
+        return Ok(None);
+      }
     }
 
-    if include_profile.is_some() {
-      let profile = include_profile.unwrap();
-      if tags.contains(&"zen.fhir/profile-schema") || tags.contains(&"zen.fhir/structure-schema") {
-        match symbol
+    if let Some(profile) = include_profile {
+      if (tags.contains(&"zen.fhir/profile-schema") || tags.contains(&"zen.fhir/structure-schema"))
+        && !(symbol
           .as_str()
           .starts_with(format!("{}.", profile).as_str())
-          || symbol.as_str().starts_with("hl7-fhir-r4-core.")
-        {
-          false => {
-            return Ok(None);
-          },
-          true => {},
-        }
+          || symbol.as_str().starts_with("hl7-fhir-r4-core."))
+      {
+        return Ok(None);
       }
     }
 
@@ -1011,7 +1129,10 @@ pub async fn symbol_read(
       return Ok(None);
     }
 
-    let resource_name = get_name(&definition);
+    let resource_name = match cache.renames.get(symbol) {
+      Some(it) => it.to_string(),
+      None => get_name(&definition),
+    };
 
     if resource_name == "Reference" {
       return Ok(None);
@@ -1055,6 +1176,8 @@ pub async fn symbol_read(
             is_array: false,
             is_reference: false,
             values: None,
+            cardinality: None,
+            source: None,
           },
         );
 
@@ -1069,6 +1192,7 @@ pub async fn symbol_read(
             schema: Some(sub_type),
             plain: None,
             values: None,
+            source: None,
           },
         }))
       } else {
@@ -1093,6 +1217,7 @@ pub async fn symbol_read(
             ),
             plain: None,
             values: None,
+            source: None,
           },
         }))
       }
@@ -1100,7 +1225,7 @@ pub async fn symbol_read(
       if is_type_and_not_map(&definition) {
         let primitive_type = convert_primitive(definition["type"].as_str().unwrap());
 
-        if cache.primitives.get(&resource_name).is_none() {
+        if !cache.primitives.contains_key(&resource_name) {
           cache.primitives.insert(
             resource_name.clone(),
             serde_json::to_value(&primitive_type).unwrap(),
@@ -1117,9 +1242,10 @@ pub async fn symbol_read(
             schema: None,
             plain: Some(primitive_type),
             values: None,
+            source: None,
           },
         }))
-      } else if definition.get("type").is_none() {
+      } else if !definition.contains_key("type") {
         let values = match definition.get("zen.fhir/value-set") {
           Some(it) => match get_value_set(
             box_instance,
@@ -1155,10 +1281,14 @@ pub async fn symbol_read(
                 schema: None,
                 plain: None,
                 values,
+                source: None,
               },
             }))
           } else {
-            let new_name = zen_path_to_name(&definition["zen/name"]);
+            let new_name = match cache.renames.get(symbol) {
+              Some(it) => it.to_string(),
+              None => zen_path_to_name(&definition["zen/name"]),
+            };
 
             Ok(Some(ElementWrapper {
               name: new_name.clone(),
@@ -1171,6 +1301,7 @@ pub async fn symbol_read(
                 schema: None,
                 plain: None,
                 values,
+                source: None,
               },
             }))
           }
@@ -1203,6 +1334,8 @@ pub async fn symbol_read(
               is_array: false,
               is_reference: false,
               values: None,
+              cardinality: None,
+              source: None,
             },
           );
 
@@ -1217,6 +1350,7 @@ pub async fn symbol_read(
               schema: Some(keys),
               plain: None,
               values: None,
+              source: None,
             },
           }))
         } else if resource_name == "DomainResource" {
@@ -1231,6 +1365,7 @@ pub async fn symbol_read(
               schema: Some(keys),
               plain: None,
               values: None,
+              source: None,
             },
           }))
         } else {
@@ -1245,6 +1380,7 @@ pub async fn symbol_read(
               schema: Some(keys),
               plain: None,
               values: None,
+              source: None,
             },
           }))
         }
@@ -1276,6 +1412,7 @@ pub async fn symbol_read(
           schema: Some(keys),
           plain: None,
           values: None,
+          source: None,
         },
       )))
     };
@@ -1288,6 +1425,7 @@ pub async fn symbol_read(
 pub struct ReadSchemaResponse {
   pub symbol: String,
   pub name: String,
+  pub explicit_name: bool,
   pub element: Element,
 }
 
@@ -1312,10 +1450,20 @@ pub async fn read_schema<'a>(
     .await
     {
       if it.is_some() {
-        let new_element = it.unwrap();
+        let mut new_element = it.unwrap();
+        let mut explicit_name = false;
+        if let Some(definition) = cache.schema.get(symbol) {
+          let source = get_source(symbol, definition);
+          if let Some(schema) = new_element.element.schema.as_mut() {
+            set_nested_sources(schema, &source, None);
+          }
+          new_element.element.source = Some(source);
+          explicit_name = has_explicit_name(cache, symbol, definition);
+        }
         yield (ReadSchemaResponse{
         symbol: symbol.to_owned(),
         name: new_element.name.to_string(),
+        explicit_name,
         element:  new_element.element.clone(),
         })
       }
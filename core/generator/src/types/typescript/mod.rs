@@ -1,17 +1,224 @@
-use crate::common::{Element, ElementSchema};
-use crate::helpers::key_required;
+use crate::common::{Cardinality, Element, ElementSchema, ElementSource, SearchParam};
+use crate::helpers::{key_required, refined_primitive, wrap_key};
 use dprint_plugin_typescript::configuration::{ConfigurationBuilder, QuoteStyle};
 use dprint_plugin_typescript::format_text;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::PathBuf;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PrimitiveMode {
+  Plain,
+  Branded,
+  Template,
+}
+
 #[derive(Clone)]
 pub struct WriterConfig {
   pub fhir: bool,
   pub output: String,
   pub collapse_values: bool,
   pub max_values: usize,
+  pub type_guards: bool,
+  pub primitives: PrimitiveMode,
+  pub primitive_validators: bool,
+}
+
+const FHIR_DATE_REGEX: &str = "^([0-9]([0-9]([0-9][1-9]|[1-9]0)|[1-9]00)|[1-9]000)(-(0[1-9]|1[0-2])(-(0[1-9]|[1-2][0-9]|3[0-1]))?)?$";
+const FHIR_DATETIME_REGEX: &str = "^([0-9]([0-9]([0-9][1-9]|[1-9]0)|[1-9]00)|[1-9]000)(-(0[1-9]|1[0-2])(-(0[1-9]|[1-2][0-9]|3[0-1])(T([01][0-9]|2[0-3]):[0-5][0-9]:([0-5][0-9]|60)(\\.[0-9]{1,9})?)?)?(Z|(\\+|-)((0[0-9]|1[0-3]):[0-5][0-9]|14:00)?)?)?$";
+const FHIR_ID_REGEX: &str = "^[A-Za-z0-9\\-\\.]{1,64}$";
+
+fn write_primitive_types(mode: PrimitiveMode, result: &mut Vec<String>) {
+  match mode {
+    PrimitiveMode::Plain => {},
+    PrimitiveMode::Branded => {
+      result.push("declare const brand: unique symbol;".to_string());
+      result.push("export type Brand<T, B> = T & { readonly [brand]: B };".to_string());
+      result.push("export type FhirDate = Brand<string, 'FhirDate'>;".to_string());
+      result.push("export type FhirDateTime = Brand<string, 'FhirDateTime'>;".to_string());
+      result.push("export type FhirInteger = Brand<number, 'FhirInteger'>;".to_string());
+      result.push("export type Id<T = string> = Brand<string, ['Id', T]>;\n".to_string());
+    },
+    PrimitiveMode::Template => {
+      result.push(
+        "export type FhirDate = `${number}` | `${number}-${number}` | `${number}-${number}-${number}`;"
+          .to_string(),
+      );
+      result.push(
+        "export type FhirDateTime = FhirDate | `${number}-${number}-${number}T${string}`;"
+          .to_string(),
+      );
+      result.push("export type FhirInteger = number;".to_string());
+      // Optional phantom key: any string is an id, but ids of different resources don't mix
+      result.push(
+        "export type Id<T = string> = string & { readonly __resourceType?: T };\n".to_string(),
+      );
+    },
+  }
+}
+
+fn write_primitive_validators(input_config: &WriterConfig) {
+  let output = PathBuf::from(input_config.output.clone());
+  let stem = output
+    .file_name()
+    .and_then(|it| it.to_str())
+    .map(|it| {
+      it.trim_end_matches(".ts")
+        .trim_end_matches(".d")
+        .to_string()
+    })
+    .unwrap_or_else(|| "types".to_string());
+  let target = output.with_file_name(format!("{}.validators.ts", stem));
+
+  let mut result: Vec<String> = vec![format!(
+    "import type {{ FhirDate, FhirDateTime, FhirInteger, Id }} from './{}';\n",
+    stem
+  )];
+
+  result.push(format!("const FHIR_DATE = /{}/;", FHIR_DATE_REGEX));
+  result.push(format!("const FHIR_DATETIME = /{}/;", FHIR_DATETIME_REGEX));
+  result.push(format!("const FHIR_ID = /{}/;\n", FHIR_ID_REGEX));
+
+  for (name, check) in [
+    (
+      "FhirDate",
+      "typeof value === 'string' && FHIR_DATE.test(value)",
+    ),
+    (
+      "FhirDateTime",
+      "typeof value === 'string' && FHIR_DATETIME.test(value)",
+    ),
+    (
+      "FhirInteger",
+      "typeof value === 'number' && Number.isInteger(value)",
+    ),
+  ] {
+    result.push(format!(
+      "export const is{0} = (value: unknown): value is {0} => {1};",
+      name, check
+    ));
+    result.push(format!(
+      "export const parse{0} = (value: unknown): {0} => {{",
+      name
+    ));
+    result.push(format!(
+      "  if (!is{0}(value)) throw new TypeError(`Invalid {0}: ${{String(value)}}`);",
+      name
+    ));
+    result.push("  return value;".to_string());
+    result.push("};\n".to_string());
+  }
+
+  result.push("export const isId = <T extends string = string>(value: unknown): value is Id<T> => typeof value === 'string' && FHIR_ID.test(value);".to_string());
+  result.push(
+    "export const parseId = <T extends string = string>(value: unknown, _resourceType?: T): Id<T> => {"
+      .to_string(),
+  );
+  result.push(
+    "  if (!isId<T>(value)) throw new TypeError(`Invalid id: ${String(value)}`);".to_string(),
+  );
+  result.push("  return value;".to_string());
+  result.push("};".to_string());
+
+  write_formatted(&target, result.join("\n"));
+}
+
+fn write_formatted(target: &PathBuf, source: String) {
+  let config = ConfigurationBuilder::new()
+    .line_width(120)
+    .quote_style(QuoteStyle::PreferSingle)
+    .build();
+
+  match format_text(target, &source, &config) {
+    Ok(formatted) => {
+      fs::write(target, formatted.as_deref().unwrap_or(&source))
+        .expect("Expected to write to the file.");
+    },
+    Err(_) => {
+      fs::write(target, source).expect("Write result to file error");
+    },
+  }
+}
+
+fn escape_jsdoc_line(line: &str) -> String {
+  let escaped = line.trim_end().replace("*/", "*\\/");
+  match escaped.trim_start().starts_with('@') {
+    true => escaped.replacen('@', "\\@", 1),
+    false => escaped,
+  }
+}
+
+/// Url inside `{@link ...}`. Braces would end the tag early and whitespace would start its
+/// label, so they are percent-encoded like `*/`
+fn escape_jsdoc_link(url: &str) -> String {
+  url
+    .trim()
+    .replace('{', "%7B")
+    .replace('}', "%7D")
+    .replace("*/", "*%2F")
+    .split_whitespace()
+    .collect::<Vec<_>>()
+    .join("%20")
+}
+
+fn write_jsdoc(
+  description: Option<&String>,
+  source: Option<&ElementSource>,
+  cardinality: Option<&Cardinality>,
+  result: &mut Vec<String>,
+) {
+  let mut lines: Vec<String> = match description {
+    Some(it) => it.lines().map(escape_jsdoc_line).collect(),
+    None => vec![],
+  };
+
+  if let Some(cardinality) = cardinality {
+    if !lines.is_empty() {
+      lines.push(String::new());
+    }
+    lines.push(format!("Cardinality: `{}`", cardinality));
+  }
+
+  let mut tags: Vec<String> = vec![];
+  if let Some(source) = source {
+    match &source.path {
+      Some(path) => tags.push(format!(
+        "@see `{}` {}",
+        escape_jsdoc_line(&source.symbol),
+        escape_jsdoc_line(path)
+      )),
+      None => tags.push(format!("@see `{}`", escape_jsdoc_line(&source.symbol))),
+    }
+    if let Some(url) = &source.profile_url {
+      tags.push(format!("@see {{@link {}}}", escape_jsdoc_link(url)));
+    }
+    if source.deprecated {
+      tags.push("@deprecated".to_string());
+    }
+  }
+
+  if lines.is_empty() && tags.is_empty() {
+    return;
+  }
+
+  if lines.len() == 1 && tags.is_empty() {
+    result.push(format!("/** {} */", lines[0]));
+    return;
+  }
+
+  if !lines.is_empty() && !tags.is_empty() {
+    lines.push(String::new());
+  }
+  lines.extend(tags);
+
+  result.push("/**".to_string());
+  for line in lines {
+    match line.is_empty() {
+      true => result.push(" *".to_string()),
+      false => result.push(format!(" * {}", line)),
+    }
+  }
+  result.push(" */".to_string());
 }
 
 fn build_any(value: ElementSchema) -> String {
@@ -87,14 +294,16 @@ fn typescript_write_nested_type(
   input_config: WriterConfig,
 ) {
   for (key, value) in map {
-    if value.description.is_some() {
-      result.push(format!("/* {} */", value.description.as_ref().unwrap()));
-    }
+    write_jsdoc(
+      value.description.as_ref(),
+      value.source.as_ref(),
+      value.cardinality.as_ref(),
+      result,
+    );
     if key == "__" {
       result.push("[key: string]: any;".to_string());
     } else if value.plain_type.is_none() && value.sub_type.is_none() {
-      if value.extends.is_some() {
-        let target_extends = value.extends.unwrap();
+      if let Some(target_extends) = value.extends {
         if !target_extends.is_empty() {
           result.push(format!(
             "{}: {};",
@@ -157,24 +366,114 @@ fn write_rpc(
     "method: {};",
     format_args!("\"{}\"", definition.rpc_method.unwrap())
   ));
-  if definition.schema.is_some() {
+  if let Some(schema) = definition.schema {
     result.push("params: {".to_string());
-    typescript_write_nested_type(definition.schema.unwrap(), result, input_config);
+    typescript_write_nested_type(schema, result, input_config);
     result.push("}".to_string());
   }
   result.push("}\n".to_string());
 }
 
-pub fn write_typescript_types(types: HashMap<String, Element>, input_config: WriterConfig) {
+fn write_type_guards(resources: &[String], result: &mut Vec<String>) {
+  result.push(format!(
+    "export const ResourceTypes = [{}] as const;\n",
+    resources
+      .iter()
+      .map(|it| format!("'{}'", it))
+      .collect::<Vec<_>>()
+      .join(", ")
+  ));
+
+  result.push("export const isResourceOfType = <T extends EntityType>(resource: unknown, resourceType: T): resource is Entity<T> =>".to_string());
+  result.push("  typeof resource === 'object' && resource !== null && (resource as { resourceType?: unknown }).resourceType === resourceType;\n".to_string());
+
+  result.push("export function assertResourceType<T extends EntityType>(resource: unknown, resourceType: T): asserts resource is Entity<T> {".to_string());
+  result.push("  if (!isResourceOfType(resource, resourceType)) {".to_string());
+  result.push("    const actual = typeof resource === 'object' && resource !== null ? (resource as { resourceType?: unknown }).resourceType : typeof resource;".to_string());
+  result.push(
+    "    throw new TypeError(`Expected resourceType '${resourceType}', got '${String(actual)}'`);"
+      .to_string(),
+  );
+  result.push("  }".to_string());
+  result.push("}\n".to_string());
+
+  for resource in resources {
+    result.push(format!(
+      "export const is{0} = (resource: unknown): resource is {0} => isResourceOfType(resource, '{0}');",
+      resource
+    ));
+  }
+}
+
+fn write_search_params(params: &[SearchParam], result: &mut Vec<String>) {
+  let common_resources = ["Resource", "DomainResource"];
+  let mut kinds: BTreeMap<String, BTreeMap<String, &SearchParam>> = BTreeMap::new();
+
+  for param in params {
+    for resource in &param.resources {
+      kinds
+        .entry(resource.clone())
+        .or_default()
+        .insert(param.name.clone(), param);
+    }
+  }
+
+  let common: Vec<&SearchParam> = common_resources
+    .iter()
+    .filter_map(|it| kinds.get(*it))
+    .flat_map(|it| it.values().copied())
+    .collect();
+
+  for (resource, resource_params) in kinds.iter_mut() {
+    if common_resources.contains(&resource.as_str()) {
+      continue;
+    }
+    for param in &common {
+      resource_params.entry(param.name.clone()).or_insert(param);
+    }
+  }
+
+  result.push("export type SearchParamKind = 'number' | 'date' | 'string' | 'token' | 'reference' | 'composite' | 'quantity' | 'uri' | 'special';".to_string());
+  result.push(
+    "export type SearchParamValue<K> = K extends 'number' | 'quantity' ? number | string : string;\n"
+      .to_string(),
+  );
+
+  result.push("export type SearchParamKinds = {".to_string());
+  for (resource, resource_params) in kinds {
+    result.push(format!("{}: {{", resource));
+    for (name, param) in resource_params {
+      write_jsdoc(param.description.as_ref(), None, None, result);
+      result.push(format!("{}: '{}';", wrap_key(&name), param.kind));
+    }
+    result.push("};".to_string());
+  }
+  result.push("};\n".to_string());
+
+  result.push("export type SearchParams<T extends keyof SearchParamKinds> = {".to_string());
+  result.push("  [K in keyof SearchParamKinds[T]]?: SearchParamValue<SearchParamKinds[T][K]> | SearchParamValue<SearchParamKinds[T][K]>[];".to_string());
+  result.push("};".to_string());
+}
+
+pub fn write_typescript_types(
+  types: HashMap<String, Element>,
+  search_params: Vec<SearchParam>,
+  input_config: WriterConfig,
+) {
   let mut result: Vec<String> = vec![];
   let mut resource_map: Vec<String> = vec![];
 
   if input_config.fhir {
     result.push("export type Reference<T extends string> = {\n  reference: `${T}/${string}`;\n  display?: string;\n identifier: Identifier[];\n};\n".to_string());
   } else {
-    result.push("export type Reference<T = string> = {\n  id: string;\n  resourceType: T;\n  display?: string;\n identifier: Identifier[];\n};\n".to_string());
+    result.push(format!("export type Reference<T = string> = {{\n  id: {};\n  resourceType: T;\n  display?: string;\n identifier: Identifier[];\n}};\n", match input_config.primitives {
+      PrimitiveMode::Plain => "string",
+      _ => "Id<T>",
+    }));
   }
 
+  write_primitive_types(input_config.primitives, &mut result);
+
   for (key, value) in types {
     let mut name = key;
     if name.as_str() == "boolean" || name.as_str() == "string" {
@@ -195,33 +494,37 @@ pub fn write_typescript_types(types: HashMap<String, Element>, input_config: Wri
       }
     }
 
-    if value.description.is_some() {
-      result.push(format!("/* {} */", value.description.as_ref().unwrap()));
-    }
+    write_jsdoc(
+      value.description.as_ref(),
+      value.source.as_ref(),
+      None,
+      &mut result,
+    );
 
     if value.is_rpc {
       write_rpc(name, value, &mut result, input_config.clone());
-    } else if value.plain.is_some() {
+    } else if let Some(plain) = value.plain {
+      let refined = match input_config.primitives {
+        PrimitiveMode::Plain => None,
+        _ => refined_primitive(name.as_str()),
+      };
       result.push(format!(
         "export type {} = {};",
         name.clone(),
-        value.plain.unwrap()
+        refined.map(str::to_string).unwrap_or(plain)
       ))
     } else if value.schema.is_none() && value.plain.is_none() {
-      if value.values.is_some() {
+      if let Some(values) = value.values {
         result.push(format!(
           "export type {} = {};",
           name.clone(),
-          value
-            .values
-            .unwrap()
+          values
             .iter()
             .map(|it| format!("\"{}\"", it))
             .collect::<Vec<_>>()
             .join(" | ")
         ))
-      } else if value.extends.is_some() {
-        let target_extends = value.extends.unwrap();
+      } else if let Some(target_extends) = value.extends {
         if !target_extends.is_empty() {
           result.push(format!(
             "export type {} = {};",
@@ -246,7 +549,14 @@ pub fn write_typescript_types(types: HashMap<String, Element>, input_config: Wri
           None => "{ ".to_string(),
         }
       ));
-      typescript_write_nested_type(value.schema.unwrap(), &mut result, input_config.clone());
+      let mut schema = value.schema.unwrap();
+      if name.starts_with("Resource<") && input_config.primitives != PrimitiveMode::Plain {
+        if let Some(id) = schema.get_mut("id") {
+          id.plain_type = None;
+          id.extends = Some(vec!["Id<T>".to_string()]);
+        }
+      }
+      typescript_write_nested_type(schema, &mut result, input_config.clone());
 
       result.push("};\n".to_string());
     }
@@ -254,8 +564,8 @@ pub fn write_typescript_types(types: HashMap<String, Element>, input_config: Wri
 
   result.push("export type EntityList = {".to_string());
 
-  for resource in resource_map {
-    result.push(format!("{}:{}", resource.clone(), resource))
+  for resource in resource_map.iter() {
+    result.push(format!("{}:{}", resource, resource))
   }
 
   result.push("};\n".to_string());
@@ -263,26 +573,163 @@ pub fn write_typescript_types(types: HashMap<String, Element>, input_config: Wri
   result.push("export type EntityType = keyof EntityList;".to_string());
   result.push("export type Entity<T extends EntityType | void = void> = T extends EntityType ? EntityList[T] : EntityList;".to_string());
 
-  let result_types = result.join("\n");
-  let config = ConfigurationBuilder::new()
-    .line_width(120)
-    .quote_style(QuoteStyle::PreferSingle)
-    .build();
+  if input_config.type_guards {
+    let mut resources = resource_map.clone();
+    resources.sort();
+    resources.dedup();
+    write_type_guards(&resources, &mut result);
+  }
+
+  if !search_params.is_empty() {
+    write_search_params(&search_params, &mut result);
+  }
 
-  match format_text(
+  write_formatted(
     &PathBuf::from(input_config.output.clone()),
-    &result_types,
-    &config,
-  ) {
-    Ok(formatted) => {
-      fs::write(
-        input_config.output,
-        formatted.as_deref().unwrap_or(&result_types),
-      )
-      .expect("Expected to write to the file.");
-    },
-    Err(_) => {
-      fs::write(input_config.output, result_types).expect("Write result to file error");
-    },
+    result.join("\n"),
+  );
+
+  if input_config.primitive_validators {
+    write_primitive_validators(&input_config);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_write_type_guards() {
+    let mut result: Vec<String> = vec![];
+    write_type_guards(
+      &["Encounter".to_string(), "Patient".to_string()],
+      &mut result,
+    );
+
+    assert_eq!(
+      result[0],
+      "export const ResourceTypes = ['Encounter', 'Patient'] as const;\n"
+    );
+    assert!(result.contains(
+      &"export const isPatient = (resource: unknown): resource is Patient => isResourceOfType(resource, 'Patient');"
+        .to_string()
+    ));
+    assert!(result
+      .iter()
+      .any(|line| line.starts_with("export function assertResourceType")));
+  }
+
+  #[test]
+  fn test_write_jsdoc() {
+    let mut result: Vec<String> = vec![];
+    write_jsdoc(Some(&"Single line".to_string()), None, None, &mut result);
+    assert_eq!(result, vec!["/** Single line */".to_string()]);
+
+    let mut result: Vec<String> = vec![];
+    write_jsdoc(
+      Some(&"First */ line\n@not-a-tag".to_string()),
+      Some(&ElementSource {
+        symbol: "hl7-fhir-r4-core.Patient/schema".to_string(),
+        profile_url: Some("http://hl7.org/fhir/StructureDefinition/Patient".to_string()),
+        deprecated: true,
+        path: None,
+      }),
+      None,
+      &mut result,
+    );
+    assert_eq!(
+      result,
+      vec![
+        "/**",
+        " * First *\\/ line",
+        " * \\@not-a-tag",
+        " *",
+        " * @see `hl7-fhir-r4-core.Patient/schema`",
+        " * @see {@link http://hl7.org/fhir/StructureDefinition/Patient}",
+        " * @deprecated",
+        " */",
+      ]
+    );
+
+    let mut result: Vec<String> = vec![];
+    write_jsdoc(
+      Some(&"A name".to_string()),
+      Some(&ElementSource {
+        symbol: "hl7-fhir-r4-core.Patient/schema".to_string(),
+        profile_url: None,
+        deprecated: false,
+        path: Some("name.given".to_string()),
+      }),
+      Some(&Cardinality { min: 0, max: None }),
+      &mut result,
+    );
+    assert_eq!(
+      result,
+      vec![
+        "/**",
+        " * A name",
+        " *",
+        " * Cardinality: `0..*`",
+        " *",
+        " * @see `hl7-fhir-r4-core.Patient/schema` name.given",
+        " */",
+      ]
+    );
+
+    assert_eq!(
+      escape_jsdoc_link("http://x/{id}/a */b c"),
+      "http://x/%7Bid%7D/a%20*%2Fb%20c"
+    );
+
+    let mut result: Vec<String> = vec![];
+    write_jsdoc(None, None, None, &mut result);
+    assert!(result.is_empty());
+  }
+
+  #[test]
+  fn test_write_primitive_types() {
+    let mut result: Vec<String> = vec![];
+    write_primitive_types(PrimitiveMode::Plain, &mut result);
+    assert!(result.is_empty());
+
+    write_primitive_types(PrimitiveMode::Branded, &mut result);
+    assert!(result.contains(&"export type FhirDate = Brand<string, 'FhirDate'>;".to_string()));
+
+    result.clear();
+    write_primitive_types(PrimitiveMode::Template, &mut result);
+    assert!(result.contains(
+      &"export type Id<T = string> = string & { readonly __resourceType?: T };\n".to_string()
+    ));
+
+    assert_eq!(refined_primitive("dateTime"), Some("FhirDateTime"));
+    assert_eq!(refined_primitive("string"), None);
+  }
+
+  #[test]
+  fn test_write_search_params() {
+    let mut result: Vec<String> = vec![];
+    write_search_params(
+      &[
+        SearchParam {
+          name: "_id".to_string(),
+          kind: "token".to_string(),
+          description: None,
+          resources: vec!["Resource".to_string()],
+        },
+        SearchParam {
+          name: "address-city".to_string(),
+          kind: "string".to_string(),
+          description: None,
+          resources: vec!["Patient".to_string(), "Person".to_string()],
+        },
+      ],
+      &mut result,
+    );
+
+    let patient = result.iter().position(|it| it == "Patient: {").unwrap();
+    assert_eq!(result[patient + 1], "_id: 'token';");
+    assert_eq!(result[patient + 2], "'address-city': 'string';");
+    assert!(result.contains(&"Person: {".to_string()));
+    assert!(result.contains(&"Resource: {".to_string()));
   }
 }
@@ -0,0 +1,478 @@
+use crate::aidbox::helpers::{
+  collect_files, confirm, connect, content_hash, diff_lines, format_diff, format_outcome,
+  read_value_file, resource_content, sort_keys,
+};
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use log::error;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tool_aidbox::rest::ApiFormat;
+
+/// Lock file name, written inside `--dir` unless `--lock-file` points elsewhere
+const LOCK_FILE: &str = "aidbox.lock.json";
+
+/// Page size used to read every resource of a type
+const PAGE_SIZE: usize = 1000;
+
+/// Unchanged lines shown around each change of a diff
+const DIFF_CONTEXT: usize = 3;
+
+pub fn commands() -> Command {
+  Command::new("apply")
+    .about("Make the box match a directory of JSON/YAML resources: plan, review the diff, apply")
+    .args(vec![
+      Arg::new("dir")
+        .short('d')
+        .long("dir")
+        .required(true)
+        .value_hint(ValueHint::DirPath)
+        .help("Directory of resources with resourceType and id. Files may hold one resource, a list or a Bundle"),
+      Arg::new("dry-run")
+        .long("dry-run")
+        .action(SetTrue)
+        .help("Print the plan without applying it"),
+      Arg::new("yes")
+        .short('y')
+        .long("yes")
+        .action(SetTrue)
+        .help("Apply without confirmation"),
+      Arg::new("lock-file")
+        .long("lock-file")
+        .value_hint(ValueHint::FilePath)
+        .help("Where applied versions are recorded. Defaults to aidbox.lock.json in the directory"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  match apply(sub_matches).await {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+/// Desired resource with the file it was read from
+#[derive(Debug)]
+struct Desired {
+  resource_type: String,
+  id: String,
+  resource: Value,
+  file: String,
+}
+
+impl Desired {
+  fn key(&self) -> String {
+    format!("{}/{}", self.resource_type, self.id)
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Action {
+  Create,
+  Update,
+  Unchanged,
+  /// In the box, but not in the directory. Never deleted
+  Extra,
+}
+
+#[derive(Debug)]
+struct Step {
+  action: Action,
+  key: String,
+  /// Index in the desired resources. `None` for extras
+  desired: Option<usize>,
+  /// Resource in the box
+  current: Option<Value>,
+}
+
+/// Resources of a file: one resource, a list of them or Bundle entries
+fn file_resources(value: Value) -> Vec<Value> {
+  match value {
+    Value::Array(items) => items,
+    bundle if bundle.get("resourceType").and_then(Value::as_str) == Some("Bundle") => bundle
+      .get("entry")
+      .and_then(Value::as_array)
+      .map(|entries| {
+        entries
+          .iter()
+          .filter_map(|it| it.get("resource").cloned())
+          .collect()
+      })
+      .unwrap_or_default(),
+    other => vec![other],
+  }
+}
+
+fn read_desired(dir: &Path, lock_path: &Path) -> Result<Vec<Desired>, String> {
+  let mut desired: Vec<Desired> = vec![];
+  let mut seen: BTreeMap<String, String> = BTreeMap::new();
+  for file in collect_files(dir, &["json", "yaml", "yml"])? {
+    if file == lock_path {
+      continue;
+    }
+    let file = file.display().to_string();
+    for resource in file_resources(read_value_file(&file)?) {
+      let (resource_type, id) = match (
+        resource.get("resourceType").and_then(Value::as_str),
+        resource.get("id").and_then(Value::as_str),
+      ) {
+        (Some(resource_type), Some(id)) => (resource_type.to_string(), id.to_string()),
+        _ => {
+          return Err(format!(
+            "{}: every resource needs resourceType and id",
+            file
+          ))
+        },
+      };
+      let item = Desired {
+        resource_type,
+        id,
+        resource: resource_content(&resource),
+        file: file.clone(),
+      };
+      if let Some(other) = seen.insert(item.key(), file.clone()) {
+        return Err(format!(
+          "{} is defined in {} and {}",
+          item.key(),
+          other,
+          file
+        ));
+      }
+      desired.push(item);
+    }
+  }
+  Ok(desired)
+}
+
+/// Steps that make the box match the desired resources of the given types, ordered by key
+fn plan(desired: &[Desired], current: &BTreeMap<String, Value>) -> Vec<Step> {
+  let mut steps: Vec<Step> = desired
+    .iter()
+    .enumerate()
+    .map(|(index, item)| {
+      let existing = current.get(&item.key());
+      let action = match existing {
+        None => Action::Create,
+        Some(it) if content_hash(it) == content_hash(&item.resource) => Action::Unchanged,
+        Some(..) => Action::Update,
+      };
+      Step {
+        action,
+        key: item.key(),
+        desired: Some(index),
+        current: existing.cloned(),
+      }
+    })
+    .collect();
+  steps.extend(
+    current
+      .iter()
+      .filter(|(key, _)| !desired.iter().any(|it| it.key() == **key))
+      .map(|(key, resource)| Step {
+        action: Action::Extra,
+        key: key.to_string(),
+        desired: None,
+        current: Some(resource.clone()),
+      }),
+  );
+  steps.sort_by(|a, b| a.key.cmp(&b.key));
+  steps
+}
+
+fn version_id(resource: &Value) -> Option<&str> {
+  resource
+    .get("meta")
+    .and_then(|it| it.get("versionId"))
+    .and_then(Value::as_str)
+}
+
+/// Pretty JSON with sorted keys, so diffs show changes rather than key order
+fn pretty(value: &Value) -> String {
+  serde_json::to_string_pretty(&sort_keys(value)).unwrap_or_default()
+}
+
+fn read_lock(path: &Path) -> Result<Value, String> {
+  match std::fs::read_to_string(path) {
+    Ok(source) => serde_json::from_str(&source)
+      .map_err(|err| format!("Cannot parse {}: {}", path.display(), err)),
+    Err(..) => Ok(json!({})),
+  }
+}
+
+/// Lock entry of the instance: applied time and `versionId`/hash of every resource
+fn lock_entry(url: &str, resources: &BTreeMap<String, (Option<String>, String)>) -> Value {
+  json!({
+    "url": url,
+    "appliedAt": chrono::Utc::now().to_rfc3339(),
+    "resources": resources
+      .iter()
+      .map(|(key, (version, hash))| (key.to_string(), json!({"versionId": version, "hash": hash})))
+      .collect::<Map<String, Value>>(),
+  })
+}
+
+/// Returns `false` when some resource failed to apply
+async fn apply(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let dir = PathBuf::from(sub_matches.get_one::<String>("dir").unwrap());
+  let lock_path = match sub_matches.get_one::<String>("lock-file") {
+    Some(it) => PathBuf::from(it),
+    None => dir.join(LOCK_FILE),
+  };
+
+  let desired = read_desired(&dir, &lock_path)?;
+  if desired.is_empty() {
+    return Err(format!("No resources in {}", dir.display()));
+  }
+  let mut lock = read_lock(&lock_path)?;
+  let locked = lock
+    .get(instance)
+    .and_then(|it| it.get("resources"))
+    .cloned()
+    .unwrap_or(Value::Null);
+
+  let client = connect(instance).await?;
+  let mut types: Vec<&String> = desired.iter().map(|it| &it.resource_type).collect();
+  types.sort();
+  types.dedup();
+  let mut current: BTreeMap<String, Value> = BTreeMap::new();
+  for resource_type in types {
+    for resource in client
+      .search_all(ApiFormat::Aidbox, resource_type, PAGE_SIZE)
+      .await?
+    {
+      if let Some(id) = resource.get("id").and_then(Value::as_str) {
+        current.insert(format!("{}/{}", resource_type, id), resource);
+      }
+    }
+  }
+
+  let steps = plan(&desired, &current);
+  println!(
+    "{} {} ({})",
+    style("Plan for").bold(),
+    style(instance).cyan().bold(),
+    client.url()
+  );
+  for step in steps.iter() {
+    let file = step
+      .desired
+      .map(|index| desired[index].file.as_str())
+      .unwrap_or_default();
+    match step.action {
+      Action::Unchanged => continue,
+      Action::Create => {
+        println!(
+          "  {} {}  {}",
+          style("+").green().bold(),
+          step.key,
+          style(file).dim()
+        );
+        for line in pretty(&desired[step.desired.unwrap()].resource).lines() {
+          println!("      {}", style(format!("+ {}", line)).green());
+        }
+      },
+      Action::Update => {
+        println!(
+          "  {} {}  {}",
+          style("~").yellow().bold(),
+          step.key,
+          style(file).dim()
+        );
+        // Changed in the box since the last apply, e.g. from the UI
+        let applied = locked
+          .get(&step.key)
+          .and_then(|it| it.get("versionId"))
+          .and_then(Value::as_str);
+        let actual = step.current.as_ref().and_then(version_id);
+        if let (Some(applied), Some(actual)) = (applied, actual) {
+          if applied != actual {
+            println!(
+              "      {}",
+              style(format!(
+                "changed in the box since the last apply (version {} -> {})",
+                applied, actual
+              ))
+              .yellow()
+            );
+          }
+        }
+        let old = pretty(&resource_content(step.current.as_ref().unwrap()));
+        let new = pretty(&desired[step.desired.unwrap()].resource);
+        for line in format_diff(&diff_lines(&old, &new), DIFF_CONTEXT) {
+          println!("      {}", line);
+        }
+      },
+      Action::Extra => println!(
+        "  {} {}  {}",
+        style("?").dim(),
+        step.key,
+        style("only in the box").dim()
+      ),
+    }
+  }
+
+  let count = |action: Action| steps.iter().filter(|it| it.action == action).count();
+  let pending = count(Action::Create) + count(Action::Update);
+  println!(
+    "{} {} to create, {} to update, {} unchanged, {} only in the box",
+    style("Plan:").bold(),
+    count(Action::Create),
+    count(Action::Update),
+    count(Action::Unchanged),
+    count(Action::Extra)
+  );
+
+  if sub_matches.get_flag("dry-run") {
+    return Ok(true);
+  }
+  if pending > 0
+    && !sub_matches.get_flag("yes")
+    && !confirm(&format!("Apply {} changes to {}?", pending, instance))?
+  {
+    return Ok(true);
+  }
+
+  // Applied versions. Failed resources keep their previous lock entry
+  let mut applied: BTreeMap<String, (Option<String>, String)> = BTreeMap::new();
+  let mut failed = 0;
+  for step in steps.iter() {
+    let item = match step.desired {
+      Some(index) => &desired[index],
+      None => continue,
+    };
+    if step.action == Action::Unchanged {
+      let version = step
+        .current
+        .as_ref()
+        .and_then(version_id)
+        .map(str::to_string);
+      applied.insert(step.key.clone(), (version, content_hash(&item.resource)));
+      continue;
+    }
+
+    let response = client
+      .update_resource(
+        ApiFormat::Aidbox,
+        &item.resource_type,
+        &item.id,
+        &item.resource,
+        None,
+      )
+      .await?;
+    let sign = match step.action {
+      Action::Create => style("+").green().bold(),
+      _ => style("~").yellow().bold(),
+    };
+    if response.is_success() {
+      println!(
+        "  {} {}  {}",
+        sign,
+        step.key,
+        style(response.status).green()
+      );
+      let version = version_id(&response.body).map(str::to_string);
+      applied.insert(step.key.clone(), (version, content_hash(&item.resource)));
+    } else {
+      failed += 1;
+      println!(
+        "  {} {}  {}",
+        sign,
+        step.key,
+        style(response.status).red().bold()
+      );
+      match response.is_operation_outcome() {
+        true => format_outcome(&response.body)
+          .iter()
+          .for_each(|line| println!("      {}", line)),
+        false => println!("      {}", response.body),
+      }
+      if let Some(previous) = locked.get(&step.key) {
+        let version = previous
+          .get("versionId")
+          .and_then(Value::as_str)
+          .map(str::to_string);
+        let hash = previous
+          .get("hash")
+          .and_then(Value::as_str)
+          .unwrap_or_default();
+        applied.insert(step.key.clone(), (version, hash.to_string()));
+      }
+    }
+  }
+
+  if let Some(object) = lock.as_object_mut() {
+    object.insert(instance.to_string(), lock_entry(client.url(), &applied));
+  }
+  if let Err(err) = std::fs::write(&lock_path, pretty(&lock)) {
+    return Err(format!("Cannot write {}: {}", lock_path.display(), err));
+  }
+
+  if pending > 0 {
+    println!(
+      "{} {} of {} changes applied. Versions recorded in {}",
+      style("Apply").bold(),
+      pending - failed,
+      pending,
+      lock_path.display()
+    );
+  } else {
+    println!("Nothing to apply");
+  }
+  Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_plan() {
+    let desired: Vec<Desired> = [("a", "allow"), ("b", "sql"), ("c", "allow")]
+      .iter()
+      .map(|(id, engine)| Desired {
+        resource_type: "AccessPolicy".to_string(),
+        id: id.to_string(),
+        resource: json!({"resourceType": "AccessPolicy", "id": id, "engine": engine}),
+        file: format!("{}.yaml", id),
+      })
+      .collect();
+    let current: BTreeMap<String, Value> = [("a", "allow"), ("b", "json-schema"), ("d", "allow")]
+      .iter()
+      .map(|(id, engine)| {
+        (
+          format!("AccessPolicy/{}", id),
+          json!({"resourceType": "AccessPolicy", "id": id, "engine": engine, "meta": {"versionId": "2"}}),
+        )
+      })
+      .collect();
+
+    let actions: Vec<Action> = plan(&desired, &current)
+      .iter()
+      .map(|it| it.action)
+      .collect();
+    assert_eq!(
+      actions,
+      vec![
+        Action::Unchanged,
+        Action::Update,
+        Action::Create,
+        Action::Extra
+      ]
+    );
+  }
+
+  #[test]
+  fn test_file_resources() {
+    let bundle =
+      json!({"resourceType": "Bundle", "entry": [{"resource": {"id": "a"}}, {"request": {}}]});
+    assert_eq!(file_resources(bundle).len(), 1);
+    assert_eq!(file_resources(json!([{"id": "a"}, {"id": "b"}])).len(), 2);
+  }
+}
@@ -0,0 +1,551 @@
+use crate::aidbox::helpers::{connect, format_outcome, print_json};
+use async_stream::stream;
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use futures_util::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::error;
+use reqwest::header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Body, Method};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Bytes read from a file per request body chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Content types by file extension, used when the file content isn't recognized
+const CONTENT_TYPES: [(&str, &str); 19] = [
+  ("json", "application/json"),
+  ("ndjson", "application/x-ndjson"),
+  ("xml", "application/xml"),
+  ("txt", "text/plain"),
+  ("csv", "text/csv"),
+  ("html", "text/html"),
+  ("md", "text/markdown"),
+  ("pdf", "application/pdf"),
+  ("png", "image/png"),
+  ("jpg", "image/jpeg"),
+  ("jpeg", "image/jpeg"),
+  ("gif", "image/gif"),
+  ("svg", "image/svg+xml"),
+  ("dcm", "application/dicom"),
+  ("zip", "application/zip"),
+  ("gz", "application/gzip"),
+  ("doc", "application/msword"),
+  (
+    "docx",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+  ),
+  (
+    "xlsx",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+  ),
+];
+
+/// Leading bytes of formats which are recognized regardless of the extension
+const SIGNATURES: [(&[u8], &str); 6] = [
+  (b"%PDF-", "application/pdf"),
+  (b"\x89PNG\r\n\x1a\n", "image/png"),
+  (b"\xff\xd8\xff", "image/jpeg"),
+  (b"GIF8", "image/gif"),
+  (b"\x1f\x8b", "application/gzip"),
+  (b"DICM", "application/dicom"),
+];
+
+pub fn commands() -> Command {
+  Command::new("attachment")
+    .about("Upload and download files as FHIR Binary resources")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .subcommand(
+      Command::new("upload")
+        .about("Stream a file into a Binary resource")
+        .args(vec![
+          Arg::new("file")
+            .required(true)
+            .value_hint(ValueHint::FilePath)
+            .help("File to upload"),
+          Arg::new("id")
+            .long("id")
+            .help("Binary id. Generated by Aidbox when omitted"),
+          Arg::new("content-type")
+            .long("content-type")
+            .help("Content type instead of the detected one"),
+          Arg::new("format")
+            .long("format")
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .help("Output format. json prints an Attachment referencing the Binary"),
+        ]),
+    )
+    .subcommand(
+      Command::new("download")
+        .about("Stream a Binary resource or an attachment url into a file")
+        .args(vec![
+          Arg::new("source")
+            .required(true)
+            .help("Binary id, Binary/<id> or url. Example: Binary/report-1"),
+          Arg::new("output")
+            .short('o')
+            .long("output")
+            .value_hint(ValueHint::FilePath)
+            .help("Output file. Named after the response or the id when omitted"),
+          Arg::new("force")
+            .long("force")
+            .action(SetTrue)
+            .help("Overwrite an existing output file"),
+        ]),
+    )
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("upload", sub_matches) => upload(sub_matches).await,
+    ("download", sub_matches) => download(sub_matches).await,
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  if let Err(err) = result {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+/// Content type by the leading bytes of a file, then by its extension
+fn detect_content_type(path: &Path, head: &[u8]) -> &'static str {
+  if let Some((_, content_type)) = SIGNATURES
+    .iter()
+    .find(|(signature, _)| head.starts_with(signature))
+  {
+    return content_type;
+  }
+  // DICOM files start with a 128 bytes preamble
+  if head.len() >= 132 && &head[128..132] == b"DICM" {
+    return "application/dicom";
+  }
+  let extension = path
+    .extension()
+    .and_then(|it| it.to_str())
+    .map(str::to_lowercase)
+    .unwrap_or_default();
+  CONTENT_TYPES
+    .iter()
+    .find(|(it, _)| *it == extension)
+    .map(|(_, content_type)| *content_type)
+    .unwrap_or("application/octet-stream")
+}
+
+/// JSON and XML content. Sent raw to /fhir/Binary, the server would parse it as a resource
+/// instead of storing it
+fn is_structured(content_type: &str) -> bool {
+  let essence = content_type
+    .split(';')
+    .next()
+    .unwrap_or_default()
+    .trim()
+    .to_lowercase();
+  ["/json", "+json", "/xml", "+xml"]
+    .iter()
+    .any(|it| essence.ends_with(it))
+}
+
+fn extension_for(content_type: &str) -> Option<&'static str> {
+  let content_type = content_type.split(';').next().unwrap_or_default().trim();
+  CONTENT_TYPES
+    .iter()
+    .find(|(_, it)| *it == content_type)
+    .map(|(extension, _)| *extension)
+}
+
+fn bytes_bar(length: Option<u64>) -> ProgressBar {
+  match length {
+    Some(length) => ProgressBar::new(length).with_style(
+      ProgressStyle::with_template(
+        "{spinner:.cyan} [{bar:50.cyan/white}] {bytes}/{total_bytes} {bytes_per_sec} {msg}",
+      )
+      .unwrap()
+      .progress_chars("=>-"),
+    ),
+    None => ProgressBar::new_spinner().with_style(
+      ProgressStyle::with_template("{spinner:.cyan} {bytes} {bytes_per_sec} {msg}").unwrap(),
+    ),
+  }
+}
+
+async fn upload(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let path = PathBuf::from(sub_matches.get_one::<String>("file").unwrap());
+  let mut file =
+    File::open(&path).map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+  let size = file
+    .metadata()
+    .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?
+    .len();
+
+  let mut head = vec![0u8; 132];
+  let read = file
+    .read(&mut head)
+    .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+  head.truncate(read);
+  let content_type = match sub_matches.get_one::<String>("content-type") {
+    Some(it) => it.to_string(),
+    None => detect_content_type(&path, &head).to_string(),
+  };
+  let name = path
+    .file_name()
+    .map(|it| it.to_string_lossy().to_string())
+    .unwrap_or_default();
+
+  let client = connect(instance).await?;
+  let id = sub_matches.get_one::<String>("id");
+  let builder = match id {
+    Some(id) => {
+      client
+        .request(Method::PUT, &format!("/fhir/Binary/{}", id))
+        .await?
+    },
+    None => client.request(Method::POST, "/fhir/Binary").await?,
+  };
+
+  let response = match is_structured(&content_type) {
+    // Wrapped in a Binary resource with base64 data, so it's stored as content
+    true => {
+      let mut data = head;
+      file
+        .read_to_end(&mut data)
+        .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+      let mut binary = json!({
+        "resourceType": "Binary",
+        "contentType": content_type,
+        "data": base64::encode(&data),
+      });
+      if let Some(id) = id {
+        binary["id"] = json!(id);
+      }
+      client
+        .send(
+          builder
+            .header(CONTENT_TYPE, "application/fhir+json")
+            .body(binary.to_string()),
+        )
+        .await
+    },
+    false => {
+      let pb = bytes_bar(Some(size));
+      pb.set_message(name.clone());
+      let progress = pb.clone();
+      let chunks = stream! {
+        yield Ok::<Vec<u8>, std::io::Error>(head);
+        progress.inc(read as u64);
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+          match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => {
+              yield Ok(buffer[..count].to_vec());
+              progress.inc(count as u64);
+            },
+            Err(err) => {
+              yield Err(err);
+              break;
+            },
+          }
+        }
+      };
+      let response = client
+        .send(
+          builder
+            .header(CONTENT_TYPE, &content_type)
+            .header(CONTENT_LENGTH, size)
+            .body(Body::wrap_stream(chunks)),
+        )
+        .await;
+      pb.finish_and_clear();
+      response
+    },
+  };
+  let response = response?;
+  if !response.is_success() {
+    return Err(match response.is_operation_outcome() {
+      true => format!(
+        "Upload failed with HTTP {}\n{}",
+        response.status,
+        format_outcome(&response.body).join("\n")
+      ),
+      false => format!(
+        "Upload failed with HTTP {} {}",
+        response.status, response.body
+      ),
+    });
+  }
+
+  let id = response
+    .body
+    .get("id")
+    .and_then(Value::as_str)
+    .map(str::to_string)
+    .or_else(|| id.cloned())
+    .unwrap_or_default();
+  if sub_matches.get_one::<String>("format").unwrap() == "json" {
+    print_json(&json!({
+      "contentType": content_type,
+      "url": format!("Binary/{}", id),
+      "size": size,
+      "title": name,
+    }));
+    return Ok(());
+  }
+  println!(
+    "{} {} as {} {}",
+    style("Uploaded").green().bold(),
+    name,
+    style(format!("Binary/{}", id)).cyan(),
+    style(format!("{}, {} bytes", content_type, size)).dim()
+  );
+  Ok(())
+}
+
+/// File name from `Content-Disposition: attachment; filename="a.pdf"`
+fn disposition_filename(value: &str) -> Option<String> {
+  value.split(';').find_map(|part| {
+    let (key, name) = part.trim().split_once('=')?;
+    match key.trim().eq_ignore_ascii_case("filename") {
+      true => Some(name.trim().trim_matches('"').to_string()),
+      false => None,
+    }
+  })
+}
+
+/// Output file name: Content-Disposition, then the last url segment with an extension by content type
+fn output_name(source: &str, disposition: Option<&str>, content_type: Option<&str>) -> String {
+  if let Some(name) = disposition.and_then(disposition_filename) {
+    // Only the name, a server must not pick the directory
+    if let Some(name) = Path::new(&name).file_name() {
+      return name.to_string_lossy().to_string();
+    }
+  }
+  let name = source
+    .split('?')
+    .next()
+    .unwrap_or_default()
+    .trim_end_matches('/')
+    .rsplit('/')
+    .next()
+    .filter(|it| !it.is_empty())
+    .unwrap_or("attachment")
+    .to_string();
+  match (
+    Path::new(&name).extension(),
+    content_type.and_then(extension_for),
+  ) {
+    (None, Some(extension)) => format!("{}.{}", name, extension),
+    _ => name,
+  }
+}
+
+fn create_output(path: &Path, force: bool) -> Result<File, String> {
+  if path.exists() && !force {
+    return Err(format!(
+      "{} already exists. Use --force to overwrite it",
+      path.display()
+    ));
+  }
+  File::create(path).map_err(|err| format!("Cannot create {}: {}", path.display(), err))
+}
+
+/// Write the whole content, removing the file when it fails part way
+fn write_output(path: &Path, force: bool, data: &[u8]) -> Result<(), String> {
+  if let Err(err) = create_output(path, force)?.write_all(data) {
+    let _ = std::fs::remove_file(path);
+    return Err(format!("Cannot write {}: {}", path.display(), err));
+  }
+  Ok(())
+}
+
+async fn download(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let source = sub_matches.get_one::<String>("source").unwrap();
+  let force = sub_matches.get_flag("force");
+
+  let client = connect(instance).await?;
+  let is_url = source.starts_with("http://") || source.starts_with("https://");
+  let builder = match is_url {
+    // Signed storage urls carry their own credentials
+    true if !client.is_box_url(source) => reqwest::Client::new().get(source),
+    true => client.request_link(Method::GET, source).await?,
+    false => {
+      let id = source.trim_start_matches("Binary/");
+      client
+        .request(Method::GET, &format!("/fhir/Binary/{}", id))
+        .await?
+    },
+  };
+  let response = builder
+    .header(ACCEPT, "*/*")
+    .send()
+    .await
+    .map_err(|err| err.to_string())?;
+  let status = response.status().as_u16();
+  if !response.status().is_success() {
+    let text = response.text().await.unwrap_or_default();
+    return Err(match serde_json::from_str::<Value>(&text) {
+      Ok(body) if body.get("resourceType").and_then(Value::as_str) == Some("OperationOutcome") => {
+        format!("HTTP {}\n{}", status, format_outcome(&body).join("\n"))
+      },
+      _ => format!("HTTP {} {}", status, text),
+    });
+  }
+
+  let header = |name| {
+    response
+      .headers()
+      .get(name)
+      .and_then(|it: &reqwest::header::HeaderValue| it.to_str().ok())
+      .map(str::to_string)
+  };
+  let content_type = header(CONTENT_TYPE);
+  let disposition = header(CONTENT_DISPOSITION);
+
+  // A Binary resource instead of its content: the content is base64 in `data`
+  if !is_url
+    && content_type
+      .as_deref()
+      .is_some_and(|it| it.contains("json"))
+  {
+    let body: Value = response.json().await.map_err(|err| err.to_string())?;
+    if body.get("resourceType").and_then(Value::as_str) == Some("Binary") {
+      let data = base64::decode(body.get("data").and_then(Value::as_str).unwrap_or_default())
+        .map_err(|err| format!("Invalid Binary.data: {}", err))?;
+      let binary_type = body.get("contentType").and_then(Value::as_str);
+      let path = match sub_matches.get_one::<String>("output") {
+        Some(it) => PathBuf::from(it),
+        None => PathBuf::from(output_name(source, None, binary_type)),
+      };
+      write_output(&path, force, &data)?;
+      print_saved(&path, binary_type, data.len() as u64);
+      return Ok(());
+    }
+    let path = match sub_matches.get_one::<String>("output") {
+      Some(it) => PathBuf::from(it),
+      None => PathBuf::from(output_name(source, None, content_type.as_deref())),
+    };
+    write_output(&path, force, body.to_string().as_bytes())?;
+    print_saved(
+      &path,
+      content_type.as_deref(),
+      body.to_string().len() as u64,
+    );
+    return Ok(());
+  }
+
+  let path = match sub_matches.get_one::<String>("output") {
+    Some(it) => PathBuf::from(it),
+    None => PathBuf::from(output_name(
+      source,
+      disposition.as_deref(),
+      content_type.as_deref(),
+    )),
+  };
+  let mut output = create_output(&path, force)?;
+  let pb = bytes_bar(response.content_length());
+  pb.set_message(path.display().to_string());
+  let mut bytes = 0u64;
+  let mut stream = response.bytes_stream();
+  while let Some(chunk) = stream.next().await {
+    let written = match chunk {
+      Ok(chunk) => output
+        .write_all(&chunk)
+        .map(|_| chunk.len() as u64)
+        .map_err(|err| format!("Cannot write {}: {}", path.display(), err)),
+      Err(err) => Err(err.to_string()),
+    };
+    match written {
+      Ok(count) => {
+        bytes += count;
+        pb.inc(count);
+      },
+      Err(err) => {
+        pb.abandon();
+        // A partial file would look like a complete download
+        drop(output);
+        let _ = std::fs::remove_file(&path);
+        return Err(err);
+      },
+    }
+  }
+  pb.finish_and_clear();
+  print_saved(&path, content_type.as_deref(), bytes);
+  Ok(())
+}
+
+fn print_saved(path: &Path, content_type: Option<&str>, bytes: u64) {
+  println!(
+    "{} {} {}",
+    style("Saved").green().bold(),
+    path.display(),
+    style(format!(
+      "{}, {} bytes",
+      content_type.unwrap_or("unknown type"),
+      bytes
+    ))
+    .dim()
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_detect_content_type() {
+    assert_eq!(
+      detect_content_type(Path::new("scan.bin"), b"%PDF-1.7\n"),
+      "application/pdf"
+    );
+    assert_eq!(
+      detect_content_type(Path::new("notes.TXT"), b"hello"),
+      "text/plain"
+    );
+    assert_eq!(
+      detect_content_type(Path::new("data"), b"hello"),
+      "application/octet-stream"
+    );
+  }
+
+  #[test]
+  fn test_is_structured() {
+    assert!(is_structured("application/json"));
+    assert!(is_structured("application/fhir+xml; charset=utf-8"));
+    assert!(!is_structured("application/x-ndjson"));
+    assert!(!is_structured("application/pdf"));
+  }
+
+  #[test]
+  fn test_output_name() {
+    assert_eq!(
+      output_name(
+        "Binary/1",
+        Some("attachment; filename=\"../report.pdf\""),
+        None
+      ),
+      "report.pdf"
+    );
+    assert_eq!(
+      output_name("Binary/scan-1", None, Some("image/png; charset=binary")),
+      "scan-1.png"
+    );
+    assert_eq!(
+      output_name(
+        "https://storage/bucket/a.csv?sig=1",
+        None,
+        Some("text/plain")
+      ),
+      "a.csv"
+    );
+  }
+}
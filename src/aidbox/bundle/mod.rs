@@ -0,0 +1,291 @@
+use crate::aidbox::helpers::{collect_files, connect, format_outcome, read_value_file};
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use log::error;
+use serde_json::{json, Value};
+use std::path::Path;
+use tool_aidbox::rest::{entry_request, entry_status, ApiFormat};
+
+pub fn commands() -> Command {
+  Command::new("bundle")
+    .about("Work with transaction and batch bundles")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .subcommand(
+      Command::new("apply")
+        .about("Send a Bundle, or build a transaction from a directory of JSON/YAML resources")
+        .args(vec![
+          Arg::new("path")
+            .required(true)
+            .value_hint(ValueHint::AnyPath)
+            .help("Bundle or resource file, or a directory of them. Use - for stdin"),
+          Arg::new("type")
+            .long("type")
+            .value_parser(["transaction", "batch"])
+            .help("Bundle type. Defaults to the type of the Bundle file or transaction"),
+          Arg::new("dry-run")
+            .long("dry-run")
+            .action(SetTrue)
+            .help("Print planned requests without sending them"),
+          Arg::new("fhir")
+            .long("fhir")
+            .action(SetTrue)
+            .help("Send to /fhir instead of Aidbox endpoint"),
+        ]),
+    )
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("apply", sub_matches) => apply(sub_matches).await,
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  match result {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+/// Bundle entry with the file (and `#index` for Bundle files) it came from
+type SourcedEntry = (Value, String);
+
+/// Entries of a Bundle or a single resource, with `request` filled in
+fn read_entries(value: Value, source: &str) -> Result<(Option<String>, Vec<SourcedEntry>), String> {
+  if value.get("resourceType").and_then(Value::as_str) != Some("Bundle") {
+    let request = entry_request(&value, None).map_err(|err| format!("{}: {}", source, err))?;
+    return Ok((
+      None,
+      vec![(
+        json!({"resource": value, "request": request}),
+        source.to_string(),
+      )],
+    ));
+  }
+
+  let bundle_type = value
+    .get("type")
+    .and_then(Value::as_str)
+    .map(str::to_string);
+  let mut entries = vec![];
+  for (index, entry) in value
+    .get("entry")
+    .and_then(Value::as_array)
+    .cloned()
+    .unwrap_or_default()
+    .into_iter()
+    .enumerate()
+  {
+    let label = format!("{}#{}", source, index);
+    let entry = match entry.get("request") {
+      Some(..) => entry,
+      None => {
+        let resource = entry.get("resource").cloned().unwrap_or(Value::Null);
+        let request =
+          entry_request(&resource, None).map_err(|err| format!("{}: {}", label, err))?;
+        json!({"resource": resource, "request": request})
+      },
+    };
+    entries.push((entry, label));
+  }
+  Ok((bundle_type, entries))
+}
+
+fn request_label(entry: &Value) -> String {
+  let request = entry.get("request");
+  format!(
+    "{} {}",
+    request
+      .and_then(|it| it.get("method"))
+      .and_then(Value::as_str)
+      .unwrap_or("?"),
+    request
+      .and_then(|it| it.get("url"))
+      .and_then(Value::as_str)
+      .unwrap_or("?")
+  )
+}
+
+/// `entry.response.location`, or the type/id of the returned resource
+fn response_label(entry: &Value) -> String {
+  if let Some(location) = entry
+    .get("response")
+    .and_then(|it| it.get("location"))
+    .and_then(Value::as_str)
+  {
+    return location.to_string();
+  }
+  match entry.get("resource") {
+    Some(resource) => match (
+      resource.get("resourceType").and_then(Value::as_str),
+      resource.get("id").and_then(Value::as_str),
+    ) {
+      (Some(resource_type), Some(id)) => format!("{}/{}", resource_type, id),
+      _ => String::new(),
+    },
+    None => String::new(),
+  }
+}
+
+/// Returns `false` when the bundle or some entries failed
+async fn apply(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let path = sub_matches.get_one::<String>("path").unwrap();
+  let format = match sub_matches.get_flag("fhir") {
+    true => ApiFormat::Fhir,
+    false => ApiFormat::Aidbox,
+  };
+
+  let mut file_type: Option<String> = None;
+  let mut entries: Vec<SourcedEntry> = vec![];
+  let files = match path.as_str() {
+    "-" => vec![path.to_string()],
+    _ => collect_files(Path::new(path), &["json", "yaml", "yml"])?
+      .into_iter()
+      .map(|it| it.display().to_string())
+      .collect(),
+  };
+  if files.is_empty() {
+    return Err(format!("No JSON or YAML files in {}", path));
+  }
+  for file in files.iter() {
+    let (bundle_type, file_entries) = read_entries(read_value_file(file)?, file)?;
+    if files.len() == 1 {
+      file_type = bundle_type;
+    }
+    entries.extend(file_entries);
+  }
+
+  let bundle_type = sub_matches
+    .get_one::<String>("type")
+    .cloned()
+    .or(file_type.filter(|it| it == "transaction" || it == "batch"))
+    .unwrap_or_else(|| "transaction".to_string());
+
+  let width = entries
+    .iter()
+    .map(|(entry, _)| request_label(entry).len())
+    .max()
+    .unwrap_or_default();
+
+  if sub_matches.get_flag("dry-run") {
+    println!(
+      "{} {} with {} entries",
+      style("Plan").bold(),
+      bundle_type,
+      entries.len()
+    );
+    for (entry, source) in entries.iter() {
+      println!(
+        "  {:width$}  {}",
+        request_label(entry),
+        style(source).dim(),
+        width = width
+      );
+    }
+    return Ok(true);
+  }
+
+  let client = connect(instance).await?;
+  let bundle = json!({
+    "resourceType": "Bundle",
+    "type": bundle_type,
+    "entry": entries.iter().map(|(entry, _)| entry.clone()).collect::<Vec<_>>(),
+  });
+  let response = client.transaction(format, &bundle).await?;
+
+  if !response.is_success() {
+    eprintln!(
+      "{} {} HTTP {}",
+      style("Failed").red().bold(),
+      bundle_type,
+      response.status
+    );
+    match response.is_operation_outcome() {
+      true => format_outcome(&response.body)
+        .iter()
+        .for_each(|line| eprintln!("{}", line)),
+      false => eprintln!("{}", response.body),
+    }
+    return Ok(false);
+  }
+
+  let response_entries = response
+    .body
+    .get("entry")
+    .and_then(Value::as_array)
+    .cloned()
+    .unwrap_or_default();
+  let mut failed = 0;
+
+  for (index, (entry, source)) in entries.iter().enumerate() {
+    let response_entry = response_entries.get(index).cloned().unwrap_or(Value::Null);
+    let status = entry_status(&response_entry);
+    let status_label = match status {
+      Some(code) if (200..300).contains(&code) => style(code.to_string()).green(),
+      Some(code) => {
+        failed += 1;
+        style(code.to_string()).red().bold()
+      },
+      None => style("-".to_string()).dim(),
+    };
+    println!(
+      "  {:width$}  {}  {}",
+      request_label(entry),
+      status_label,
+      response_label(&response_entry),
+      width = width
+    );
+    if status.is_some_and(|code| !(200..300).contains(&code)) {
+      let outcome = response_entry
+        .get("response")
+        .and_then(|it| it.get("outcome"))
+        .or_else(|| response_entry.get("resource"));
+      if let Some(outcome) = outcome {
+        for line in format_outcome(outcome) {
+          println!("      {}", line);
+        }
+      }
+      println!("      {}", style(source).dim());
+    }
+  }
+
+  println!(
+    "{} {} of {} entries succeeded",
+    style(bundle_type).bold(),
+    entries.len() - failed,
+    entries.len()
+  );
+  Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_read_entries() {
+    let (bundle_type, entries) = read_entries(
+      json!({
+        "resourceType": "Bundle",
+        "type": "batch",
+        "entry": [
+          {"resource": {"resourceType": "Patient", "id": "1"}},
+          {"resource": {"resourceType": "Patient"}, "request": {"method": "POST", "url": "Patient"}}
+        ]
+      }),
+      "bundle.json",
+    )
+    .unwrap();
+    assert_eq!(bundle_type, Some("batch".to_string()));
+    assert_eq!(request_label(&entries[0].0), "PUT Patient/1");
+    assert_eq!(entries[1].1, "bundle.json#1");
+  }
+}
@@ -0,0 +1,298 @@
+use crate::aidbox::helpers::{
+  cell, confirm, connect, generate_secret, print_json, print_response, render_table,
+};
+use crate::aidbox::matches::prompt;
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command};
+use console::style;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use tool_aidbox::rest::{ApiFormat, PatchKind};
+use tool_config::{get_config_or_error, AuthMethod};
+
+/// Page size used to read every client
+const PAGE_SIZE: usize = 1000;
+
+/// Length of generated secrets
+const SECRET_LENGTH: usize = 32;
+
+pub fn commands() -> Command {
+  Command::new("clients")
+    .about("Manage Client resources")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .subcommand(
+      Command::new("list").about("List clients").arg(
+        Arg::new("format")
+          .long("format")
+          .value_parser(["text", "json"])
+          .default_value("text")
+          .help("Output format"),
+      ),
+    )
+    .subcommand(
+      Command::new("create")
+        .about("Create a client with a secret. A generated one is printed once")
+        .args(vec![
+          Arg::new("id").help("Client id. Prompted for when omitted"),
+          Arg::new("grant-types")
+            .long("grant-types")
+            .value_delimiter(',')
+            .default_value("basic,client_credentials")
+            .help("Comma separated grant types"),
+          Arg::new("secret")
+            .long("secret")
+            .help("Secret instead of a generated one"),
+        ]),
+    )
+    .subcommand(
+      Command::new("rotate-secret")
+        .about("Set a new secret. A generated one is printed once")
+        .args(vec![
+          Arg::new("id").required(true).help("Client id"),
+          Arg::new("secret")
+            .long("secret")
+            .help("New secret instead of a generated one"),
+          Arg::new("update-config")
+            .long("update-config")
+            .action(SetTrue)
+            .help("Save the new secret for the instance when it authenticates with this client"),
+        ]),
+    )
+    .subcommand(
+      Command::new("disable")
+        .about("Mark a client inactive")
+        .args(vec![
+          Arg::new("id").required(true).help("Client id"),
+          Arg::new("yes")
+            .short('y')
+            .long("yes")
+            .action(SetTrue)
+            .help("Disable without confirmation"),
+        ]),
+    )
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("list", sub_matches) => list(sub_matches).await,
+    ("create", sub_matches) => create(sub_matches).await,
+    ("rotate-secret", sub_matches) => rotate_secret(sub_matches).await,
+    ("disable", sub_matches) => disable(sub_matches).await,
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  if let Err(err) = result {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+/// Table row: id, grant types, status and last update
+fn row(client: &Value) -> Vec<String> {
+  let grant_types = client
+    .get("grant_types")
+    .and_then(Value::as_array)
+    .map(|items| items.iter().map(cell).collect::<Vec<_>>().join(","))
+    .unwrap_or_default();
+  let active = client.get("active").and_then(Value::as_bool) != Some(false);
+  vec![
+    cell(client.get("id").unwrap_or(&Value::Null)),
+    grant_types,
+    match active {
+      true => style("active").green().to_string(),
+      false => style("inactive").red().to_string(),
+    },
+    cell(client.pointer("/meta/lastUpdated").unwrap_or(&Value::Null)),
+  ]
+}
+
+async fn list(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let client = connect(instance).await?;
+  let clients = client
+    .search_all(ApiFormat::Aidbox, "Client", PAGE_SIZE)
+    .await?;
+
+  if sub_matches.get_one::<String>("format").unwrap() == "json" {
+    // Secrets stay on the server
+    let clients: Vec<Value> = clients
+      .into_iter()
+      .map(|mut it| {
+        if let Some(map) = it.as_object_mut() {
+          map.remove("secret");
+        }
+        it
+      })
+      .collect();
+    print_json(&Value::Array(clients));
+    return Ok(());
+  }
+  let headers: Vec<String> = ["id", "grant_types", "status", "lastUpdated"]
+    .iter()
+    .map(|it| style(it).bold().to_string())
+    .collect();
+  let rows: Vec<Vec<String>> = clients.iter().map(row).collect();
+  for line in render_table(&headers, &rows) {
+    println!("{}", line);
+  }
+  println!("{}", style(format!("{} clients", clients.len())).dim());
+  Ok(())
+}
+
+async fn create(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let id = match sub_matches.get_one::<String>("id") {
+    Some(id) => id.to_string(),
+    None if console::user_attended() => prompt::<String>("Client id", None),
+    None => return Err("Client id is required without a terminal".to_string()),
+  };
+  let (secret, generated) = match sub_matches.get_one::<String>("secret") {
+    Some(secret) => (secret.to_string(), false),
+    None => (generate_secret(SECRET_LENGTH), true),
+  };
+  let grant_types: Vec<&String> = sub_matches
+    .get_many::<String>("grant-types")
+    .unwrap()
+    .filter(|it| !it.is_empty())
+    .collect();
+
+  let client = connect(instance).await?;
+  let resource = json!({
+    "resourceType": "Client",
+    "id": id,
+    "secret": secret,
+    "grant_types": grant_types,
+  });
+  // POST fails on an existing id instead of replacing the client like PUT would
+  let response = client
+    .create_resource(ApiFormat::Aidbox, "Client", &resource)
+    .await?;
+  if response.status == 409 {
+    return Err(format!(
+      "Client/{} already exists. Use rotate-secret to change its secret",
+      id
+    ));
+  }
+  if !response.is_success() {
+    print_response(response);
+    return Ok(());
+  }
+
+  println!("{} Client/{}", style("Created").green().bold(), id);
+  if generated {
+    println!("Secret: {}", secret);
+  }
+  Ok(())
+}
+
+async fn rotate_secret(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let id = sub_matches.get_one::<String>("id").unwrap();
+  let (secret, generated) = match sub_matches.get_one::<String>("secret") {
+    Some(secret) => (secret.to_string(), false),
+    None => (generate_secret(SECRET_LENGTH), true),
+  };
+
+  let client = connect(instance).await?;
+  let response = client
+    .patch_resource(
+      ApiFormat::Aidbox,
+      "Client",
+      id,
+      &json!({ "secret": secret }),
+      PatchKind::MergePatch,
+      None,
+    )
+    .await?;
+  if !response.is_success() {
+    print_response(response);
+    return Ok(());
+  }
+  println!(
+    "{} secret of Client/{}",
+    style("Rotated").green().bold(),
+    id
+  );
+  if generated {
+    println!("Secret: {}", secret);
+  }
+
+  let (mut config, key) = get_config_or_error(instance)?;
+  let mut current = config.boxes.get(key).cloned().unwrap();
+  if current.client != *id || current.auth == AuthMethod::Bearer {
+    return Ok(());
+  }
+  let update = sub_matches.get_flag("update-config")
+    || (console::user_attended()
+      && confirm(&format!(
+        "Instance {} authenticates with Client/{}. Save the new secret in the local config?",
+        key, id
+      ))?);
+  if !update {
+    warn!(
+      "Instance {} still uses the old secret of Client/{}. Run `box configure` to update it",
+      key, id
+    );
+    return Ok(());
+  }
+  current.secret = secret;
+  info!(
+    "{}",
+    config.add_box(key.to_string(), current, key.to_string())
+  );
+  Ok(())
+}
+
+async fn disable(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let id = sub_matches.get_one::<String>("id").unwrap();
+  let (config, key) = get_config_or_error(instance)?;
+  if config.boxes.get(key).is_some_and(|it| it.client == *id) {
+    warn!(
+      "Instance {} authenticates with Client/{} and will lose access",
+      key, id
+    );
+  }
+  if !sub_matches.get_flag("yes") && !confirm(&format!("Disable Client/{}?", id))? {
+    return Ok(());
+  }
+
+  let client = connect(instance).await?;
+  let response = client
+    .patch_resource(
+      ApiFormat::Aidbox,
+      "Client",
+      id,
+      &json!({"active": false}),
+      PatchKind::MergePatch,
+      None,
+    )
+    .await?;
+  if !response.is_success() {
+    print_response(response);
+    return Ok(());
+  }
+  println!("{} Client/{}", style("Disabled").yellow().bold(), id);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_row() {
+    let row = row(&json!({
+      "id": "web",
+      "secret": "s",
+      "grant_types": ["basic", "code"],
+      "active": false,
+    }));
+    assert_eq!(row[0], "web");
+    assert_eq!(row[1], "basic,code");
+    assert!(row[2].contains("inactive"));
+  }
+}
@@ -0,0 +1,393 @@
+use crate::aidbox::helpers::{
+  cell, connect, content_hash, diff_lines, format_diff, print_json, resource_content, sort_keys,
+};
+use crate::aidbox::sync::DEFAULT_TYPES;
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command};
+use console::style;
+use log::error;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use tool_aidbox::rest::ApiFormat;
+use tool_aidbox::BoxClient;
+use tool_config::ExcludeConfig;
+
+/// Page size used to read every resource of a type
+const PAGE_SIZE: usize = 1000;
+
+/// Unchanged lines shown around each change of a resource diff
+const DIFF_CONTEXT: usize = 2;
+
+/// Keys that hold credentials. They are compared but never printed
+const SECRET_KEYS: [&str; 2] = ["secret", "password"];
+
+pub fn commands() -> Command {
+  Command::new("diff")
+    .about(
+      "Compare version, zen namespaces and symbols, and configuration resources of two instances",
+    )
+    .args(vec![
+      Arg::new("left")
+        .required(true)
+        .help("Instance. Example: stage"),
+      Arg::new("right")
+        .required(true)
+        .help("Instance. Example: prod"),
+      Arg::new("types")
+        .long("types")
+        .value_delimiter(',')
+        .default_value(DEFAULT_TYPES)
+        .help("Comma separated resource types"),
+      Arg::new("details")
+        .long("details")
+        .action(SetTrue)
+        .help("Show the diff of resources that differ"),
+      Arg::new("format")
+        .long("format")
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .help("Output format"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  if let Err(err) = diff(sub_matches).await {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+/// Items only on one side, sorted
+#[derive(Debug, Default, PartialEq)]
+struct SetDiff {
+  only_left: Vec<String>,
+  only_right: Vec<String>,
+}
+
+impl SetDiff {
+  fn new(left: &[String], right: &[String]) -> Self {
+    let left: BTreeSet<&String> = left.iter().collect();
+    let right: BTreeSet<&String> = right.iter().collect();
+    SetDiff {
+      only_left: left.difference(&right).map(|it| it.to_string()).collect(),
+      only_right: right.difference(&left).map(|it| it.to_string()).collect(),
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.only_left.len() + self.only_right.len()
+  }
+
+  fn to_json(&self) -> Value {
+    json!({"onlyLeft": self.only_left, "onlyRight": self.only_right})
+  }
+}
+
+/// Resources of a type on both sides, by `Type/id`
+#[derive(Debug, Default)]
+struct ResourceDiff {
+  ids: SetDiff,
+  /// Keys with different content, with both versions without `meta` and with secrets masked
+  different: Vec<(String, Value, Value)>,
+}
+
+impl ResourceDiff {
+  fn new(resource_type: &str, left: &[Value], right: &[Value]) -> Self {
+    let by_id = |resources: &[Value]| -> BTreeMap<String, Value> {
+      resources
+        .iter()
+        .filter_map(|it| {
+          let id = it.get("id")?.as_str()?;
+          Some((format!("{}/{}", resource_type, id), it.clone()))
+        })
+        .collect()
+    };
+    let left = by_id(left);
+    let right = by_id(right);
+    ResourceDiff {
+      ids: SetDiff::new(
+        &left.keys().cloned().collect::<Vec<_>>(),
+        &right.keys().cloned().collect::<Vec<_>>(),
+      ),
+      different: left
+        .iter()
+        .filter_map(|(id, resource)| {
+          let other = right.get(id)?;
+          match content_hash(resource) == content_hash(other) {
+            true => None,
+            false => {
+              let (mut left, mut right) = (resource_content(resource), resource_content(other));
+              mask_secrets(&mut left, &mut right);
+              Some((id.to_string(), left, right))
+            },
+          }
+        })
+        .collect(),
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.ids.len() + self.different.len()
+  }
+}
+
+/// Replaces `Client.secret`, `User.password` and alike on both sides, so `--details` shows
+/// whether they differ but never the values
+fn mask_secrets(left: &mut Value, right: &mut Value) {
+  for key in SECRET_KEYS {
+    let mask = match left.get(key) == right.get(key) {
+      true => "<hidden>",
+      false => "<hidden, differs>",
+    };
+    for resource in [&mut *left, &mut *right] {
+      if let Some(value) = resource.as_object_mut().and_then(|it| it.get_mut(key)) {
+        *value = json!(mask);
+      }
+    }
+  }
+}
+
+/// `$version` keys with different values
+fn version_diff(left: &Value, right: &Value) -> Vec<(String, Value, Value)> {
+  let empty = Map::new();
+  let left = left.as_object().unwrap_or(&empty);
+  let right = right.as_object().unwrap_or(&empty);
+  let keys: BTreeSet<&String> = left.keys().chain(right.keys()).collect();
+  keys
+    .into_iter()
+    .filter_map(|key| {
+      let (a, b) = (
+        left.get(key).cloned().unwrap_or(Value::Null),
+        right.get(key).cloned().unwrap_or(Value::Null),
+      );
+      match a == b {
+        true => None,
+        false => Some((key.to_string(), a, b)),
+      }
+    })
+    .collect()
+}
+
+/// Symbols read from the box. The cache of `generate types` is skipped, as it may be stale and
+/// filtered by the user's exclusions
+async fn symbols(instance: &str, client: &BoxClient) -> Result<Vec<String>, String> {
+  let exclude = ExcludeConfig {
+    ns: None,
+    symbols: None,
+    tags: None,
+  };
+  client
+    .read_all_symbols(&exclude)
+    .await
+    .map_err(|err| format!("{}: cannot load symbols: {}", instance, err))
+}
+
+/// Section title with the number of differences, then items only on one side
+fn print_set(title: &str, differences: usize, diff: &SetDiff, left: &str, right: &str) {
+  println!("{} {}", style(title).bold(), summary(differences));
+  for item in diff.only_left.iter() {
+    println!(
+      "  {} {}  {}",
+      style("<").red(),
+      item,
+      style(format!("only in {}", left)).dim()
+    );
+  }
+  for item in diff.only_right.iter() {
+    println!(
+      "  {} {}  {}",
+      style(">").green(),
+      item,
+      style(format!("only in {}", right)).dim()
+    );
+  }
+}
+
+fn summary(differences: usize) -> String {
+  match differences {
+    0 => style("same").green().to_string(),
+    count => style(format!("{} differences", count)).yellow().to_string(),
+  }
+}
+
+async fn diff(sub_matches: &ArgMatches) -> Result<(), String> {
+  let left_name = sub_matches.get_one::<String>("left").unwrap();
+  let right_name = sub_matches.get_one::<String>("right").unwrap();
+  let types: Vec<&String> = sub_matches
+    .get_many::<String>("types")
+    .unwrap()
+    .filter(|it| !it.is_empty())
+    .collect();
+
+  let left = connect(left_name).await?;
+  let right = connect(right_name).await?;
+
+  let version = version_diff(
+    &left
+      .get_box_version()
+      .await
+      .map_err(|err| format!("{}: {}", left_name, err))?,
+    &right
+      .get_box_version()
+      .await
+      .map_err(|err| format!("{}: {}", right_name, err))?,
+  );
+  let namespaces = SetDiff::new(
+    &left
+      .get_namespaces()
+      .await
+      .map_err(|err| format!("{}: {}", left_name, err))?,
+    &right
+      .get_namespaces()
+      .await
+      .map_err(|err| format!("{}: {}", right_name, err))?,
+  );
+  let symbols = SetDiff::new(
+    &symbols(left_name, &left).await?,
+    &symbols(right_name, &right).await?,
+  );
+  let mut resources: Vec<(String, ResourceDiff)> = vec![];
+  for resource_type in types {
+    let left_resources = left
+      .search_all(ApiFormat::Aidbox, resource_type, PAGE_SIZE)
+      .await
+      .map_err(|err| format!("{}: {}", left_name, err))?;
+    let right_resources = right
+      .search_all(ApiFormat::Aidbox, resource_type, PAGE_SIZE)
+      .await
+      .map_err(|err| format!("{}: {}", right_name, err))?;
+    resources.push((
+      resource_type.to_string(),
+      ResourceDiff::new(resource_type, &left_resources, &right_resources),
+    ));
+  }
+
+  if sub_matches.get_one::<String>("format").unwrap() == "json" {
+    print_json(&json!({
+      "left": {"instance": left_name, "url": left.url()},
+      "right": {"instance": right_name, "url": right.url()},
+      "version": version
+        .iter()
+        .map(|(key, a, b)| json!({"key": key, "left": a, "right": b}))
+        .collect::<Vec<_>>(),
+      "namespaces": namespaces.to_json(),
+      "symbols": symbols.to_json(),
+      "resources": resources
+        .iter()
+        .map(|(resource_type, diff)| {
+          let mut value = diff.ids.to_json();
+          value["different"] = json!(diff.different.iter().map(|(id, _, _)| id).collect::<Vec<_>>());
+          (resource_type.to_string(), value)
+        })
+        .collect::<Map<String, Value>>(),
+    }));
+    return Ok(());
+  }
+
+  println!(
+    "Comparing {} ({}) with {} ({})\n",
+    style(left_name).cyan().bold(),
+    left.url(),
+    style(right_name).cyan().bold(),
+    right.url()
+  );
+
+  println!("{} {}", style("Version").bold(), summary(version.len()));
+  for (key, a, b) in version.iter() {
+    println!(
+      "  {} {} {} {}",
+      key,
+      style(cell(a)).red(),
+      style("|").dim(),
+      style(cell(b)).green()
+    );
+  }
+  print_set(
+    "Namespaces",
+    namespaces.len(),
+    &namespaces,
+    left_name,
+    right_name,
+  );
+  print_set("Symbols", symbols.len(), &symbols, left_name, right_name);
+
+  let details = sub_matches.get_flag("details");
+  for (resource_type, diff) in resources.iter() {
+    print_set(resource_type, diff.len(), &diff.ids, left_name, right_name);
+    for (key, a, b) in diff.different.iter() {
+      println!(
+        "  {} {}  {}",
+        style("~").yellow(),
+        key,
+        style("differs").dim()
+      );
+      if details {
+        let (a, b) = (
+          serde_json::to_string_pretty(&sort_keys(a)).unwrap_or_default(),
+          serde_json::to_string_pretty(&sort_keys(b)).unwrap_or_default(),
+        );
+        for line in format_diff(&diff_lines(&a, &b), DIFF_CONTEXT) {
+          println!("      {}", line);
+        }
+      }
+    }
+  }
+
+  let total = version.len()
+    + namespaces.len()
+    + symbols.len()
+    + resources.iter().map(|(_, it)| it.len()).sum::<usize>();
+  println!(
+    "\n{} {}",
+    style("Total:").bold(),
+    match total {
+      0 => "no differences".to_string(),
+      count => format!("{} differences", count),
+    }
+  );
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_resource_diff() {
+    let left = vec![
+      json!({"id": "a", "engine": "allow", "meta": {"versionId": "1"}}),
+      json!({"id": "b", "engine": "sql"}),
+      json!({"id": "c"}),
+    ];
+    let right = vec![
+      json!({"id": "a", "engine": "allow", "meta": {"versionId": "5"}}),
+      json!({"id": "b", "engine": "matcho"}),
+      json!({"id": "d"}),
+    ];
+    let diff = ResourceDiff::new("AccessPolicy", &left, &right);
+    assert_eq!(diff.ids.only_left, vec!["AccessPolicy/c".to_string()]);
+    assert_eq!(diff.ids.only_right, vec!["AccessPolicy/d".to_string()]);
+    assert_eq!(diff.different.len(), 1);
+    assert_eq!(diff.different[0].0, "AccessPolicy/b");
+    assert_eq!(diff.len(), 3);
+
+    let diff = ResourceDiff::new(
+      "Client",
+      &[json!({"id": "app", "secret": "one", "grant_types": ["basic"]})],
+      &[json!({"id": "app", "secret": "two", "grant_types": ["basic"]})],
+    );
+    let (_, left, right) = &diff.different[0];
+    assert_eq!(left["secret"], json!("<hidden, differs>"));
+    assert_eq!(right["secret"], json!("<hidden, differs>"));
+  }
+
+  #[test]
+  fn test_version_diff() {
+    let diff = version_diff(
+      &json!({"version": "2304", "channel": "stable"}),
+      &json!({"version": "2310", "channel": "stable", "edition": "dev"}),
+    );
+    let keys: Vec<&str> = diff.iter().map(|(key, _, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["edition", "version"]);
+  }
+}
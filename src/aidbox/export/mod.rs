@@ -0,0 +1,526 @@
+use crate::aidbox::helpers::{connect, format_outcome};
+use chrono::Local;
+use clap::ArgAction::SetTrue;
+use clap::{value_parser, Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::error;
+use reqwest::header::{ACCEPT, CONTENT_LOCATION, RETRY_AFTER};
+use reqwest::{Method, RequestBuilder};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tool_aidbox::BoxClient;
+
+pub fn commands() -> Command {
+  Command::new("export")
+    .about("Bulk export with FHIR $export or Aidbox $dump into a directory of NDJSON files")
+    .args(vec![
+      Arg::new("dir")
+        .short('d')
+        .long("dir")
+        .required(true)
+        .value_hint(ValueHint::DirPath)
+        .help("Output directory. Created if missing"),
+      Arg::new("types")
+        .long("types")
+        .value_delimiter(',')
+        .help("Resource types (_type). Required for $dump. Example: Patient,Observation"),
+      Arg::new("since")
+        .long("since")
+        .help("Only resources changed after this instant (_since). Example: 2023-01-01T00:00:00Z"),
+      Arg::new("type-filter")
+        .long("type-filter")
+        .action(clap::ArgAction::Append)
+        .help("Search query per type (_typeFilter). Example: Patient?active=true"),
+      Arg::new("mode")
+        .long("mode")
+        .value_parser(["auto", "export", "dump"])
+        .default_value("auto")
+        .help("auto tries $export and falls back to $dump per type"),
+      Arg::new("gzip")
+        .long("gzip")
+        .action(SetTrue)
+        .help("Write .ndjson.gz files"),
+      Arg::new("poll")
+        .long("poll")
+        .value_parser(value_parser!(u64))
+        .default_value("5")
+        .help("Seconds between $export status checks unless the box sends Retry-After"),
+      Arg::new("fhir")
+        .long("fhir")
+        .action(SetTrue)
+        .help("$dump resources in FHIR format"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  if let Err(err) = export(sub_matches).await {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+/// Output of `$export` status or one `$dump` call
+struct ExportFile {
+  resource_type: String,
+  url: String,
+  authorized: bool,
+}
+
+struct Written {
+  file: String,
+  lines: u64,
+  bytes: u64,
+}
+
+enum Output {
+  Plain(BufWriter<File>),
+  Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Output {
+  fn create(path: &Path, gzip: bool) -> Result<Output, String> {
+    let file = match File::create(path) {
+      Ok(it) => BufWriter::new(it),
+      Err(err) => return Err(format!("Cannot create {}: {}", path.display(), err)),
+    };
+    Ok(match gzip {
+      true => Output::Gzip(GzEncoder::new(file, Compression::default())),
+      false => Output::Plain(file),
+    })
+  }
+
+  fn write_all(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+    match self {
+      Output::Plain(it) => it.write_all(chunk),
+      Output::Gzip(it) => it.write_all(chunk),
+    }
+  }
+
+  fn finish(self) -> std::io::Result<()> {
+    match self {
+      Output::Plain(mut it) => it.flush(),
+      Output::Gzip(it) => it.finish()?.flush(),
+    }
+  }
+}
+
+/// `Patient.ndjson`, then `Patient-2.ndjson` for the next file of the same type
+fn file_name(resource_type: &str, index: usize, gzip: bool) -> String {
+  let suffix = match gzip {
+    true => ".ndjson.gz",
+    false => ".ndjson",
+  };
+  match index {
+    0 => format!("{}{}", resource_type, suffix),
+    _ => format!("{}-{}{}", resource_type, index + 1, suffix),
+  }
+}
+
+async fn error_message(response: reqwest::Response) -> String {
+  let status = response.status().as_u16();
+  let text = response.text().await.unwrap_or_default();
+  match serde_json::from_str::<Value>(&text) {
+    Ok(body) if body.get("resourceType").and_then(Value::as_str) == Some("OperationOutcome") => {
+      format!("HTTP {}\n{}", status, format_outcome(&body).join("\n"))
+    },
+    _ => format!("HTTP {} {}", status, text),
+  }
+}
+
+async fn download(builder: RequestBuilder, target: &Path, gzip: bool) -> Result<Written, String> {
+  let response = match builder.send().await {
+    Ok(it) => it,
+    Err(err) => return Err(err.to_string()),
+  };
+  if !response.status().is_success() {
+    return Err(error_message(response).await);
+  }
+
+  let file = target.file_name().unwrap().to_string_lossy().to_string();
+  let pb = match response.content_length() {
+    Some(length) => ProgressBar::new(length).with_style(
+      ProgressStyle::with_template(
+        "{spinner:.cyan} [{bar:50.cyan/white}] {bytes}/{total_bytes} {msg}",
+      )
+      .unwrap()
+      .progress_chars("=>-"),
+    ),
+    None => ProgressBar::new_spinner().with_style(
+      ProgressStyle::with_template("{spinner:.cyan} {bytes} {bytes_per_sec} {msg}").unwrap(),
+    ),
+  };
+  pb.set_message(file.clone());
+
+  let mut output = Output::create(target, gzip)?;
+  let mut lines = 0u64;
+  let mut bytes = 0u64;
+  let mut last = b'\n';
+  let mut stream = response.bytes_stream();
+
+  while let Some(chunk) = stream.next().await {
+    let chunk = match chunk {
+      Ok(it) => it,
+      Err(err) => {
+        pb.abandon();
+        return Err(err.to_string());
+      },
+    };
+    if chunk.is_empty() {
+      continue;
+    }
+    lines += chunk.iter().filter(|it| **it == b'\n').count() as u64;
+    last = chunk[chunk.len() - 1];
+    bytes += chunk.len() as u64;
+    if let Err(err) = output.write_all(&chunk) {
+      pb.abandon();
+      return Err(format!("Cannot write {}: {}", target.display(), err));
+    }
+    pb.inc(chunk.len() as u64);
+  }
+  if last != b'\n' {
+    lines += 1;
+  }
+  if let Err(err) = output.finish() {
+    return Err(format!("Cannot write {}: {}", target.display(), err));
+  }
+  pb.finish_and_clear();
+
+  Ok(Written { file, lines, bytes })
+}
+
+enum Kickoff {
+  /// Status url to poll
+  Started(String),
+  NotSupported,
+}
+
+async fn kickoff(client: &BoxClient, params: &[(String, String)]) -> Result<Kickoff, String> {
+  let response = match client
+    .request(Method::GET, "/fhir/$export")
+    .await?
+    .query(params)
+    .header(ACCEPT, "application/fhir+json")
+    .header("Prefer", "respond-async")
+    .send()
+    .await
+  {
+    Ok(it) => it,
+    Err(err) => return Err(err.to_string()),
+  };
+
+  match response.status().as_u16() {
+    202 => match response
+      .headers()
+      .get(CONTENT_LOCATION)
+      .and_then(|it| it.to_str().ok())
+    {
+      Some(location) => Ok(Kickoff::Started(location.to_string())),
+      None => Err("$export accepted, but Content-Location is missing".to_string()),
+    },
+    404 | 405 | 501 => Ok(Kickoff::NotSupported),
+    _ => Err(error_message(response).await),
+  }
+}
+
+/// Poll the status url until the export manifest is ready
+async fn wait(client: &BoxClient, status_url: &str, poll: u64) -> Result<Value, String> {
+  let pb = ProgressBar::new_spinner()
+    .with_style(ProgressStyle::with_template("{spinner:.cyan} $export {msg} {elapsed}").unwrap());
+  pb.enable_steady_tick(Duration::from_millis(120));
+
+  loop {
+    let response = match client
+      .request_link(Method::GET, status_url)
+      .await?
+      .header(ACCEPT, "application/json")
+      .send()
+      .await
+    {
+      Ok(it) => it,
+      Err(err) => {
+        pb.abandon();
+        return Err(err.to_string());
+      },
+    };
+
+    match response.status().as_u16() {
+      202 => {
+        if let Some(progress) = response
+          .headers()
+          .get("X-Progress")
+          .and_then(|it| it.to_str().ok())
+        {
+          pb.set_message(progress.to_string());
+        }
+        let delay = response
+          .headers()
+          .get(RETRY_AFTER)
+          .and_then(|it| it.to_str().ok())
+          .and_then(|it| it.parse::<u64>().ok())
+          .unwrap_or(poll);
+        tokio::time::sleep(Duration::from_secs(delay.max(1))).await;
+      },
+      200 => {
+        pb.finish_and_clear();
+        return match response.json::<Value>().await {
+          Ok(it) => Ok(it),
+          Err(err) => Err(format!("Cannot read $export manifest: {}", err)),
+        };
+      },
+      _ => {
+        pb.abandon();
+        return Err(error_message(response).await);
+      },
+    }
+  }
+}
+
+fn manifest_files(manifest: &Value, key: &str) -> Vec<ExportFile> {
+  let authorized = manifest
+    .get("requiresAccessToken")
+    .and_then(Value::as_bool)
+    .unwrap_or(false);
+  manifest
+    .get(key)
+    .and_then(Value::as_array)
+    .map(|it| {
+      it.iter()
+        .filter_map(|output| {
+          Some(ExportFile {
+            resource_type: output.get("type")?.as_str()?.to_string(),
+            url: output.get("url")?.as_str()?.to_string(),
+            authorized,
+          })
+        })
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+async fn fetch(
+  client: &BoxClient,
+  files: Vec<ExportFile>,
+  dir: &Path,
+  gzip: bool,
+) -> Result<Vec<(ExportFile, Written)>, String> {
+  let mut per_type: HashMap<String, usize> = HashMap::new();
+  let mut result = vec![];
+
+  for export_file in files {
+    let index = per_type
+      .entry(export_file.resource_type.clone())
+      .or_default();
+    let target = dir.join(file_name(&export_file.resource_type, *index, gzip));
+    *index += 1;
+
+    let builder = match export_file.authorized {
+      true => client.request_link(Method::GET, &export_file.url).await?,
+      // Pre-signed storage urls reject foreign credentials
+      false => reqwest::Client::new().get(&export_file.url),
+    };
+    let written = download(builder, &target, gzip)
+      .await
+      .map_err(|err| format!("{} {}", export_file.resource_type, err))?;
+    eprintln!(
+      "{} {} ({} resources)",
+      style("Saved").green().bold(),
+      target.display(),
+      written.lines
+    );
+    result.push((export_file, written));
+  }
+  Ok(result)
+}
+
+fn manifest_entries(files: &[(ExportFile, Written)]) -> Vec<Value> {
+  files
+    .iter()
+    .map(|(export_file, written)| {
+      json!({
+        "type": export_file.resource_type,
+        "url": export_file.url,
+        "file": written.file,
+        "count": written.lines,
+        "bytes": written.bytes,
+      })
+    })
+    .collect()
+}
+
+async fn export(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let dir = PathBuf::from(sub_matches.get_one::<String>("dir").unwrap());
+  let types: Vec<String> = sub_matches
+    .get_many::<String>("types")
+    .unwrap_or_default()
+    .cloned()
+    .collect();
+  let since = sub_matches.get_one::<String>("since");
+  let type_filters: Vec<String> = sub_matches
+    .get_many::<String>("type-filter")
+    .unwrap_or_default()
+    .cloned()
+    .collect();
+  let mode = sub_matches.get_one::<String>("mode").unwrap().as_str();
+  let gzip = sub_matches.get_flag("gzip");
+  let poll = *sub_matches.get_one::<u64>("poll").unwrap();
+
+  if let Err(err) = std::fs::create_dir_all(&dir) {
+    return Err(format!("Cannot create {}: {}", dir.display(), err));
+  }
+
+  let client = connect(instance).await?;
+  let started = Local::now();
+
+  let mut params: Vec<(String, String)> = vec![];
+  if !types.is_empty() {
+    params.push(("_type".to_string(), types.join(",")));
+  }
+  if let Some(since) = since {
+    params.push(("_since".to_string(), since.to_string()));
+  }
+  for filter in type_filters.iter() {
+    params.push(("_typeFilter".to_string(), filter.to_string()));
+  }
+
+  let kickoff = match mode {
+    "dump" => Kickoff::NotSupported,
+    _ => kickoff(&client, &params).await?,
+  };
+
+  let mut manifest = match kickoff {
+    Kickoff::Started(status_url) => {
+      let status = wait(&client, &status_url, poll).await?;
+      let outputs = fetch(&client, manifest_files(&status, "output"), &dir, gzip).await?;
+
+      let errors_dir = dir.join("errors");
+      let error_files = manifest_files(&status, "error");
+      let errors = match error_files.is_empty() {
+        true => vec![],
+        false => {
+          if let Err(err) = std::fs::create_dir_all(&errors_dir) {
+            return Err(format!("Cannot create {}: {}", errors_dir.display(), err));
+          }
+          fetch(&client, error_files, &errors_dir, gzip).await?
+        },
+      };
+      for (_, written) in errors.iter().filter(|(_, it)| it.lines > 0) {
+        eprintln!(
+          "{} {} issue(s) in errors/{}",
+          style("Export reported").yellow().bold(),
+          written.lines,
+          written.file
+        );
+      }
+
+      json!({
+        "mode": "export",
+        "transactionTime": status.get("transactionTime"),
+        "request": status.get("request"),
+        "output": manifest_entries(&outputs),
+        "error": manifest_entries(&errors),
+      })
+    },
+    Kickoff::NotSupported => {
+      if mode == "export" {
+        return Err("$export is not supported by this box. Try --mode dump".to_string());
+      }
+      if types.is_empty() {
+        return Err("$dump works per resource type. Provide --types".to_string());
+      }
+      if !type_filters.is_empty() {
+        return Err("$dump doesn't support --type-filter".to_string());
+      }
+      if mode == "auto" {
+        log::warn!("$export is not available, using $dump");
+      }
+
+      let mut query = vec![];
+      if let Some(since) = since {
+        query.push(("_since".to_string(), since.to_string()));
+      }
+      if sub_matches.get_flag("fhir") {
+        query.push(("fhir".to_string(), "true".to_string()));
+      }
+
+      let mut outputs = vec![];
+      for resource_type in types.iter() {
+        let path = format!("/{}/$dump", resource_type);
+        let target = dir.join(file_name(resource_type, 0, gzip));
+        let written = download(
+          client.request(Method::GET, &path).await?.query(&query),
+          &target,
+          gzip,
+        )
+        .await
+        .map_err(|err| format!("{} {}", resource_type, err))?;
+        eprintln!(
+          "{} {} ({} resources)",
+          style("Saved").green().bold(),
+          target.display(),
+          written.lines
+        );
+        outputs.push((
+          ExportFile {
+            resource_type: resource_type.to_string(),
+            url: format!("{}{}", client.url(), path),
+            authorized: true,
+          },
+          written,
+        ));
+      }
+
+      json!({
+        "mode": "dump",
+        "transactionTime": started.to_rfc3339(),
+        "output": manifest_entries(&outputs),
+        "error": [],
+      })
+    },
+  };
+
+  manifest["instance"] = Value::String(instance.to_string());
+  manifest["since"] = json!(since);
+  manifest["types"] = json!(types);
+  manifest["typeFilter"] = json!(type_filters);
+  manifest["gzip"] = Value::Bool(gzip);
+
+  let manifest_path = dir.join("manifest.json");
+  match File::create(&manifest_path) {
+    Ok(file) => serde_json::to_writer_pretty(file, &manifest)
+      .map_err(|err| format!("Cannot write {}: {}", manifest_path.display(), err))?,
+    Err(err) => {
+      return Err(format!(
+        "Cannot create {}: {}",
+        manifest_path.display(),
+        err
+      ))
+    },
+  }
+  eprintln!(
+    "{} {}",
+    style("Manifest").green().bold(),
+    manifest_path.display()
+  );
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_file_name() {
+    assert_eq!(file_name("Patient", 0, false), "Patient.ndjson");
+    assert_eq!(file_name("Patient", 1, true), "Patient-2.ndjson.gz");
+  }
+}
@@ -0,0 +1,1093 @@
+use super::parser::Expr;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+
+/// Items `repeat()` collects before it gives up, so a projection that keeps producing new
+/// values can't loop forever
+const MAX_REPEAT_ITEMS: usize = 10_000;
+
+/// Item of a collection
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+  pub value: Value,
+  /// Type known from a choice element key. Example: `Quantity` of `valueQuantity`
+  pub type_name: Option<String>,
+}
+
+impl Item {
+  fn new(value: Value) -> Item {
+    Item {
+      value,
+      type_name: None,
+    }
+  }
+
+  /// Type shown next to a result
+  pub fn type_of(&self) -> String {
+    if let Some(name) = self.type_name.as_ref() {
+      return name.to_string();
+    }
+    match &self.value {
+      Value::Object(map) => map
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .unwrap_or("object")
+        .to_string(),
+      Value::Bool(..) => "boolean".to_string(),
+      Value::Number(number) if number.is_i64() || number.is_u64() => "integer".to_string(),
+      Value::Number(..) => "decimal".to_string(),
+      Value::String(..) => "string".to_string(),
+      _ => "null".to_string(),
+    }
+  }
+}
+
+type Collection = Vec<Item>;
+
+struct Env<'a> {
+  root: &'a Item,
+  /// Focus of the expression: the input, or the item of `where`, `select` and similar
+  this: Collection,
+  index: Option<usize>,
+}
+
+impl<'a> Env<'a> {
+  fn with(&self, item: Item, index: usize) -> Env<'a> {
+    Env {
+      root: self.root,
+      this: vec![item],
+      index: Some(index),
+    }
+  }
+}
+
+/// Evaluate a parsed expression with the resource as `%resource` and the initial focus
+pub fn evaluate(expr: &Expr, resource: &Value) -> Result<Vec<Item>, String> {
+  let root = Item::new(resource.clone());
+  let env = Env {
+    root: &root,
+    this: vec![root.clone()],
+    index: None,
+  };
+  eval(expr, &env)
+}
+
+fn eval(expr: &Expr, env: &Env) -> Result<Collection, String> {
+  match expr {
+    Expr::Empty => Ok(vec![]),
+    Expr::Literal(value) => Ok(vec![Item::new(value.clone())]),
+    Expr::This => Ok(env.this.clone()),
+    Expr::Index => Ok(
+      env
+        .index
+        .map(|it| Item::new(json!(it)))
+        .into_iter()
+        .collect(),
+    ),
+    Expr::Variable(name) => variable(name, env),
+    Expr::Member(None, name) if starts_upper(name) && env.this.iter().any(is_resource) => {
+      // A type name at the start of a path filters the focus by type
+      Ok(
+        env
+          .this
+          .iter()
+          .filter(|it| it.value.get("resourceType").and_then(Value::as_str) == Some(name))
+          .cloned()
+          .collect(),
+      )
+    },
+    Expr::Member(None, name) => Ok(member(&env.this, name)),
+    Expr::Member(Some(base), name) => Ok(member(&eval(base, env)?, name)),
+    Expr::Indexer(base, index) => {
+      let items = eval(base, env)?;
+      Ok(
+        integer(&eval(index, env)?)?
+          .and_then(|it| usize::try_from(it).ok())
+          .and_then(|it| items.get(it).cloned())
+          .into_iter()
+          .collect(),
+      )
+    },
+    Expr::Negate(inner) => eval(inner, env)?
+      .into_iter()
+      .map(|item| match item.value.as_i64() {
+        Some(number) => match number.checked_neg() {
+          Some(negated) => Ok(Item::new(json!(negated))),
+          None => Err(format!("Cannot negate {}, it overflows", number)),
+        },
+        None => match item.value.as_f64() {
+          Some(number) => Ok(Item::new(json!(-number))),
+          None => Err(format!("Cannot negate {}", item.value)),
+        },
+      })
+      .collect(),
+    Expr::TypeOp(base, operator, type_name) => {
+      let items = eval(base, env)?;
+      type_operation(operator, items, type_name)
+    },
+    Expr::Binary(left, operator, right) => binary(left, operator, right, env),
+    Expr::Function(base, name, args) => {
+      let input = match base {
+        Some(base) => eval(base, env)?,
+        None => env.this.clone(),
+      };
+      function(name, input, args, env)
+    },
+  }
+}
+
+fn starts_upper(name: &str) -> bool {
+  name.chars().next().is_some_and(char::is_uppercase)
+}
+
+fn is_resource(item: &Item) -> bool {
+  item.value.get("resourceType").is_some()
+}
+
+fn variable(name: &str, env: &Env) -> Result<Collection, String> {
+  Ok(match name {
+    "resource" | "context" | "rootResource" => vec![env.root.clone()],
+    "ucum" => vec![Item::new(json!("http://unitsofmeasure.org"))],
+    "sct" => vec![Item::new(json!("http://snomed.info/sct"))],
+    "loinc" => vec![Item::new(json!("http://loinc.org"))],
+    other => return Err(format!("Unknown variable %{}", other)),
+  })
+}
+
+fn push_values(result: &mut Collection, value: &Value, type_name: Option<&str>) {
+  let item = |value: &Value| Item {
+    value: value.clone(),
+    type_name: type_name.map(str::to_string),
+  };
+  match value {
+    Value::Null => {},
+    Value::Array(items) => result.extend(items.iter().filter(|it| !it.is_null()).map(item)),
+    other => result.push(item(other)),
+  }
+}
+
+/// Child elements by name. A choice element like `value` matches `valueQuantity`
+fn member(items: &[Item], name: &str) -> Collection {
+  let mut result = vec![];
+  for map in items.iter().filter_map(|it| it.value.as_object()) {
+    if let Some(value) = map.get(name) {
+      push_values(&mut result, value, None);
+      continue;
+    }
+    for (key, value) in map {
+      if let Some(suffix) = key.strip_prefix(name).filter(|it| starts_upper(it)) {
+        push_values(&mut result, value, Some(suffix));
+      }
+    }
+  }
+  result
+}
+
+fn children(items: &[Item]) -> Collection {
+  let mut result = vec![];
+  for map in items.iter().filter_map(|it| it.value.as_object()) {
+    for (key, value) in map {
+      if key != "resourceType" && !key.starts_with('_') {
+        push_values(&mut result, value, None);
+      }
+    }
+  }
+  result
+}
+
+fn is_type(item: &Item, type_name: &str) -> bool {
+  let name = type_name
+    .trim_start_matches("FHIR.")
+    .trim_start_matches("System.");
+  if let Some(resource_type) = item.value.get("resourceType").and_then(Value::as_str) {
+    return resource_type == name || name == "Resource" || name == "DomainResource";
+  }
+  if let Some(known) = item.type_name.as_ref() {
+    return known.eq_ignore_ascii_case(name);
+  }
+  let name = name.to_lowercase();
+  match &item.value {
+    Value::Bool(..) => name == "boolean",
+    Value::String(..) => matches!(
+      name.as_str(),
+      "string"
+        | "code"
+        | "id"
+        | "uri"
+        | "url"
+        | "canonical"
+        | "markdown"
+        | "oid"
+        | "uuid"
+        | "date"
+        | "datetime"
+        | "instant"
+        | "time"
+        | "base64binary"
+    ),
+    Value::Number(number) if number.is_i64() || number.is_u64() => matches!(
+      name.as_str(),
+      "integer" | "positiveint" | "unsignedint" | "decimal"
+    ),
+    Value::Number(..) => name == "decimal",
+    _ => false,
+  }
+}
+
+fn type_operation(
+  operator: &str,
+  items: Collection,
+  type_name: &str,
+) -> Result<Collection, String> {
+  match operator {
+    "is" => match items.as_slice() {
+      [] => Ok(vec![]),
+      [item] => Ok(vec![Item::new(json!(is_type(item, type_name)))]),
+      _ => Err(format!("'is' needs a single item, got {}", items.len())),
+    },
+    _ => Ok(
+      items
+        .into_iter()
+        .filter(|it| is_type(it, type_name))
+        .collect(),
+    ),
+  }
+}
+
+/// Type name given as a function argument. Example: `ofType(FHIR.Quantity)`
+fn type_arg(expr: &Expr) -> Result<String, String> {
+  match expr {
+    Expr::Member(None, name) => Ok(name.to_string()),
+    Expr::Member(Some(base), name) => Ok(format!("{}.{}", type_arg(base)?, name)),
+    _ => Err("Expected a type name".to_string()),
+  }
+}
+
+/// Singleton evaluation as a boolean: empty is unknown, any other single item is true
+fn boolean(items: &[Item]) -> Result<Option<bool>, String> {
+  match items {
+    [] => Ok(None),
+    [item] => Ok(Some(item.value.as_bool().unwrap_or(true))),
+    _ => Err(format!(
+      "Expected a single boolean, got {} items",
+      items.len()
+    )),
+  }
+}
+
+fn from_bool(value: Option<bool>) -> Collection {
+  value.map(|it| Item::new(json!(it))).into_iter().collect()
+}
+
+fn integer(items: &[Item]) -> Result<Option<i64>, String> {
+  match items {
+    [] => Ok(None),
+    [item] => match item.value.as_i64() {
+      Some(it) => Ok(Some(it)),
+      None => Err(format!("Expected an integer, got {}", item.value)),
+    },
+    _ => Err(format!(
+      "Expected a single integer, got {} items",
+      items.len()
+    )),
+  }
+}
+
+fn string(items: &[Item]) -> Result<Option<String>, String> {
+  match items {
+    [] => Ok(None),
+    [item] => match item.value.as_str() {
+      Some(it) => Ok(Some(it.to_string())),
+      None => Err(format!("Expected a string, got {}", item.value)),
+    },
+    _ => Err(format!(
+      "Expected a single string, got {} items",
+      items.len()
+    )),
+  }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+  match (a.as_f64(), b.as_f64()) {
+    (Some(a), Some(b)) => a == b,
+    _ => a == b,
+  }
+}
+
+fn equivalent(a: &Value, b: &Value) -> bool {
+  let normalize = |text: &str| {
+    text
+      .split_whitespace()
+      .collect::<Vec<_>>()
+      .join(" ")
+      .to_lowercase()
+  };
+  match (a, b) {
+    (Value::String(a), Value::String(b)) => normalize(a) == normalize(b),
+    _ => equal(a, b),
+  }
+}
+
+fn compare(a: &Value, b: &Value) -> Result<Ordering, String> {
+  match (a, b) {
+    (Value::Number(..), Value::Number(..)) => a
+      .as_f64()
+      .unwrap()
+      .partial_cmp(&b.as_f64().unwrap())
+      .ok_or_else(|| "Cannot compare NaN".to_string()),
+    (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+    _ => Err(format!("Cannot compare {} with {}", a, b)),
+  }
+}
+
+fn distinct(items: Collection) -> Collection {
+  let mut result: Collection = vec![];
+  for item in items {
+    if !result.iter().any(|it| equal(&it.value, &item.value)) {
+      result.push(item);
+    }
+  }
+  result
+}
+
+fn contains_value(items: &[Item], value: &Value) -> bool {
+  items.iter().any(|it| equal(&it.value, value))
+}
+
+fn arithmetic(operator: &str, a: &Value, b: &Value) -> Result<Option<Value>, String> {
+  if let (Some(a), Some(b), true) = (a.as_i64(), b.as_i64(), operator != "/") {
+    return Ok(match operator {
+      "+" => a.checked_add(b).map(|it| json!(it)),
+      "-" => a.checked_sub(b).map(|it| json!(it)),
+      "*" => a.checked_mul(b).map(|it| json!(it)),
+      "div" => a.checked_div(b).map(|it| json!(it)),
+      _ => a.checked_rem(b).map(|it| json!(it)),
+    });
+  }
+  let (Some(x), Some(y)) = (a.as_f64(), b.as_f64()) else {
+    return match (operator, a, b) {
+      ("+", Value::String(a), Value::String(b)) => Ok(Some(json!(format!("{}{}", a, b)))),
+      _ => Err(format!("Cannot apply '{}' to {} and {}", operator, a, b)),
+    };
+  };
+  let result = match operator {
+    "+" => x + y,
+    "-" => x - y,
+    "*" => x * y,
+    _ if y == 0.0 => return Ok(None),
+    "/" => x / y,
+    "div" => (x / y).trunc(),
+    _ => x % y,
+  };
+  Ok(Some(json!(result)))
+}
+
+fn binary(left: &Expr, operator: &str, right: &Expr, env: &Env) -> Result<Collection, String> {
+  let a = eval(left, env)?;
+  let b = eval(right, env)?;
+  match operator {
+    "and" => Ok(from_bool(match (boolean(&a)?, boolean(&b)?) {
+      (Some(false), _) | (_, Some(false)) => Some(false),
+      (Some(true), Some(true)) => Some(true),
+      _ => None,
+    })),
+    "or" => Ok(from_bool(match (boolean(&a)?, boolean(&b)?) {
+      (Some(true), _) | (_, Some(true)) => Some(true),
+      (Some(false), Some(false)) => Some(false),
+      _ => None,
+    })),
+    "xor" => Ok(from_bool(match (boolean(&a)?, boolean(&b)?) {
+      (Some(x), Some(y)) => Some(x != y),
+      _ => None,
+    })),
+    "implies" => Ok(from_bool(match (boolean(&a)?, boolean(&b)?) {
+      (Some(false), _) | (_, Some(true)) => Some(true),
+      (Some(true), other) => other,
+      (None, _) => None,
+    })),
+    "|" => Ok(distinct(a.into_iter().chain(b).collect())),
+    "=" | "!=" => {
+      if a.is_empty() || b.is_empty() {
+        return Ok(vec![]);
+      }
+      let same = a.len() == b.len()
+        && a
+          .iter()
+          .zip(b.iter())
+          .all(|(x, y)| equal(&x.value, &y.value));
+      Ok(from_bool(Some(same == (operator == "="))))
+    },
+    "~" | "!~" => {
+      let same = a.len() == b.len()
+        && a
+          .iter()
+          .all(|x| b.iter().any(|y| equivalent(&x.value, &y.value)));
+      Ok(from_bool(Some(same == (operator == "~"))))
+    },
+    "<" | ">" | "<=" | ">=" => match (a.as_slice(), b.as_slice()) {
+      ([x], [y]) => {
+        let order = compare(&x.value, &y.value)?;
+        Ok(from_bool(Some(match operator {
+          "<" => order == Ordering::Less,
+          ">" => order == Ordering::Greater,
+          "<=" => order != Ordering::Greater,
+          _ => order != Ordering::Less,
+        })))
+      },
+      ([], _) | (_, []) => Ok(vec![]),
+      _ => Err(format!("'{}' needs single items", operator)),
+    },
+    "in" | "contains" => {
+      let (needle, haystack) = match operator {
+        "in" => (a, b),
+        _ => (b, a),
+      };
+      match needle.as_slice() {
+        [] => Ok(vec![]),
+        [item] => Ok(from_bool(Some(contains_value(&haystack, &item.value)))),
+        _ => Err(format!("'{}' needs a single item", operator)),
+      }
+    },
+    "&" => {
+      let text = |items: &[Item]| string(items).map(Option::unwrap_or_default);
+      Ok(vec![Item::new(json!(format!(
+        "{}{}",
+        text(&a)?,
+        text(&b)?
+      )))])
+    },
+    _ => match (a.as_slice(), b.as_slice()) {
+      ([x], [y]) => Ok(
+        arithmetic(operator, &x.value, &y.value)?
+          .map(Item::new)
+          .into_iter()
+          .collect(),
+      ),
+      ([], _) | (_, []) => Ok(vec![]),
+      _ => Err(format!("'{}' needs single items", operator)),
+    },
+  }
+}
+
+/// Target of a reference: a contained resource, or a stub with `resourceType` and `id`
+fn resolve(item: &Item, root: &Item) -> Option<Item> {
+  let reference = match &item.value {
+    Value::String(it) => it.as_str(),
+    other => other.get("reference")?.as_str()?,
+  };
+  if let Some(id) = reference.strip_prefix('#') {
+    return root
+      .value
+      .get("contained")?
+      .as_array()?
+      .iter()
+      .find(|it| it.get("id").and_then(Value::as_str) == Some(id))
+      .cloned()
+      .map(Item::new);
+  }
+  let parts: Vec<&str> = reference.split("/_history/").next()?.split('/').collect();
+  match parts.as_slice() {
+    [.., resource_type, id] if starts_upper(resource_type) => {
+      Some(Item::new(json!({"resourceType": resource_type, "id": id})))
+    },
+    _ => None,
+  }
+}
+
+fn to_string(value: &Value) -> Option<String> {
+  match value {
+    Value::String(it) => Some(it.to_string()),
+    Value::Number(..) | Value::Bool(..) => Some(value.to_string()),
+    _ => None,
+  }
+}
+
+fn function(name: &str, input: Collection, args: &[Expr], env: &Env) -> Result<Collection, String> {
+  let arity = |min: usize, max: usize| match args.len() {
+    count if count < min || count > max => Err(format!(
+      "{}() takes {} arguments, got {}",
+      name,
+      match min == max {
+        true => min.to_string(),
+        false => format!("{} to {}", min, max),
+      },
+      count
+    )),
+    _ => Ok(()),
+  };
+  let arg = |index: usize| eval(&args[index], env);
+  // Criteria are evaluated for every item with `$this` and `$index`
+  let each = |index: usize| -> Result<Vec<(Item, Collection)>, String> {
+    input
+      .iter()
+      .enumerate()
+      .map(|(position, item)| {
+        eval(&args[index], &env.with(item.clone(), position)).map(|result| (item.clone(), result))
+      })
+      .collect()
+  };
+  let single_string = || string(&input);
+  let map_string = |apply: &dyn Fn(&str) -> Result<Value, String>| -> Result<Collection, String> {
+    match single_string()? {
+      Some(text) => Ok(vec![Item::new(apply(&text)?)]),
+      None => Ok(vec![]),
+    }
+  };
+  let map_number = |apply: &dyn Fn(f64) -> f64| -> Result<Collection, String> {
+    match input.as_slice() {
+      [] => Ok(vec![]),
+      [item] => match (item.value.as_i64(), item.value.as_f64()) {
+        (Some(number), _) => Ok(vec![Item::new(json!(apply(number as f64) as i64))]),
+        (None, Some(number)) => Ok(vec![Item::new(json!(apply(number)))]),
+        _ => Err(format!("{}() needs a number, got {}", name, item.value)),
+      },
+      _ => Err(format!("{}() needs a single number", name)),
+    }
+  };
+
+  match name {
+    "empty" => {
+      arity(0, 0)?;
+      Ok(from_bool(Some(input.is_empty())))
+    },
+    "exists" => {
+      arity(0, 1)?;
+      Ok(from_bool(Some(match args.is_empty() {
+        true => !input.is_empty(),
+        false => each(0)?
+          .iter()
+          .any(|(_, result)| boolean(result) == Ok(Some(true))),
+      })))
+    },
+    "all" => {
+      arity(1, 1)?;
+      for (_, result) in each(0)? {
+        if boolean(&result)? != Some(true) {
+          return Ok(from_bool(Some(false)));
+        }
+      }
+      Ok(from_bool(Some(true)))
+    },
+    "allTrue" | "anyTrue" | "allFalse" | "anyFalse" => {
+      arity(0, 0)?;
+      let expected = name.ends_with("True");
+      let mut matches = input.iter().map(|it| it.value.as_bool() == Some(expected));
+      Ok(from_bool(Some(match name.starts_with("all") {
+        true => matches.all(|it| it),
+        false => matches.any(|it| it),
+      })))
+    },
+    "count" => {
+      arity(0, 0)?;
+      Ok(vec![Item::new(json!(input.len()))])
+    },
+    "distinct" => {
+      arity(0, 0)?;
+      Ok(distinct(input))
+    },
+    "isDistinct" => {
+      arity(0, 0)?;
+      let count = input.len();
+      Ok(from_bool(Some(distinct(input).len() == count)))
+    },
+    "subsetOf" | "supersetOf" => {
+      arity(1, 1)?;
+      let other = arg(0)?;
+      let (small, large) = match name {
+        "subsetOf" => (&input, &other),
+        _ => (&other, &input),
+      };
+      Ok(from_bool(Some(
+        small.iter().all(|it| contains_value(large, &it.value)),
+      )))
+    },
+    "where" => {
+      arity(1, 1)?;
+      let mut result = vec![];
+      for (item, matched) in each(0)? {
+        if boolean(&matched)? == Some(true) {
+          result.push(item);
+        }
+      }
+      Ok(result)
+    },
+    "select" => {
+      arity(1, 1)?;
+      Ok(each(0)?.into_iter().flat_map(|(_, it)| it).collect())
+    },
+    "repeat" => {
+      arity(1, 1)?;
+      let mut result: Collection = vec![];
+      let mut queue = input.clone();
+      while !queue.is_empty() {
+        let mut next = vec![];
+        for (position, item) in queue.into_iter().enumerate() {
+          for found in eval(&args[0], &env.with(item, position))? {
+            if !result.contains(&found) {
+              if result.len() >= MAX_REPEAT_ITEMS {
+                return Err(format!(
+                  "repeat() stopped after {} items. The expression may never run out of new items",
+                  MAX_REPEAT_ITEMS
+                ));
+              }
+              result.push(found.clone());
+              next.push(found);
+            }
+          }
+        }
+        queue = next;
+      }
+      Ok(result)
+    },
+    "ofType" | "as" => {
+      arity(1, 1)?;
+      type_operation("as", input, &type_arg(&args[0])?)
+    },
+    "is" => {
+      arity(1, 1)?;
+      type_operation("is", input, &type_arg(&args[0])?)
+    },
+    "single" => {
+      arity(0, 0)?;
+      match input.len() {
+        0 | 1 => Ok(input),
+        count => Err(format!("single() got {} items", count)),
+      }
+    },
+    "first" => {
+      arity(0, 0)?;
+      Ok(input.into_iter().take(1).collect())
+    },
+    "last" => {
+      arity(0, 0)?;
+      Ok(input.last().cloned().into_iter().collect())
+    },
+    "tail" => {
+      arity(0, 0)?;
+      Ok(input.into_iter().skip(1).collect())
+    },
+    "skip" | "take" => {
+      arity(1, 1)?;
+      let count = integer(&arg(0)?)?.unwrap_or(0).max(0) as usize;
+      Ok(match name {
+        "skip" => input.into_iter().skip(count).collect(),
+        _ => input.into_iter().take(count).collect(),
+      })
+    },
+    "intersect" | "exclude" => {
+      arity(1, 1)?;
+      let other = arg(0)?;
+      let keep = name == "intersect";
+      let result = input
+        .into_iter()
+        .filter(|it| contains_value(&other, &it.value) == keep)
+        .collect();
+      Ok(match keep {
+        true => distinct(result),
+        false => result,
+      })
+    },
+    "union" => {
+      arity(1, 1)?;
+      Ok(distinct(input.into_iter().chain(arg(0)?).collect()))
+    },
+    "combine" => {
+      arity(1, 1)?;
+      Ok(input.into_iter().chain(arg(0)?).collect())
+    },
+    "iif" => {
+      arity(2, 3)?;
+      let local = Env {
+        root: env.root,
+        this: input,
+        index: env.index,
+      };
+      match boolean(&eval(&args[0], &local)?)? {
+        Some(true) => eval(&args[1], &local),
+        _ if args.len() == 3 => eval(&args[2], &local),
+        _ => Ok(vec![]),
+      }
+    },
+    "not" => {
+      arity(0, 0)?;
+      Ok(from_bool(boolean(&input)?.map(|it| !it)))
+    },
+    "hasValue" => {
+      arity(0, 0)?;
+      Ok(from_bool(Some(matches!(
+        input.as_slice(),
+        [item] if !item.value.is_object() && !item.value.is_array()
+      ))))
+    },
+    "toString" => {
+      arity(0, 0)?;
+      Ok(
+        input
+          .iter()
+          .take(1)
+          .filter_map(|it| to_string(&it.value))
+          .map(|it| Item::new(json!(it)))
+          .collect(),
+      )
+    },
+    "toInteger" | "toDecimal" | "toBoolean" => {
+      arity(0, 0)?;
+      let Some(item) = input.first() else {
+        return Ok(vec![]);
+      };
+      let text = to_string(&item.value).unwrap_or_default();
+      let converted = match name {
+        "toInteger" => match &item.value {
+          Value::Bool(it) => Some(json!(*it as i64)),
+          _ => text.parse::<i64>().ok().map(|it| json!(it)),
+        },
+        "toDecimal" => match &item.value {
+          Value::Bool(it) => Some(json!(*it as i64 as f64)),
+          _ => text.parse::<f64>().ok().map(|it| json!(it)),
+        },
+        _ => match text.to_lowercase().as_str() {
+          "true" | "t" | "yes" | "y" | "1" | "1.0" => Some(json!(true)),
+          "false" | "f" | "no" | "n" | "0" | "0.0" => Some(json!(false)),
+          _ => None,
+        },
+      };
+      Ok(converted.map(Item::new).into_iter().collect())
+    },
+    "length" => {
+      arity(0, 0)?;
+      map_string(&|text| Ok(json!(text.chars().count())))
+    },
+    "upper" | "lower" | "trim" => {
+      arity(0, 0)?;
+      map_string(&|text| {
+        Ok(json!(match name {
+          "upper" => text.to_uppercase(),
+          "lower" => text.to_lowercase(),
+          _ => text.trim().to_string(),
+        }))
+      })
+    },
+    "toChars" => {
+      arity(0, 0)?;
+      Ok(
+        single_string()?
+          .unwrap_or_default()
+          .chars()
+          .map(|it| Item::new(json!(it.to_string())))
+          .collect(),
+      )
+    },
+    "startsWith" | "endsWith" | "contains" | "indexOf" | "matches" | "split" => {
+      arity(1, 1)?;
+      let Some(other) = string(&arg(0)?)? else {
+        return Ok(vec![]);
+      };
+      if name == "split" {
+        return Ok(
+          single_string()?
+            .map(|text| {
+              text
+                .split(other.as_str())
+                .map(|it| Item::new(json!(it)))
+                .collect()
+            })
+            .unwrap_or_default(),
+        );
+      }
+      let pattern = match name {
+        "matches" => Some(Regex::new(&other).map_err(|err| format!("Invalid regex: {}", err))?),
+        _ => None,
+      };
+      map_string(&|text| {
+        Ok(match name {
+          "startsWith" => json!(text.starts_with(other.as_str())),
+          "endsWith" => json!(text.ends_with(other.as_str())),
+          "contains" => json!(text.contains(other.as_str())),
+          "indexOf" => json!(text
+            .find(other.as_str())
+            .map(|it| text[..it].chars().count() as i64)
+            .unwrap_or(-1)),
+          _ => json!(pattern.as_ref().unwrap().is_match(text)),
+        })
+      })
+    },
+    "substring" => {
+      arity(1, 2)?;
+      let Some(start) = integer(&arg(0)?)? else {
+        return Ok(vec![]);
+      };
+      let length = match args.len() {
+        2 => integer(&arg(1)?)?,
+        _ => None,
+      };
+      let chars: Vec<char> = single_string()?.unwrap_or_default().chars().collect();
+      if start < 0 || start as usize >= chars.len() {
+        return Ok(vec![]);
+      }
+      let end = match length {
+        Some(length) => start.saturating_add(length.max(0)).min(chars.len() as i64) as usize,
+        None => chars.len(),
+      };
+      Ok(vec![Item::new(json!(chars[start as usize..end]
+        .iter()
+        .collect::<String>()))])
+    },
+    "replace" | "replaceMatches" => {
+      arity(2, 2)?;
+      let (Some(pattern), Some(substitution)) = (string(&arg(0)?)?, string(&arg(1)?)?) else {
+        return Ok(vec![]);
+      };
+      let regex = match name {
+        "replaceMatches" => {
+          Some(Regex::new(&pattern).map_err(|err| format!("Invalid regex: {}", err))?)
+        },
+        _ => None,
+      };
+      map_string(&|text| {
+        Ok(json!(match regex.as_ref() {
+          Some(regex) => regex.replace_all(text, substitution.as_str()).to_string(),
+          None => text.replace(pattern.as_str(), &substitution),
+        }))
+      })
+    },
+    "join" => {
+      arity(0, 1)?;
+      let separator = match args.len() {
+        1 => string(&arg(0)?)?.unwrap_or_default(),
+        _ => String::new(),
+      };
+      let parts: Vec<String> = input.iter().filter_map(|it| to_string(&it.value)).collect();
+      Ok(vec![Item::new(json!(parts.join(&separator)))])
+    },
+    "abs" => {
+      arity(0, 0)?;
+      map_number(&f64::abs)
+    },
+    "ceiling" | "floor" => {
+      arity(0, 0)?;
+      let result = map_number(&|it| match name {
+        "ceiling" => it.ceil(),
+        _ => it.floor(),
+      })?;
+      // Both return integers
+      Ok(
+        result
+          .into_iter()
+          .map(|it| Item::new(json!(it.value.as_f64().unwrap_or_default() as i64)))
+          .collect(),
+      )
+    },
+    "round" => {
+      arity(0, 1)?;
+      let precision = match args.len() {
+        1 => integer(&arg(0)?)?.unwrap_or(0),
+        _ => 0,
+      };
+      let factor = 10f64.powi(precision as i32);
+      map_number(&|it| (it * factor).round() / factor)
+    },
+    "children" => {
+      arity(0, 0)?;
+      Ok(children(&input))
+    },
+    "descendants" => {
+      arity(0, 0)?;
+      let mut result = vec![];
+      let mut level = children(&input);
+      while !level.is_empty() {
+        let next = children(&level);
+        result.extend(level);
+        level = next;
+      }
+      Ok(result)
+    },
+    "extension" => {
+      arity(1, 1)?;
+      let url = string(&arg(0)?)?;
+      Ok(
+        member(&input, "extension")
+          .into_iter()
+          .filter(|it| it.value.get("url").and_then(Value::as_str) == url.as_deref())
+          .collect(),
+      )
+    },
+    "resolve" => {
+      arity(0, 0)?;
+      Ok(
+        input
+          .iter()
+          .filter_map(|it| resolve(it, env.root))
+          .collect(),
+      )
+    },
+    "now" => {
+      arity(0, 0)?;
+      Ok(vec![Item::new(json!(
+        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+      ))])
+    },
+    "today" => {
+      arity(0, 0)?;
+      Ok(vec![Item::new(json!(chrono::Local::now()
+        .format("%Y-%m-%d")
+        .to_string()))])
+    },
+    "trace" => {
+      arity(1, 2)?;
+      Ok(input)
+    },
+    other => Err(format!("Unsupported function {}()", other)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::parser::parse;
+  use super::*;
+
+  fn run(expr: &str, resource: &Value) -> Vec<Value> {
+    evaluate(&parse(expr).unwrap(), resource)
+      .unwrap()
+      .into_iter()
+      .map(|it| it.value)
+      .collect()
+  }
+
+  #[test]
+  fn test_evaluate() {
+    let patient = json!({
+      "resourceType": "Patient",
+      "id": "pt-1",
+      "active": true,
+      "name": [
+        {"use": "official", "family": "Lee", "given": ["Ann", "Marie"]},
+        {"use": "nickname", "given": ["Annie"]}
+      ],
+      "generalPractitioner": [{"reference": "Practitioner/pr-1"}],
+      "extension": [{"url": "http://example.org/race", "valueCode": "2106-3"}]
+    });
+    assert_eq!(
+      run("Patient.name.given", &patient),
+      vec![json!("Ann"), json!("Marie"), json!("Annie")]
+    );
+    assert_eq!(
+      run("name.where(use = 'official').family", &patient),
+      vec![json!("Lee")]
+    );
+    assert_eq!(run("Observation.code", &patient), Vec::<Value>::new());
+    assert_eq!(
+      run("name.given.count() > 2 and active", &patient),
+      vec![json!(true)]
+    );
+    assert_eq!(
+      run("generalPractitioner.resolve() is Practitioner", &patient),
+      vec![json!(true)]
+    );
+    assert_eq!(
+      run("extension('http://example.org/race').value", &patient),
+      vec![json!("2106-3")]
+    );
+    assert_eq!(
+      run(
+        "name.first().given.join(' ') & ' ' + name[0].family",
+        &patient
+      ),
+      vec![json!("Ann Marie Lee")]
+    );
+    assert_eq!(run("(1 + 2 * 3) div 2", &patient), vec![json!(3)]);
+    assert_eq!(run("{}.empty()", &patient), vec![json!(true)]);
+  }
+
+  #[test]
+  fn test_empty_and_bounds() {
+    let empty = Vec::<Value>::new();
+    let patient = json!({
+      "resourceType": "Patient",
+      "name": [{"family": "Lee"}],
+      "contained": [{"resourceType": "Organization", "id": "org"}],
+      "managingOrganization": {"reference": "#org"},
+      "link": [{"other": {"reference": "http://box/fhir/Patient/pt-2/_history/3"}}]
+    });
+    // Empty operands propagate instead of failing
+    assert_eq!(run("{} + 1", &patient), empty);
+    assert_eq!(run("name.given = 'Ann'", &patient), empty);
+    assert_eq!(run("name.given.first().upper()", &patient), empty);
+
+    assert_eq!(
+      run("iif(name.exists(), 'yes', 'no')", &patient),
+      vec![json!("yes")]
+    );
+    assert_eq!(run("iif(name.given.exists(), 'yes')", &patient), empty);
+    assert_eq!(run("iif({}, 'yes', 'no')", &patient), vec![json!("no")]);
+
+    assert_eq!(run("'abc'.substring(1)", &patient), vec![json!("bc")]);
+    assert_eq!(run("'abc'.substring(1, 100)", &patient), vec![json!("bc")]);
+    assert_eq!(run("'abc'.substring(3)", &patient), empty);
+    assert_eq!(run("'abc'.substring(-1)", &patient), empty);
+    assert_eq!(run("'abc'.substring(1, -2)", &patient), vec![json!("")]);
+    assert_eq!(
+      run("'abc'.substring(1, 9223372036854775807)", &patient),
+      vec![json!("bc")]
+    );
+    assert_eq!(run("'abc'.indexOf('c')", &patient), vec![json!(2)]);
+    assert_eq!(run("'abc'.indexOf('x')", &patient), vec![json!(-1)]);
+    assert_eq!(run("'abc'.indexOf('')", &patient), vec![json!(0)]);
+
+    assert_eq!(run("5 div 0", &patient), empty);
+    assert_eq!(run("5 mod 0", &patient), empty);
+    assert_eq!(run("5.5 div 0", &patient), empty);
+    assert_eq!(run("5 / 0", &patient), empty);
+    assert_eq!(run("7 mod 3", &patient), vec![json!(1)]);
+
+    assert_eq!(
+      run("managingOrganization.resolve().id", &patient),
+      vec![json!("org")]
+    );
+    assert_eq!(
+      run("link.other.resolve()", &patient),
+      vec![json!({"resourceType": "Patient", "id": "pt-2"})]
+    );
+    assert_eq!(run("'not a reference'.resolve()", &patient), empty);
+  }
+
+  #[test]
+  fn test_limits() {
+    let expr = parse("1.repeat($this + 1)").unwrap();
+    assert!(evaluate(&expr, &json!({}))
+      .unwrap_err()
+      .contains("repeat()"));
+    let expr = parse("-(-9223372036854775807 - 1)").unwrap();
+    assert!(evaluate(&expr, &json!({})).is_err());
+  }
+
+  #[test]
+  fn test_now() {
+    let now = run("now()", &json!({}));
+    let text = now[0].as_str().unwrap();
+    assert!(chrono::DateTime::parse_from_rfc3339(text).is_ok());
+    // Milliseconds, not nanoseconds
+    assert_eq!(text.split('.').nth(1).unwrap().len(), "000+00:00".len());
+  }
+
+  #[test]
+  fn test_choice_types() {
+    let observation = json!({
+      "resourceType": "Observation",
+      "valueQuantity": {"value": 5.4, "unit": "mmol/L"},
+      "effectiveDateTime": "2024-01-02"
+    });
+    assert_eq!(
+      run("Observation.value.ofType(Quantity).value", &observation),
+      vec![json!(5.4)]
+    );
+    assert_eq!(
+      run("(Observation.value as string).exists()", &observation),
+      vec![json!(false)]
+    );
+    assert_eq!(
+      run("effective > @2024-01-01", &observation),
+      vec![json!(true)]
+    );
+  }
+}
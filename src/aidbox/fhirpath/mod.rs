@@ -0,0 +1,92 @@
+mod eval;
+mod parser;
+
+use crate::aidbox::helpers::{connect, print_json, read_value_file};
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use log::error;
+use regex::Regex;
+use serde_json::Value;
+use tool_aidbox::rest::ApiFormat;
+
+pub fn commands() -> Command {
+  Command::new("fhirpath")
+    .about("Evaluate a FHIRPath expression locally against a resource")
+    .arg_required_else_help(true)
+    .args(vec![
+      Arg::new("expression")
+        .required(true)
+        .help("Expression. Example: \"Patient.name.where(use = 'official').given\""),
+      Arg::new("source")
+        .value_hint(ValueHint::FilePath)
+        .help("Resource file (JSON/YAML), - for stdin, or Type/id to fetch it from the instance. Defaults to stdin"),
+      Arg::new("format")
+        .long("format")
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .help("text prints an item per line, json prints the collection as an array"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  if let Err(err) = fhirpath(sub_matches).await {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+async fn read_source(instance: &str, source: &str) -> Result<Value, String> {
+  let reference = Regex::new(r"^([A-Z][A-Za-z]+)/([^/]+)$").unwrap();
+  let captures = match std::path::Path::new(source).exists() {
+    true => None,
+    false => reference.captures(source),
+  };
+  let Some(captures) = captures else {
+    return read_value_file(source);
+  };
+  let client = connect(instance).await?;
+  let response = client
+    .read_resource(ApiFormat::Fhir, &captures[1], &captures[2])
+    .await?;
+  match response.is_success() {
+    true => Ok(response.body),
+    false => Err(format!(
+      "Cannot read {} (HTTP {}): {}",
+      source, response.status, response.body
+    )),
+  }
+}
+
+async fn fhirpath(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let expression = sub_matches.get_one::<String>("expression").unwrap();
+  let expr = parser::parse(expression).map_err(|err| format!("Invalid expression: {}", err))?;
+  let source = sub_matches
+    .get_one::<String>("source")
+    .map(String::as_str)
+    .unwrap_or("-");
+  let resource = read_source(instance, source).await?;
+  let result = eval::evaluate(&expr, &resource)?;
+
+  match sub_matches.get_one::<String>("format").unwrap().as_str() {
+    "json" => print_json(&Value::Array(
+      result.into_iter().map(|it| it.value).collect(),
+    )),
+    _ if result.is_empty() => println!("{}", style("(empty)").dim()),
+    _ => {
+      // Types are only shown on a terminal so the output can be piped
+      let typed = console::user_attended();
+      for item in result {
+        let text = match &item.value {
+          Value::String(it) => it.to_string(),
+          other => other.to_string(),
+        };
+        match typed {
+          true => println!("{}  {}", text, style(item.type_of()).dim()),
+          false => println!("{}", text),
+        }
+      }
+    },
+  }
+  Ok(())
+}
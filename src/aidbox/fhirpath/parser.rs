@@ -0,0 +1,449 @@
+use serde_json::{json, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Ident(String),
+  /// Identifier in backticks, never a keyword
+  Quoted(String),
+  Str(String),
+  Num(String),
+  /// `@2020-01-01`, `@T10:00`. Kept as the string after `@`
+  Date(String),
+  /// `%resource`
+  Var(String),
+  /// `$this`, `$index`, `$total`
+  Special(String),
+  Punct(&'static str),
+  End,
+}
+
+const PUNCTS: [&str; 22] = [
+  "!=", "!~", "<=", ">=", ".", "[", "]", "(", ")", ",", "|", "=", "~", "<", ">", "+", "-", "*",
+  "/", "&", "{", "}",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+  /// `{}`
+  Empty,
+  Literal(Value),
+  /// Path step, on the focus when there is no base
+  Member(Option<Box<Expr>>, String),
+  /// Function call, on the focus when there is no base
+  Function(Option<Box<Expr>>, String, Vec<Expr>),
+  Indexer(Box<Expr>, Box<Expr>),
+  Variable(String),
+  This,
+  Index,
+  Negate(Box<Expr>),
+  Binary(Box<Expr>, String, Box<Expr>),
+  /// `is` or `as` with a type name
+  TypeOp(Box<Expr>, String, String),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+  let chars: Vec<char> = source.chars().collect();
+  let mut tokens = vec![];
+  let mut index = 0;
+  let take_while = |index: &mut usize, test: &dyn Fn(char) -> bool| -> String {
+    let start = *index;
+    while *index < chars.len() && test(chars[*index]) {
+      *index += 1;
+    }
+    chars[start..*index].iter().collect()
+  };
+
+  while index < chars.len() {
+    let char = chars[index];
+    let rest: String = chars[index..chars.len().min(index + 2)].iter().collect();
+    if char.is_whitespace() {
+      index += 1;
+    } else if rest == "//" {
+      take_while(&mut index, &|it| it != '\n');
+    } else if rest == "/*" {
+      let end = (index + 2..chars.len().saturating_sub(1))
+        .find(|it| chars[*it] == '*' && chars[*it + 1] == '/')
+        .ok_or("Unterminated comment")?;
+      index = end + 2;
+    } else if char == '\'' || char == '`' {
+      let (text, next) = quoted(&chars, index)?;
+      index = next;
+      tokens.push(match char {
+        '\'' => Token::Str(text),
+        _ => Token::Quoted(text),
+      });
+    } else if char.is_ascii_digit() {
+      let mut number = take_while(&mut index, &|it| it.is_ascii_digit());
+      if index + 1 < chars.len() && chars[index] == '.' && chars[index + 1].is_ascii_digit() {
+        index += 1;
+        number = format!(
+          "{}.{}",
+          number,
+          take_while(&mut index, &|it| it.is_ascii_digit())
+        );
+      }
+      tokens.push(Token::Num(number));
+    } else if char.is_alphabetic() || char == '_' {
+      tokens.push(Token::Ident(take_while(&mut index, &|it| {
+        it.is_alphanumeric() || it == '_'
+      })));
+    } else if char == '%' || char == '$' {
+      index += 1;
+      let name = match chars.get(index) {
+        Some('`') | Some('\'') => {
+          let (text, next) = quoted(&chars, index)?;
+          index = next;
+          text
+        },
+        _ => take_while(&mut index, &|it| {
+          it.is_alphanumeric() || it == '_' || it == '-'
+        }),
+      };
+      tokens.push(match char {
+        '%' => Token::Var(name),
+        _ => Token::Special(name),
+      });
+    } else if char == '@' {
+      index += 1;
+      let start = index;
+      while let Some(&it) = chars.get(index) {
+        // `.` starts fractional seconds only before a digit, `@2024-01-01.exists()` is a call
+        let fraction = it == '.' && chars.get(index + 1).is_some_and(char::is_ascii_digit);
+        if !(it.is_ascii_digit() || "-:T+Z".contains(it) || fraction) {
+          break;
+        }
+        index += 1;
+      }
+      tokens.push(Token::Date(chars[start..index].iter().collect()));
+    } else {
+      match PUNCTS.iter().find(|it| rest.starts_with(**it)) {
+        Some(punct) => {
+          index += punct.len();
+          tokens.push(Token::Punct(punct));
+        },
+        None => return Err(format!("Unexpected character '{}'", char)),
+      }
+    }
+  }
+  tokens.push(Token::End);
+  Ok(tokens)
+}
+
+/// Text of a `'...'` or `` `...` `` token starting at `start`, and the index after it
+fn quoted(chars: &[char], start: usize) -> Result<(String, usize), String> {
+  let delimiter = chars[start];
+  let mut text = String::new();
+  let mut index = start + 1;
+  while index < chars.len() {
+    match chars[index] {
+      '\\' if index + 1 < chars.len() => {
+        index += 1;
+        match chars[index] {
+          'n' => text.push('\n'),
+          'r' => text.push('\r'),
+          't' => text.push('\t'),
+          'f' => text.push('\u{c}'),
+          'u' => {
+            let code: String = chars[index + 1..chars.len().min(index + 5)]
+              .iter()
+              .collect();
+            let value = u32::from_str_radix(&code, 16)
+              .ok()
+              .and_then(char::from_u32)
+              .ok_or_else(|| format!("Invalid escape \\u{}", code))?;
+            text.push(value);
+            index += 4;
+          },
+          other => text.push(other),
+        }
+      },
+      it if it == delimiter => return Ok((text, index + 1)),
+      it => text.push(it),
+    }
+    index += 1;
+  }
+  Err(format!("Unterminated {}", delimiter))
+}
+
+/// Binding power of infix operators, from `implies` to `*`
+fn infix_power(operator: &str) -> Option<u8> {
+  Some(match operator {
+    "implies" => 1,
+    "or" | "xor" => 2,
+    "and" => 3,
+    "in" | "contains" => 4,
+    "=" | "~" | "!=" | "!~" => 5,
+    "<" | ">" | "<=" | ">=" => 6,
+    "|" => 7,
+    "is" | "as" => 8,
+    "+" | "-" | "&" => 9,
+    "*" | "/" | "div" | "mod" => 10,
+    _ => return None,
+  })
+}
+
+const PREFIX_POWER: u8 = 11;
+
+/// Deepest expression tree accepted, so nested input can't overflow the stack
+const MAX_DEPTH: usize = 200;
+
+struct Parser {
+  tokens: Vec<Token>,
+  position: usize,
+  /// Depth of the tree built so far on the current path
+  depth: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> &Token {
+    &self.tokens[self.position]
+  }
+
+  fn next(&mut self) -> Token {
+    let token = self.tokens[self.position].clone();
+    if token != Token::End {
+      self.position += 1;
+    }
+    token
+  }
+
+  fn expect(&mut self, punct: &str) -> Result<(), String> {
+    match self.next() {
+      Token::Punct(it) if it == punct => Ok(()),
+      other => Err(format!("Expected '{}', got {}", punct, describe(&other))),
+    }
+  }
+
+  fn nest(&mut self) -> Result<(), String> {
+    self.depth += 1;
+    match self.depth > MAX_DEPTH {
+      true => Err(format!("Expression is nested deeper than {}", MAX_DEPTH)),
+      false => Ok(()),
+    }
+  }
+
+  fn expression(&mut self, min_power: u8) -> Result<Expr, String> {
+    let depth = self.depth;
+    self.nest()?;
+    let left = self.operations(min_power);
+    self.depth = depth;
+    left
+  }
+
+  fn operations(&mut self, min_power: u8) -> Result<Expr, String> {
+    let mut left = self.prefix()?;
+    loop {
+      if matches!(self.peek(), Token::Punct(".") | Token::Punct("[")) {
+        self.nest()?;
+      }
+      match self.peek().clone() {
+        Token::Punct(".") => {
+          self.next();
+          left = self.invocation(Some(left))?;
+        },
+        Token::Punct("[") => {
+          self.next();
+          let index = self.expression(0)?;
+          self.expect("]")?;
+          left = Expr::Indexer(Box::new(left), Box::new(index));
+        },
+        token => {
+          let Some(operator) = operator_of(&token) else {
+            break;
+          };
+          let power = infix_power(&operator).unwrap();
+          if power < min_power {
+            break;
+          }
+          self.nest()?;
+          self.next();
+          left = match operator.as_str() {
+            "is" | "as" => Expr::TypeOp(Box::new(left), operator, self.type_name()?),
+            _ => Expr::Binary(
+              Box::new(left),
+              operator,
+              Box::new(self.expression(power + 1)?),
+            ),
+          };
+        },
+      }
+    }
+    Ok(left)
+  }
+
+  fn prefix(&mut self) -> Result<Expr, String> {
+    match self.next() {
+      Token::Punct("-") => Ok(Expr::Negate(Box::new(self.expression(PREFIX_POWER)?))),
+      Token::Punct("+") => self.expression(PREFIX_POWER),
+      Token::Punct("(") => {
+        let expr = self.expression(0)?;
+        self.expect(")")?;
+        Ok(expr)
+      },
+      Token::Punct("{") => {
+        self.expect("}")?;
+        Ok(Expr::Empty)
+      },
+      Token::Str(text) => Ok(Expr::Literal(json!(text))),
+      Token::Date(text) => Ok(Expr::Literal(json!(text.trim_start_matches('T')))),
+      Token::Num(number) => Ok(Expr::Literal(
+        number
+          .parse::<i64>()
+          .map(|it| json!(it))
+          .or_else(|_| number.parse::<f64>().map(|it| json!(it)))
+          .map_err(|_| format!("Invalid number {}", number))?,
+      )),
+      Token::Ident(name) if name == "true" || name == "false" => {
+        Ok(Expr::Literal(json!(name == "true")))
+      },
+      Token::Var(name) => Ok(Expr::Variable(name)),
+      Token::Special(name) => match name.as_str() {
+        "this" => Ok(Expr::This),
+        "index" => Ok(Expr::Index),
+        other => Err(format!("Unsupported ${}", other)),
+      },
+      Token::Ident(..) | Token::Quoted(..) => {
+        self.position -= 1;
+        self.invocation(None)
+      },
+      other => Err(format!("Unexpected {}", describe(&other))),
+    }
+  }
+
+  /// Member or function call after a `.`, or at the start of a path
+  fn invocation(&mut self, base: Option<Expr>) -> Result<Expr, String> {
+    let name = match self.next() {
+      Token::Ident(name) | Token::Quoted(name) => name,
+      Token::Special(name) if name == "this" && base.is_none() => return Ok(Expr::This),
+      other => return Err(format!("Expected a name, got {}", describe(&other))),
+    };
+    let base = base.map(Box::new);
+    if self.peek() != &Token::Punct("(") {
+      return Ok(Expr::Member(base, name));
+    }
+    self.next();
+    let mut args = vec![];
+    if self.peek() != &Token::Punct(")") {
+      loop {
+        args.push(self.expression(0)?);
+        match self.next() {
+          Token::Punct(",") => continue,
+          Token::Punct(")") => break,
+          other => return Err(format!("Expected ',' or ')', got {}", describe(&other))),
+        }
+      }
+    } else {
+      self.next();
+    }
+    Ok(Expr::Function(base, name, args))
+  }
+
+  /// Qualified type name. Example: `FHIR.Patient`
+  fn type_name(&mut self) -> Result<String, String> {
+    let mut parts = vec![];
+    loop {
+      match self.next() {
+        Token::Ident(name) | Token::Quoted(name) => parts.push(name),
+        other => return Err(format!("Expected a type, got {}", describe(&other))),
+      }
+      if self.peek() != &Token::Punct(".") {
+        return Ok(parts.join("."));
+      }
+      self.next();
+    }
+  }
+}
+
+/// Infix operator of a token, including keyword operators
+fn operator_of(token: &Token) -> Option<String> {
+  let operator = match token {
+    Token::Punct(it) => it.to_string(),
+    Token::Ident(it) => it.to_string(),
+    _ => return None,
+  };
+  infix_power(&operator).map(|_| operator)
+}
+
+fn describe(token: &Token) -> String {
+  match token {
+    Token::End => "end of expression".to_string(),
+    Token::Punct(it) => format!("'{}'", it),
+    Token::Ident(it) | Token::Quoted(it) => format!("'{}'", it),
+    Token::Str(it) => format!("'{}'", it),
+    Token::Num(it) | Token::Date(it) => it.to_string(),
+    Token::Var(it) => format!("%{}", it),
+    Token::Special(it) => format!("${}", it),
+  }
+}
+
+pub fn parse(source: &str) -> Result<Expr, String> {
+  let mut parser = Parser {
+    tokens: tokenize(source)?,
+    position: 0,
+    depth: 0,
+  };
+  let expr = parser.expression(0)?;
+  match parser.peek() {
+    Token::End => Ok(expr),
+    other => Err(format!("Unexpected {}", describe(other))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse() {
+    let member =
+      |base: Option<Expr>, name: &str| Expr::Member(base.map(Box::new), name.to_string());
+    assert_eq!(
+      parse("Patient.name.given").unwrap(),
+      member(Some(member(Some(member(None, "Patient")), "name")), "given")
+    );
+    // `and` binds tighter than `or`, `=` tighter than `and`
+    assert_eq!(
+      parse("a or b and c = 1").unwrap(),
+      Expr::Binary(
+        Box::new(member(None, "a")),
+        "or".to_string(),
+        Box::new(Expr::Binary(
+          Box::new(member(None, "b")),
+          "and".to_string(),
+          Box::new(Expr::Binary(
+            Box::new(member(None, "c")),
+            "=".to_string(),
+            Box::new(Expr::Literal(json!(1)))
+          ))
+        ))
+      )
+    );
+    assert_eq!(
+      parse("value as Quantity").unwrap(),
+      Expr::TypeOp(
+        Box::new(member(None, "value")),
+        "as".to_string(),
+        "Quantity".to_string()
+      )
+    );
+    assert_eq!(
+      parse("@2024-01-01.exists()").unwrap(),
+      Expr::Function(
+        Some(Box::new(Expr::Literal(json!("2024-01-01")))),
+        "exists".to_string(),
+        vec![]
+      )
+    );
+    assert_eq!(
+      parse("@2024-01-01T10:00:00.123Z").unwrap(),
+      Expr::Literal(json!("2024-01-01T10:00:00.123Z"))
+    );
+    assert!(parse("name.where(").is_err());
+    assert!(parse("name given").is_err());
+
+    let nested = format!("{}1{}", "(".repeat(5000), ")".repeat(5000));
+    assert!(parse(&nested).unwrap_err().contains("nested deeper"));
+    assert!(parse(&vec!["1"; 5000].join(" + ")).is_err());
+    assert!(parse(&format!("{}1", "-".repeat(5000))).is_err());
+  }
+}
@@ -0,0 +1,313 @@
+use crate::aidbox::helpers::{cell, connect, print_json, read_value_file, render_table};
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use log::error;
+use reqwest::Method;
+use serde_json::{json, Map, Value};
+use std::io::Read;
+
+/// Standard introspection query, as sent by GraphQL tools
+const INTROSPECTION_QUERY: &str = r#"query IntrospectionQuery {
+  __schema {
+    queryType { name }
+    mutationType { name }
+    subscriptionType { name }
+    types { ...FullType }
+    directives { name description locations args { ...InputValue } }
+  }
+}
+fragment FullType on __Type {
+  kind name description
+  fields(includeDeprecated: true) {
+    name description args { ...InputValue } type { ...TypeRef } isDeprecated deprecationReason
+  }
+  inputFields { ...InputValue }
+  interfaces { ...TypeRef }
+  enumValues(includeDeprecated: true) { name description isDeprecated deprecationReason }
+  possibleTypes { ...TypeRef }
+}
+fragment InputValue on __InputValue { name description type { ...TypeRef } defaultValue }
+fragment TypeRef on __Type {
+  kind name
+  ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name ofType { kind name } } } } } } }
+}"#;
+
+pub fn commands() -> Command {
+  Command::new("graphql")
+    .about("Run a GraphQL query with $graphql")
+    .arg_required_else_help(true)
+    .args(vec![
+      Arg::new("query")
+        .required_unless_present_any(["file", "introspect"])
+        .help("Query text. Example: '{ PatientList(_count: 5) { id } }'"),
+      Arg::new("file")
+        .short('f')
+        .long("file")
+        .conflicts_with("query")
+        .value_hint(ValueHint::FilePath)
+        .help(".graphql file with the query, or - for stdin"),
+      Arg::new("variables")
+        .long("variables")
+        .help("Variables as a JSON object, or @file with JSON/YAML"),
+      Arg::new("operation")
+        .long("operation")
+        .help("Operation to run when the query has several"),
+      Arg::new("introspect")
+        .long("introspect")
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with_all(["query", "file"])
+        .help("Dump the schema with the introspection query"),
+      Arg::new("output")
+        .short('o')
+        .long("output")
+        .requires("introspect")
+        .value_hint(ValueHint::FilePath)
+        .help("File for the introspection dump instead of stdout"),
+      Arg::new("format")
+        .long("format")
+        .value_parser(["json", "table", "raw"])
+        .default_value("json")
+        .help("json prints data, table renders lists of objects, raw prints the whole response"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  match graphql(sub_matches).await {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+fn read_query(sub_matches: &ArgMatches) -> Result<String, String> {
+  if sub_matches.get_flag("introspect") {
+    return Ok(INTROSPECTION_QUERY.to_string());
+  }
+  match sub_matches.get_one::<String>("file") {
+    Some(path) if path == "-" => {
+      let mut buffer = String::new();
+      std::io::stdin()
+        .read_to_string(&mut buffer)
+        .map_err(|err| format!("Cannot read stdin: {}", err))?;
+      Ok(buffer)
+    },
+    Some(path) => {
+      std::fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path, err))
+    },
+    None => Ok(sub_matches.get_one::<String>("query").unwrap().to_string()),
+  }
+}
+
+fn parse_variables(value: Option<&String>) -> Result<Value, String> {
+  let variables = match value {
+    None => return Ok(json!({})),
+    Some(value) => match value.strip_prefix('@') {
+      Some(path) => read_value_file(path)?,
+      None => serde_json::from_str(value).map_err(|err| format!("Invalid variables: {}", err))?,
+    },
+  };
+  match variables.is_object() {
+    true => Ok(variables),
+    false => Err("Variables must be a JSON object".to_string()),
+  }
+}
+
+/// Error messages with their path and the query line under the location
+fn format_errors(query: &str, errors: &[Value]) -> Vec<String> {
+  let lines: Vec<&str> = query.lines().collect();
+  let mut result = vec![];
+  for err in errors {
+    result.push(format!(
+      "{} {}",
+      style("error:").red().bold(),
+      err
+        .get("message")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .unwrap_or_else(|| err.to_string())
+    ));
+    if let Some(path) = err.get("path").and_then(Value::as_array) {
+      let path: Vec<String> = path.iter().map(cell).collect();
+      result.push(format!("  {} {}", style("path").dim(), path.join(".")));
+    }
+    for location in err
+      .get("locations")
+      .and_then(Value::as_array)
+      .into_iter()
+      .flatten()
+    {
+      let line = location.get("line").and_then(Value::as_u64).unwrap_or(0) as usize;
+      let column = location.get("column").and_then(Value::as_u64).unwrap_or(0) as usize;
+      let Some(text) = line.checked_sub(1).and_then(|index| lines.get(index)) else {
+        continue;
+      };
+      let number = line.to_string();
+      result.push(format!(
+        "  {} {} {}",
+        style(&number).dim(),
+        style("|").dim(),
+        text
+      ));
+      result.push(format!(
+        "  {} {} {}{}",
+        " ".repeat(number.len()),
+        style("|").dim(),
+        " ".repeat(column.saturating_sub(1)),
+        style("^").red().bold()
+      ));
+    }
+  }
+  result
+}
+
+/// Root fields of `data` rendered as tables when they are lists of objects
+fn render_tables(data: &Map<String, Value>) -> Vec<String> {
+  let mut result = vec![];
+  for (field, value) in data {
+    let items: Vec<&Map<String, Value>> = match value {
+      Value::Array(items) => items.iter().filter_map(Value::as_object).collect(),
+      Value::Object(item) => vec![item],
+      _ => vec![],
+    };
+    result.push(format!(
+      "{} {}",
+      style(field).bold(),
+      style(format!("({})", items.len())).dim()
+    ));
+    let mut columns: Vec<&String> = vec![];
+    for item in items.iter() {
+      for key in item.keys() {
+        if !columns.contains(&key) {
+          columns.push(key);
+        }
+      }
+    }
+    if columns.is_empty() {
+      result.push(cell(value));
+      continue;
+    }
+    let headers: Vec<String> = columns
+      .iter()
+      .map(|it| style(it).bold().to_string())
+      .collect();
+    let rows: Vec<Vec<String>> = items
+      .iter()
+      .map(|item| {
+        columns
+          .iter()
+          .map(|key| cell(item.get(*key).unwrap_or(&Value::Null)))
+          .collect()
+      })
+      .collect();
+    result.extend(render_table(&headers, &rows));
+  }
+  result
+}
+
+/// Returns `false` when the response has errors
+async fn graphql(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let query = read_query(sub_matches)?;
+  let mut request = json!({
+    "query": query,
+    "variables": parse_variables(sub_matches.get_one::<String>("variables"))?,
+  });
+  if let Some(operation) = sub_matches.get_one::<String>("operation") {
+    request["operationName"] = json!(operation);
+  }
+
+  let client = connect(instance).await?;
+  let response = client
+    .send(
+      client
+        .request(Method::POST, "/$graphql")
+        .await?
+        .json(&request),
+    )
+    .await?;
+  let errors: Vec<Value> = response
+    .body
+    .get("errors")
+    .and_then(Value::as_array)
+    .cloned()
+    .unwrap_or_default();
+  if !response.is_success() && errors.is_empty() {
+    return Err(format!(
+      "$graphql failed with HTTP {}: {}",
+      response.status, response.body
+    ));
+  }
+
+  let data = response.body.get("data").filter(|it| !it.is_null());
+  if sub_matches.get_flag("introspect") {
+    if let (Some(data), Some(path)) = (data, sub_matches.get_one::<String>("output")) {
+      let text = serde_json::to_string_pretty(data).unwrap_or_default();
+      std::fs::write(path, text).map_err(|err| format!("Cannot write {}: {}", path, err))?;
+      println!("{} schema to {}", style("Saved").green().bold(), path);
+    } else if let Some(data) = data {
+      print_json(data);
+    }
+  } else {
+    match (
+      sub_matches.get_one::<String>("format").unwrap().as_str(),
+      data,
+    ) {
+      ("raw", _) => print_json(&response.body),
+      ("table", Some(Value::Object(data))) => render_tables(data)
+        .iter()
+        .for_each(|line| println!("{}", line)),
+      (_, Some(data)) => print_json(data),
+      _ => {},
+    }
+  }
+
+  for line in format_errors(&query, &errors) {
+    eprintln!("{}", line);
+  }
+  Ok(errors.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_errors() {
+    let query = "{\n  PatientList {\n    nam\n  }\n}";
+    let lines = format_errors(
+      query,
+      &[json!({
+        "message": "Unknown field nam",
+        "locations": [{"line": 3, "column": 5}],
+        "path": ["PatientList", 0]
+      })],
+    );
+    let lines: Vec<String> = lines
+      .iter()
+      .map(|it| console::strip_ansi_codes(it).to_string())
+      .collect();
+    assert_eq!(
+      lines,
+      vec![
+        "error: Unknown field nam",
+        "  path PatientList.0",
+        "  3 |     nam",
+        "    |     ^",
+      ]
+    );
+  }
+
+  #[test]
+  fn test_parse_variables() {
+    let variables = "{\"id\": \"pt-1\"}".to_string();
+    assert_eq!(
+      parse_variables(Some(&variables)).unwrap(),
+      json!({"id": "pt-1"})
+    );
+    assert!(parse_variables(Some(&"[1]".to_string())).is_err());
+    assert_eq!(parse_variables(None).unwrap(), json!({}));
+  }
+}
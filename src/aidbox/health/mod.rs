@@ -0,0 +1,347 @@
+use crate::aidbox::helpers::render_table;
+use clap::ArgAction::SetTrue;
+use clap::{value_parser, Arg, ArgMatches, Command};
+use console::{style, Term};
+use futures_util::future::join_all;
+use log::error;
+use reqwest::Method;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tool_aidbox::BoxClient;
+use tool_config::{AuthMethod, BoxInstance, Config};
+
+/// Rounds kept in the history column
+const HISTORY: usize = 20;
+
+/// Endpoints probed for every instance
+const PROBES: [(&str, &str); 3] = [
+  ("health", "/health"),
+  ("userinfo", "/auth/userinfo"),
+  ("version", "/$version"),
+];
+
+pub fn commands() -> Command {
+  Command::new("health")
+    .about(
+      "Probe /health, /auth/userinfo and $version of instances. Exits with 1 when one is unhealthy",
+    )
+    .args(vec![
+      Arg::new("all")
+        .long("all")
+        .action(SetTrue)
+        .help("Probe every configured instance instead of --instance"),
+      Arg::new("watch")
+        .short('w')
+        .long("watch")
+        .value_parser(value_parser!(u64).range(1..))
+        .help("Repeat every <seconds> with a live table until interrupted. Add --count in scripts, the exit code is only set when watching stops"),
+      Arg::new("count")
+        .long("count")
+        .value_parser(value_parser!(usize))
+        .requires("watch")
+        .help("Stop watching after <count> rounds and exit non-zero if the last round was unhealthy"),
+      Arg::new("timeout")
+        .long("timeout")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value("10")
+        .help("Timeout of a probe in seconds"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  match health(sub_matches).await {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+#[derive(Clone, Debug)]
+struct Probe {
+  /// Error of a failed probe
+  error: Option<String>,
+  latency: Duration,
+  /// `version` of `$version`
+  detail: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Round {
+  instance: String,
+  url: String,
+  probes: Vec<Probe>,
+}
+
+impl Round {
+  fn healthy(&self) -> bool {
+    self.probes.iter().all(|it| it.error.is_none())
+  }
+}
+
+async fn probe(client: &BoxClient, path: &str, timeout: Duration) -> Probe {
+  let started = Instant::now();
+  let response = match client.request(Method::GET, path).await {
+    Ok(builder) => client.send(builder.timeout(timeout)).await,
+    Err(err) => Err(err),
+  };
+  let latency = started.elapsed();
+  match response {
+    Ok(response) if response.is_success() => Probe {
+      error: None,
+      latency,
+      detail: response
+        .body
+        .get("version")
+        .and_then(|it| it.as_str())
+        .map(str::to_string),
+    },
+    Ok(response) => Probe {
+      error: Some(format!("HTTP {}", response.status)),
+      latency,
+      detail: None,
+    },
+    Err(err) => Probe {
+      error: Some(err),
+      latency,
+      detail: None,
+    },
+  }
+}
+
+/// OAuth2 token of the round, requested once under the probe timeout. The client caches it,
+/// so rounds reuse it until it expires
+async fn token(
+  instance: &BoxInstance,
+  client: &BoxClient,
+  timeout: Duration,
+) -> Result<(), String> {
+  if !matches!(
+    instance.auth,
+    AuthMethod::ClientCredentials | AuthMethod::Password
+  ) {
+    return Ok(());
+  }
+  match tokio::time::timeout(timeout, client.access_token()).await {
+    Ok(token) => token.map(|_| ()),
+    Err(_) => Err(format!("Token request timed out after {:?}", timeout)),
+  }
+}
+
+async fn check_instance(
+  key: &str,
+  instance: &BoxInstance,
+  client: &BoxClient,
+  timeout: Duration,
+) -> Round {
+  let probes = match token(instance, client, timeout).await {
+    Ok(()) => join_all(PROBES.iter().map(|(_, path)| probe(client, path, timeout))).await,
+    Err(err) => PROBES
+      .iter()
+      .map(|_| Probe {
+        error: Some(err.clone()),
+        latency: Duration::ZERO,
+        detail: None,
+      })
+      .collect(),
+  };
+  Round {
+    instance: key.to_string(),
+    url: instance.url.clone(),
+    probes,
+  }
+}
+
+/// `history` is the latest results first
+fn history_cell(history: &VecDeque<bool>) -> String {
+  history
+    .iter()
+    .rev()
+    .map(|healthy| match healthy {
+      true => style("●").green().to_string(),
+      false => style("●").red().to_string(),
+    })
+    .collect()
+}
+
+/// Short form of a probe error for the table. The full one is printed below it
+fn short_error(err: &str) -> &str {
+  if err.starts_with("HTTP ") {
+    err
+  } else if err.contains("timed out") {
+    "timeout"
+  } else {
+    "unreachable"
+  }
+}
+
+fn probe_cell(probe: &Probe) -> String {
+  let latency = format!("{}ms", probe.latency.as_millis());
+  match (probe.error.as_ref(), probe.detail.as_ref()) {
+    (Some(err), _) => style(format!("{} {}", short_error(err), latency))
+      .red()
+      .to_string(),
+    (None, Some(detail)) => format!("{} {}", detail, style(latency).dim()),
+    (None, None) => latency,
+  }
+}
+
+fn render(rounds: &[Round], history: &HashMap<String, VecDeque<bool>>) -> Vec<String> {
+  let mut headers = vec!["instance".to_string(), "url".to_string()];
+  headers.extend(PROBES.iter().map(|(name, _)| name.to_string()));
+  headers.extend(["status".to_string(), "history".to_string()]);
+  let headers: Vec<String> = headers
+    .iter()
+    .map(|it| style(it).bold().to_string())
+    .collect();
+
+  let rows: Vec<Vec<String>> = rounds
+    .iter()
+    .map(|round| {
+      let mut row = vec![round.instance.clone(), round.url.clone()];
+      row.extend(round.probes.iter().map(probe_cell));
+      row.push(match round.healthy() {
+        true => style("Ok").green().bold().to_string(),
+        false => style("Error").red().bold().to_string(),
+      });
+      row.push(
+        history
+          .get(&round.instance)
+          .map(history_cell)
+          .unwrap_or_default(),
+      );
+      row
+    })
+    .collect();
+  let mut lines = render_table(&headers, &rows);
+
+  // Transport errors are the same for every probe of an instance, so one is enough
+  for round in rounds {
+    if let Some(err) = round
+      .probes
+      .iter()
+      .filter_map(|it| it.error.as_ref())
+      .find(|it| short_error(it) != it.as_str())
+    {
+      lines.push(format!("{} {}", style(&round.instance).red().bold(), err));
+    }
+  }
+  lines
+}
+
+/// Returns `false` when an instance is unhealthy in the last round.
+/// `--watch` without `--count` only stops on interrupt, so it never returns
+async fn health(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let config = Config::new(None)?;
+  let mut instances: Vec<(&String, &BoxInstance)> = match sub_matches.get_flag("all") {
+    true => config.boxes.iter().collect(),
+    false => {
+      let key = sub_matches.get_one::<String>("instance").unwrap();
+      match config.boxes.get_key_value(key) {
+        Some(it) => vec![it],
+        None => return Err(format!("Instance '{}' doesn't exist", key)),
+      }
+    },
+  };
+  if instances.is_empty() {
+    return Err("Your instance list is empty! Please run box configure".to_string());
+  }
+  instances.sort_by(|a, b| a.0.cmp(b.0));
+  // One client per instance for every round, so its token cache survives between rounds
+  let clients: Vec<BoxClient> = instances
+    .iter()
+    .map(|(key, instance)| BoxClient::new((*instance).clone().to_box_config(key.to_string())))
+    .collect();
+
+  let timeout = Duration::from_secs(*sub_matches.get_one::<u64>("timeout").unwrap());
+  let watch = sub_matches
+    .get_one::<u64>("watch")
+    .map(|it| Duration::from_secs(*it));
+  let count = sub_matches.get_one::<usize>("count").copied();
+  let term = Term::stdout();
+  let mut history: HashMap<String, VecDeque<bool>> = HashMap::new();
+  let mut printed = 0;
+  let mut round = 0;
+  loop {
+    round += 1;
+    let started = Instant::now();
+    let rounds = join_all(
+      instances
+        .iter()
+        .zip(clients.iter())
+        .map(|((key, instance), client)| check_instance(key, instance, client, timeout)),
+    )
+    .await;
+    for it in rounds.iter() {
+      let entry = history.entry(it.instance.clone()).or_default();
+      entry.push_front(it.healthy());
+      entry.truncate(HISTORY);
+    }
+    let healthy = rounds.iter().all(Round::healthy);
+
+    let mut lines = render(&rounds, &history);
+    if watch.is_some() {
+      lines.insert(
+        0,
+        style(format!(
+          "{}  round {}",
+          chrono::Local::now().format("%H:%M:%S"),
+          round
+        ))
+        .dim()
+        .to_string(),
+      );
+    }
+    // Redraw in place on a terminal, append otherwise
+    if printed > 0 && term.is_term() {
+      term.clear_last_lines(printed).unwrap_or_default();
+    }
+    for line in lines.iter() {
+      println!("{}", line);
+    }
+    printed = lines.len();
+
+    match watch {
+      Some(interval) if count.is_none_or(|it| round < it) => {
+        tokio::time::sleep(interval.saturating_sub(started.elapsed())).await
+      },
+      _ => return Ok(healthy),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_history_cell() {
+    let history: VecDeque<bool> = VecDeque::from(vec![false, true, true]);
+    // Oldest first, so the latest failure is the last dot
+    let (ok, failed) = (style("●").green(), style("●").red());
+    assert_eq!(history_cell(&history), format!("{}{}{}", ok, ok, failed));
+    let round = Round {
+      instance: "dev".to_string(),
+      url: "http://localhost:8080".to_string(),
+      probes: vec![
+        Probe {
+          error: None,
+          latency: Duration::from_millis(5),
+          detail: None,
+        },
+        Probe {
+          error: Some("HTTP 503".to_string()),
+          latency: Duration::from_millis(7),
+          detail: None,
+        },
+      ],
+    };
+    assert!(!round.healthy());
+    assert_eq!(
+      console::strip_ansi_codes(&probe_cell(&round.probes[1])),
+      "HTTP 503 7ms"
+    );
+  }
+}
@@ -0,0 +1,401 @@
+use console::{measure_text_width, pad_str, style, Alignment};
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::Confirm;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_json::Value;
+use std::io::Read;
+use std::path::Path;
+use tool_aidbox::rest::RestResponse;
+use tool_aidbox::{create_box, BoxClient};
+use tool_config::get_config_or_error;
+
+/// Client for a configured instance. Checks health, but not credentials
+pub async fn connect(instance: &str) -> Result<BoxClient, String> {
+  let (config, key) = get_config_or_error(instance)?;
+  let box_config = config.boxes.get(key).unwrap();
+
+  create_box(box_config.clone().to_box_config(key.to_string())).await
+}
+
+/// Read JSON or YAML (by `.yaml`/`.yml` extension) from a file, or from stdin for `-`
+pub fn read_value_file(path: &str) -> Result<Value, String> {
+  let source = if path == "-" {
+    let mut buffer = String::new();
+    if let Err(err) = std::io::stdin().read_to_string(&mut buffer) {
+      return Err(format!("Cannot read stdin: {}", err));
+    }
+    buffer
+  } else {
+    match std::fs::read_to_string(path) {
+      Ok(it) => it,
+      Err(err) => return Err(format!("Cannot read {}: {}", path, err)),
+    }
+  };
+
+  parse_value(&source, Path::new(path)).map_err(|err| format!("Cannot parse {}: {}", path, err))
+}
+
+pub fn parse_value(source: &str, path: &Path) -> Result<Value, String> {
+  match path.extension().and_then(|it| it.to_str()) {
+    Some("yaml") | Some("yml") => serde_yaml::from_str(source).map_err(|err| err.to_string()),
+    Some("json") => serde_json::from_str(source).map_err(|err| err.to_string()),
+    _ => serde_json::from_str(source)
+      .or_else(|_| serde_yaml::from_str(source))
+      .map_err(|err: serde_yaml::Error| err.to_string()),
+  }
+}
+
+pub fn print_json(value: &Value) {
+  println!(
+    "{}",
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+  );
+}
+
+/// One line per `OperationOutcome.issue`
+pub fn format_outcome(outcome: &Value) -> Vec<String> {
+  match outcome.get("issue").and_then(Value::as_array) {
+    Some(issues) if !issues.is_empty() => issues
+      .iter()
+      .map(|issue| {
+        let severity = issue
+          .get("severity")
+          .and_then(Value::as_str)
+          .unwrap_or("error");
+        let code = issue.get("code").and_then(Value::as_str).unwrap_or("");
+        let text = issue
+          .get("diagnostics")
+          .and_then(Value::as_str)
+          .or_else(|| {
+            issue
+              .get("details")
+              .and_then(|it| it.get("text"))
+              .and_then(Value::as_str)
+          })
+          .unwrap_or("");
+        let location = issue
+          .get("expression")
+          .or_else(|| issue.get("location"))
+          .and_then(Value::as_array)
+          .map(|it| {
+            it.iter()
+              .filter_map(Value::as_str)
+              .collect::<Vec<_>>()
+              .join(", ")
+          })
+          .unwrap_or_default();
+
+        let severity = match severity {
+          "fatal" | "error" => style(severity).red().bold(),
+          "warning" => style(severity).yellow().bold(),
+          _ => style(severity).cyan(),
+        };
+        match location.is_empty() {
+          true => format!("{} [{}] {}", severity, code, text),
+          false => format!("{} [{}] {} at {}", severity, code, text, location),
+        }
+      })
+      .collect(),
+    _ => vec![outcome.to_string()],
+  }
+}
+
+/// Print the body of a successful response, or the error and exit with a non-zero code
+pub fn print_response(response: RestResponse) {
+  if response.is_success() {
+    if !response.body.is_null() {
+      print_json(&response.body);
+    }
+    return;
+  }
+
+  eprintln!(
+    "{} {}",
+    style("HTTP").red().bold(),
+    style(response.status).red().bold()
+  );
+  if response.is_operation_outcome() {
+    for line in format_outcome(&response.body) {
+      eprintln!("{}", line);
+    }
+  } else if !response.body.is_null() {
+    eprintln!(
+      "{}",
+      serde_json::to_string_pretty(&response.body).unwrap_or_default()
+    );
+  }
+  std::process::exit(1);
+}
+
+/// Confirm a change in the terminal. Fails without one, so scripts pass `--yes` instead
+pub fn confirm(prompt: &str) -> Result<bool, String> {
+  if !console::user_attended() {
+    return Err("No terminal to confirm changes. Use --yes to apply them".to_string());
+  }
+  Confirm::with_theme(&ColorfulTheme::default())
+    .with_prompt(prompt)
+    .default(false)
+    .interact()
+    .map_err(|err| format!("{}. Use --yes to apply without confirmation", err))
+}
+
+/// Random alphanumeric secret
+pub fn generate_secret(length: usize) -> String {
+  rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(length)
+    .map(char::from)
+    .collect()
+}
+
+/// Files with one of the extensions under a path, recursively and sorted. A file path is returned as is
+pub fn collect_files(path: &Path, extensions: &[&str]) -> Result<Vec<std::path::PathBuf>, String> {
+  if path.is_file() {
+    return Ok(vec![path.to_path_buf()]);
+  }
+  if !path.is_dir() {
+    return Err(format!("{} doesn't exist", path.display()));
+  }
+
+  let mut files = vec![];
+  let entries = match std::fs::read_dir(path) {
+    Ok(it) => it,
+    Err(err) => return Err(format!("Cannot read {}: {}", path.display(), err)),
+  };
+  for entry in entries.filter_map(Result::ok) {
+    let entry_path = entry.path();
+    if entry_path.is_dir() {
+      files.extend(collect_files(&entry_path, extensions)?);
+    } else if entry_path
+      .extension()
+      .and_then(|it| it.to_str())
+      .is_some_and(|it| extensions.contains(&it))
+    {
+      files.push(entry_path);
+    }
+  }
+  files.sort();
+  Ok(files)
+}
+
+/// Plain text cell for a JSON value: strings as is, `null` as empty
+pub fn cell(value: &Value) -> String {
+  match value {
+    Value::Null => String::new(),
+    Value::String(text) => text.to_string(),
+    other => other.to_string(),
+  }
+}
+
+/// Column aligned table with a bold header. Cells may contain styles
+pub fn render_table(headers: &[String], rows: &[Vec<String>]) -> Vec<String> {
+  let mut widths: Vec<usize> = headers.iter().map(|it| measure_text_width(it)).collect();
+  for row in rows {
+    for (index, value) in row.iter().enumerate() {
+      if index < widths.len() {
+        widths[index] = widths[index].max(measure_text_width(value));
+      }
+    }
+  }
+  let line = |cells: Vec<String>| {
+    cells
+      .iter()
+      .enumerate()
+      .map(|(index, value)| pad_str(value, widths[index], Alignment::Left, None).to_string())
+      .collect::<Vec<_>>()
+      .join("  ")
+      .trim_end()
+      .to_string()
+  };
+
+  let mut result = vec![line(
+    headers
+      .iter()
+      .map(|it| style(it).bold().to_string())
+      .collect(),
+  )];
+  result.push(
+    widths
+      .iter()
+      .map(|it| "-".repeat(*it))
+      .collect::<Vec<_>>()
+      .join("  "),
+  );
+  for row in rows {
+    result.push(line(
+      (0..headers.len())
+        .map(|index| row.get(index).cloned().unwrap_or_default())
+        .collect(),
+    ));
+  }
+  result
+}
+
+/// RFC 4180 CSV line
+pub fn csv_line(cells: &[String]) -> String {
+  cells
+    .iter()
+    .map(|it| match it.contains([',', '"', '\n', '\r']) {
+      true => format!("\"{}\"", it.replace('"', "\"\"")),
+      false => it.to_string(),
+    })
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// Resource without `meta`, which differs between boxes for the same content
+pub fn resource_content(resource: &Value) -> Value {
+  let mut content = resource.clone();
+  if let Some(object) = content.as_object_mut() {
+    object.remove("meta");
+  }
+  content
+}
+
+/// Copy with object keys sorted at every level. Maps keep insertion order, so equal content
+/// read from different sources may list keys differently
+pub fn sort_keys(value: &Value) -> Value {
+  match value {
+    Value::Object(map) => {
+      let mut keys: Vec<&String> = map.keys().collect();
+      keys.sort();
+      Value::Object(
+        keys
+          .into_iter()
+          .map(|key| (key.to_string(), sort_keys(&map[key])))
+          .collect(),
+      )
+    },
+    Value::Array(items) => Value::Array(items.iter().map(sort_keys).collect()),
+    other => other.clone(),
+  }
+}
+
+/// FNV-1a hash of `resource_content`. Keys are sorted, so equal content hashes the same
+pub fn content_hash(resource: &Value) -> String {
+  let hash = sort_keys(&resource_content(resource))
+    .to_string()
+    .bytes()
+    .fold(0xcbf29ce484222325u64, |hash, byte| {
+      (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+  format!("{:016x}", hash)
+}
+
+/// Line diff by longest common subsequence: `' '` kept, `'-'` only in old, `'+'` only in new
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<(char, &'a str)> {
+  let old: Vec<&str> = old.lines().collect();
+  let new: Vec<&str> = new.lines().collect();
+  let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+  for i in (0..old.len()).rev() {
+    for j in (0..new.len()).rev() {
+      common[i][j] = match old[i] == new[j] {
+        true => common[i + 1][j + 1] + 1,
+        false => common[i + 1][j].max(common[i][j + 1]),
+      };
+    }
+  }
+
+  let (mut i, mut j) = (0, 0);
+  let mut result = vec![];
+  while i < old.len() && j < new.len() {
+    if old[i] == new[j] {
+      result.push((' ', old[i]));
+      i += 1;
+      j += 1;
+    } else if common[i + 1][j] >= common[i][j + 1] {
+      result.push(('-', old[i]));
+      i += 1;
+    } else {
+      result.push(('+', new[j]));
+      j += 1;
+    }
+  }
+  result.extend(old[i..].iter().map(|it| ('-', *it)));
+  result.extend(new[j..].iter().map(|it| ('+', *it)));
+  result
+}
+
+/// Colored changed lines of `diff_lines` with `context` kept lines around them
+pub fn format_diff(diff: &[(char, &str)], context: usize) -> Vec<String> {
+  let changed: Vec<usize> = diff
+    .iter()
+    .enumerate()
+    .filter(|(_, (kind, _))| *kind != ' ')
+    .map(|(index, _)| index)
+    .collect();
+  let visible = |index: usize| {
+    changed
+      .iter()
+      .any(|it| index + context >= *it && index <= it + context)
+  };
+
+  let mut result = vec![];
+  let mut skipped = false;
+  for (index, (kind, line)) in diff.iter().enumerate() {
+    if !visible(index) {
+      skipped = true;
+      continue;
+    }
+    if skipped && !result.is_empty() {
+      result.push(style("  ...").dim().to_string());
+    }
+    skipped = false;
+    result.push(match kind {
+      '-' => style(format!("- {}", line)).red().to_string(),
+      '+' => style(format!("+ {}", line)).green().to_string(),
+      _ => format!("  {}", line),
+    });
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_csv_line() {
+    assert_eq!(
+      csv_line(&["a".to_string(), "b,c".to_string(), "say \"hi\"".to_string()]),
+      "a,\"b,c\",\"say \"\"hi\"\"\""
+    );
+  }
+
+  #[test]
+  fn test_render_table() {
+    let table = render_table(
+      &["id".to_string(), "name".to_string()],
+      &[vec!["1".to_string(), "john".to_string()]],
+    );
+    assert_eq!(console::strip_ansi_codes(&table[0]), "id  name");
+    assert_eq!(table[1], "--  ----");
+    assert_eq!(table[2], "1   john");
+  }
+
+  #[test]
+  fn test_content_hash() {
+    let a = serde_json::json!({"resourceType": "Client", "id": "a", "meta": {"versionId": "1"}});
+    let b = serde_json::json!({"id": "a", "resourceType": "Client", "meta": {"versionId": "7"}});
+    assert_eq!(content_hash(&a), content_hash(&b));
+    assert_ne!(
+      content_hash(&a),
+      content_hash(&resource_content(&serde_json::json!({"id": "b"})))
+    );
+  }
+
+  #[test]
+  fn test_diff_lines() {
+    let diff = diff_lines("a\nb\nc\nd", "a\nc\nd\ne");
+    assert_eq!(
+      diff,
+      vec![(' ', "a"), ('-', "b"), (' ', "c"), (' ', "d"), ('+', "e")]
+    );
+    let lines: Vec<String> = format_diff(&diff, 0)
+      .iter()
+      .map(|it| console::strip_ansi_codes(it).to_string())
+      .collect();
+    assert_eq!(lines, vec!["- b", "  ...", "+ e"]);
+  }
+}
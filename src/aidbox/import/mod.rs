@@ -0,0 +1,629 @@
+use crate::aidbox::helpers::{connect, format_outcome, print_response};
+use async_stream::stream;
+use chrono::Local;
+use clap::ArgAction::SetTrue;
+use clap::{value_parser, Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use flate2::read::MultiGzDecoder;
+use futures_util::{pin_mut, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::error;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tool_aidbox::rest::{entry_request, entry_status, ApiFormat, RestResponse};
+use tool_aidbox::BoxClient;
+
+pub fn commands() -> Command {
+  Command::new("import")
+    .about("Import NDJSON(.gz) files, directories of them or urls")
+    .args(vec![
+      Arg::new("paths")
+        .required(true)
+        .num_args(1..)
+        .value_hint(ValueHint::AnyPath)
+        .help("Files, directories or http(s) urls. Type is taken from resourceType or the file name (Patient.ndjson.gz)"),
+      Arg::new("mode")
+        .long("mode")
+        .value_parser(["auto", "load", "import", "bundle"])
+        .default_value("auto")
+        .help("auto uses $load for urls and bundles for local files. load and import need urls reachable by the box"),
+      Arg::new("batch-size")
+        .long("batch-size")
+        .value_parser(value_parser!(usize))
+        .default_value("100")
+        .help("Resources per bundle"),
+      Arg::new("concurrency")
+        .short('c')
+        .long("concurrency")
+        .value_parser(value_parser!(usize))
+        .default_value("4")
+        .help("Bundles in flight"),
+      Arg::new("offset")
+        .long("offset")
+        .value_parser(value_parser!(usize))
+        .default_value("0")
+        .help("Skip this many lines across all inputs. Use to resume an interrupted import"),
+      Arg::new("bundle-type")
+        .long("bundle-type")
+        .value_parser(["transaction", "batch"])
+        .default_value("transaction")
+        .help("transaction rolls back the whole bundle on error, batch reports every line"),
+      Arg::new("errors")
+        .long("errors")
+        .value_hint(ValueHint::FilePath)
+        .help("Write failed lines to this file as NDJSON"),
+      Arg::new("fhir")
+        .long("fhir")
+        .action(SetTrue)
+        .help("Data is in FHIR format. Bundles are sent to /fhir"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  match import(sub_matches).await {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Source {
+  File(PathBuf),
+  Url(String),
+}
+
+impl Source {
+  fn name(&self) -> String {
+    match self {
+      Source::File(path) => path.display().to_string(),
+      Source::Url(url) => url.to_string(),
+    }
+  }
+
+  /// File path or url path, without the query string
+  fn path(&self) -> String {
+    match self {
+      Source::File(path) => path.display().to_string(),
+      Source::Url(url) => match reqwest::Url::parse(url) {
+        Ok(it) => it.path().to_string(),
+        Err(_) => url.to_string(),
+      },
+    }
+  }
+
+  /// `Patient.ndjson.gz` -> `Patient`
+  fn resource_type(&self) -> Option<String> {
+    let path = self.path();
+    let file_name = path.rsplit('/').next()?;
+    let stem = file_name.split('.').next()?;
+    match stem.chars().next() {
+      Some(first) if first.is_ascii_uppercase() => Some(stem.to_string()),
+      _ => None,
+    }
+  }
+
+  fn is_gzip(&self) -> bool {
+    self.path().ends_with(".gz")
+  }
+}
+
+fn is_ndjson(name: &str) -> bool {
+  name.ends_with(".ndjson") || name.ends_with(".ndjson.gz")
+}
+
+fn collect_sources(paths: &[String]) -> Result<Vec<Source>, String> {
+  let mut sources = vec![];
+  for path in paths {
+    if path.starts_with("http://") || path.starts_with("https://") {
+      sources.push(Source::Url(path.to_string()));
+      continue;
+    }
+    let path_buf = PathBuf::from(path);
+    if path_buf.is_dir() {
+      let mut files: Vec<PathBuf> = match std::fs::read_dir(&path_buf) {
+        Ok(it) => it
+          .filter_map(Result::ok)
+          .map(|entry| entry.path())
+          .filter(|it| it.is_file() && is_ndjson(&it.to_string_lossy()))
+          .collect(),
+        Err(err) => return Err(format!("Cannot read {}: {}", path, err)),
+      };
+      files.sort();
+      sources.extend(files.into_iter().map(Source::File));
+    } else if path_buf.is_file() {
+      sources.push(Source::File(path_buf));
+    } else {
+      return Err(format!("{} doesn't exist", path));
+    }
+  }
+  Ok(sources)
+}
+
+async fn open(source: &Source) -> Result<Pin<Box<dyn AsyncRead + Send>>, String> {
+  match source {
+    Source::File(path) => match tokio::fs::File::open(path).await {
+      Ok(file) => Ok(Box::pin(file)),
+      Err(err) => Err(err.to_string()),
+    },
+    Source::Url(url) => match reqwest::get(url).await {
+      Ok(response) if response.status().is_success() => {
+        let body = response
+          .bytes_stream()
+          .map(|chunk| chunk.map_err(io::Error::other));
+        Ok(Box::pin(StreamReader::new(body)))
+      },
+      Ok(response) => Err(format!("HTTP {}", response.status().as_u16())),
+      Err(err) => Err(err.to_string()),
+    },
+  }
+}
+
+/// Decompresses and splits the source on a blocking thread.
+/// Lines arrive through the channel while the body is still downloading
+fn read_lines(
+  source: &Source,
+  reader: Pin<Box<dyn AsyncRead + Send>>,
+) -> mpsc::Receiver<io::Result<String>> {
+  let (sender, receiver) = mpsc::channel(1024);
+  let reader = SyncIoBridge::new(reader);
+  let gzip = source.is_gzip();
+  tokio::task::spawn_blocking(move || {
+    let reader: Box<dyn BufRead> = match gzip {
+      true => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+      false => Box::new(BufReader::new(reader)),
+    };
+    for line in reader.lines() {
+      let failed = line.is_err();
+      if sender.blocking_send(line).is_err() || failed {
+        break;
+      }
+    }
+  });
+  receiver
+}
+
+struct Line {
+  source: String,
+  default_type: Option<String>,
+  line: usize,
+  offset: usize,
+  resource: Value,
+}
+
+struct Failure {
+  source: String,
+  line: usize,
+  offset: usize,
+  status: Option<u16>,
+  messages: Vec<String>,
+  outcome: Option<Value>,
+}
+
+impl Failure {
+  fn new(line: &Line, status: Option<u16>, messages: Vec<String>, outcome: Option<Value>) -> Self {
+    Failure {
+      source: line.source.clone(),
+      line: line.line,
+      offset: line.offset,
+      status,
+      messages,
+      outcome,
+    }
+  }
+
+  fn report(&self) -> Value {
+    json!({
+      "file": self.source,
+      "line": self.line,
+      "offset": self.offset,
+      "status": self.status,
+      "error": console::strip_ansi_codes(&self.messages.join("; ")),
+      "outcome": self.outcome,
+    })
+  }
+}
+
+#[derive(Default)]
+struct Batch {
+  lines: Vec<Line>,
+  failures: Vec<Failure>,
+}
+
+struct BatchResult {
+  /// Offsets of the batch, `start..end`
+  start: usize,
+  end: usize,
+  sent: usize,
+  failures: Vec<Failure>,
+  /// The request itself failed, nothing is known about the lines
+  interrupted: bool,
+}
+
+async fn send_batch(
+  client: BoxClient,
+  format: ApiFormat,
+  bundle_type: String,
+  batch: Batch,
+) -> BatchResult {
+  let start = batch
+    .lines
+    .first()
+    .map(|it| it.offset)
+    .or_else(|| batch.failures.first().map(|it| it.offset))
+    .unwrap_or_default();
+  let end = batch
+    .lines
+    .iter()
+    .map(|it| it.offset)
+    .chain(batch.failures.iter().map(|it| it.offset))
+    .max()
+    .map_or(start, |it| it + 1);
+  let mut failures = batch.failures;
+  let mut lines = vec![];
+  let mut entries = vec![];
+
+  for line in batch.lines {
+    match entry_request(&line.resource, line.default_type.as_deref()) {
+      Ok(request) => {
+        entries.push(json!({"resource": line.resource, "request": request}));
+        lines.push(line);
+      },
+      Err(err) => failures.push(Failure::new(&line, None, vec![err], None)),
+    }
+  }
+
+  let sent = lines.len() + failures.len();
+  if entries.is_empty() {
+    return BatchResult {
+      start,
+      end,
+      sent,
+      failures,
+      interrupted: false,
+    };
+  }
+
+  let bundle = json!({"resourceType": "Bundle", "type": bundle_type, "entry": entries});
+  let response: RestResponse = match client.transaction(format, &bundle).await {
+    Ok(it) => it,
+    Err(err) => {
+      failures.extend(
+        lines
+          .iter()
+          .map(|line| Failure::new(line, None, vec![err.clone()], None)),
+      );
+      return BatchResult {
+        start,
+        end,
+        sent,
+        failures,
+        interrupted: true,
+      };
+    },
+  };
+
+  if !response.is_success() {
+    let messages = match response.is_operation_outcome() {
+      true => format_outcome(&response.body),
+      false => vec![response.body.to_string()],
+    };
+    let outcome = Some(response.body.clone()).filter(|_| response.is_operation_outcome());
+    failures.extend(lines.iter().map(|line| {
+      Failure::new(
+        line,
+        Some(response.status),
+        messages.clone(),
+        outcome.clone(),
+      )
+    }));
+    return BatchResult {
+      start,
+      end,
+      sent,
+      failures,
+      interrupted: false,
+    };
+  }
+
+  let response_entries = response
+    .body
+    .get("entry")
+    .and_then(Value::as_array)
+    .cloned()
+    .unwrap_or_default();
+  for (line, entry) in lines.iter().zip(response_entries.iter()) {
+    match entry_status(entry) {
+      Some(status) if !(200..300).contains(&status) => {
+        let outcome = entry
+          .get("response")
+          .and_then(|it| it.get("outcome"))
+          .or_else(|| entry.get("resource"))
+          .cloned();
+        let messages = match &outcome {
+          Some(it) => format_outcome(it),
+          None => vec![format!("HTTP {}", status)],
+        };
+        failures.push(Failure::new(line, Some(status), messages, outcome));
+      },
+      _ => {},
+    }
+  }
+
+  BatchResult {
+    start,
+    end,
+    sent,
+    failures,
+    interrupted: false,
+  }
+}
+
+/// Returns `false` when some lines were not imported
+async fn import(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let paths: Vec<String> = sub_matches
+    .get_many::<String>("paths")
+    .unwrap()
+    .cloned()
+    .collect();
+  let sources = collect_sources(&paths)?;
+  if sources.is_empty() {
+    return Err("No .ndjson or .ndjson.gz files found".to_string());
+  }
+
+  let client = connect(instance).await?;
+
+  let (urls, files): (Vec<Source>, Vec<Source>) = sources
+    .into_iter()
+    .partition(|it| matches!(it, Source::Url(..)));
+
+  let mut ok = true;
+  let bundled = match sub_matches.get_one::<String>("mode").unwrap().as_str() {
+    "bundle" => urls.into_iter().chain(files).collect(),
+    "import" => {
+      if !files.is_empty() {
+        return Err(
+          "$import needs urls reachable by the box. Use --mode bundle for local files".to_string(),
+        );
+      }
+      let mut inputs = vec![];
+      for url in urls.iter() {
+        match url.resource_type() {
+          Some(resource_type) => inputs.push((resource_type, url.name())),
+          None => return Err(format!("Cannot take resource type from {}", url.name())),
+        }
+      }
+      let id = format!("aidbox-tool-{}", Local::now().format("%Y%m%d%H%M%S"));
+      print_response(client.bulk_import(&id, &inputs).await?);
+      return Ok(true);
+    },
+    mode => {
+      if mode == "load" && !files.is_empty() {
+        return Err(
+          "$load needs urls reachable by the box. Use --mode bundle for local files".to_string(),
+        );
+      }
+      let mut fallback = vec![];
+      for url in urls {
+        let response = client
+          .load(&url.name(), url.resource_type().as_deref())
+          .await?;
+        match response.status {
+          // No `$load` on this box
+          404 | 405 if mode == "auto" => {
+            log::warn!("$load is not available, sending {} in bundles", url.name());
+            fallback.push(url);
+          },
+          _ if response.is_success() => {
+            println!("{} {}", style("Loaded").green().bold(), url.name());
+            if !response.body.is_null() {
+              println!("{}", response.body);
+            }
+          },
+          status => {
+            ok = false;
+            eprintln!(
+              "{} {} HTTP {}",
+              style("Failed").red().bold(),
+              url.name(),
+              status
+            );
+            for line in format_outcome(&response.body) {
+              eprintln!("{}", line);
+            }
+          },
+        }
+      }
+      fallback.into_iter().chain(files).collect::<Vec<_>>()
+    },
+  };
+
+  if bundled.is_empty() {
+    return Ok(ok);
+  }
+
+  let format = match sub_matches.get_flag("fhir") {
+    true => ApiFormat::Fhir,
+    false => ApiFormat::Aidbox,
+  };
+  let batch_size = (*sub_matches.get_one::<usize>("batch-size").unwrap()).max(1);
+  let concurrency = (*sub_matches.get_one::<usize>("concurrency").unwrap()).max(1);
+  let skip = *sub_matches.get_one::<usize>("offset").unwrap();
+  let bundle_type = sub_matches
+    .get_one::<String>("bundle-type")
+    .unwrap()
+    .to_string();
+  let mut report: Option<File> = match sub_matches.get_one::<String>("errors") {
+    Some(path) => match File::create(path) {
+      Ok(it) => Some(it),
+      Err(err) => return Err(format!("Cannot create {}: {}", path, err)),
+    },
+    None => None,
+  };
+
+  let pb = ProgressBar::new_spinner();
+  pb.set_style(
+    ProgressStyle::with_template("{spinner:.cyan} {pos} lines {per_sec} {msg}").unwrap(),
+  );
+
+  let batches = stream! {
+    let mut offset = 0usize;
+    let mut batch = Batch::default();
+    for source in bundled.iter() {
+      let reader = match open(source).await {
+        Ok(it) => it,
+        Err(err) => {
+          batch.failures.push(Failure {
+            source: source.name(),
+            line: 0,
+            offset,
+            status: None,
+            messages: vec![format!("Cannot read: {}", err)],
+            outcome: None,
+          });
+          continue;
+        },
+      };
+      let mut lines = read_lines(source, reader);
+      let mut index = 0usize;
+      while let Some(text) = lines.recv().await {
+        index += 1;
+        let text = match text {
+          Ok(it) => it,
+          Err(err) => {
+            batch.failures.push(Failure {
+              source: source.name(),
+              line: index,
+              offset,
+              status: None,
+              messages: vec![format!("Cannot read: {}", err)],
+              outcome: None,
+            });
+            break;
+          },
+        };
+        offset += 1;
+        if offset <= skip || text.trim().is_empty() {
+          continue;
+        }
+        let line = Line {
+          source: source.name(),
+          default_type: source.resource_type(),
+          line: index,
+          offset: offset - 1,
+          resource: Value::Null,
+        };
+        match serde_json::from_str::<Value>(&text) {
+          Ok(resource) => batch.lines.push(Line { resource, ..line }),
+          Err(err) => batch.failures.push(Failure::new(&line, None, vec![format!("Invalid JSON: {}", err)], None)),
+        }
+        if batch.lines.len() + batch.failures.len() >= batch_size {
+          yield std::mem::take(&mut batch);
+        }
+      }
+    }
+    if !batch.lines.is_empty() || !batch.failures.is_empty() {
+      yield batch;
+    }
+  };
+
+  let results = batches
+    .map(|batch| send_batch(client.clone(), format, bundle_type.clone(), batch))
+    .buffer_unordered(concurrency);
+  pin_mut!(results);
+
+  let mut total = 0usize;
+  let mut failed = 0usize;
+  // Bundles run concurrently, so later ones may be delivered after an earlier one failed
+  let mut undelivered: Vec<(usize, usize)> = vec![];
+  let mut delivered_end = 0usize;
+
+  while let Some(result) = results.next().await {
+    total += result.sent;
+    failed += result.failures.len();
+    match result.interrupted {
+      true => undelivered.push((result.start, result.end)),
+      false => delivered_end = delivered_end.max(result.end),
+    }
+    for failure in result.failures.iter() {
+      pb.println(format!(
+        "{}:{} {}",
+        style(&failure.source).bold(),
+        failure.line,
+        failure.messages.join("; ")
+      ));
+      if let Some(file) = report.as_mut() {
+        if let Err(err) = writeln!(file, "{}", failure.report()) {
+          pb.println(format!("Cannot write error report: {}", err));
+          report = None;
+        }
+      }
+    }
+    pb.inc(result.sent as u64);
+    if failed > 0 {
+      pb.set_message(format!("{} failed", failed));
+    }
+  }
+
+  pb.finish_and_clear();
+  eprintln!(
+    "Imported {} of {} lines in {:?}s",
+    total - failed,
+    total,
+    (pb.elapsed().as_secs_f64() * 100f64).floor() / 100f64
+  );
+
+  undelivered.sort();
+  match undelivered.first() {
+    // Nothing after the first undelivered bundle got through, so skipping up to it is safe
+    Some((start, _)) if delivered_end <= *start => eprintln!(
+      "Some bundles were not delivered. Resume with {}",
+      style(format!("--offset {}", start)).bold()
+    ),
+    Some(..) => eprintln!(
+      "Bundles of offsets {} were not delivered while later ones were, so --offset would resend delivered lines. Import the failed lines again, --errors writes them to a file",
+      undelivered
+        .iter()
+        .map(|(start, end)| format!("{}..{}", start, end))
+        .collect::<Vec<_>>()
+        .join(", ")
+    ),
+    None => {},
+  }
+
+  Ok(ok && failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_resource_type_from_name() {
+    assert_eq!(
+      Source::File(PathBuf::from("/tmp/export/Patient.ndjson.gz")).resource_type(),
+      Some("Patient".to_string())
+    );
+    assert_eq!(
+      Source::Url("https://storage/bucket/data.ndjson".to_string()).resource_type(),
+      None
+    );
+  }
+
+  #[test]
+  fn test_signed_url() {
+    let source = Source::Url("https://storage/bucket/Patient.ndjson.gz?sig=a/b.c".to_string());
+    assert!(source.is_gzip());
+    assert_eq!(source.resource_type(), Some("Patient".to_string()));
+  }
+}
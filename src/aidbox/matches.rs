@@ -1,13 +1,13 @@
 use clap::ArgMatches;
 use console::{style, Emoji};
 use dialoguer::theme::ColorfulTheme;
-use dialoguer::{Confirm, Input, Password};
+use dialoguer::{Confirm, Input, Password, Select};
 use log::{error, info};
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::str::FromStr;
 use tool_aidbox::create_box;
-use tool_config::{get_config_or_error, BoxConfig, BoxInstance, Config};
+use tool_config::{get_config_or_error, AuthMethod, BoxConfig, BoxInstance, Config};
 
 pub async fn configure(sub_matches: &ArgMatches) {
   let mut config = match Config::new(None) {
@@ -18,30 +18,63 @@ pub async fn configure(sub_matches: &ArgMatches) {
     },
   };
   let key = sub_matches.get_one::<String>("instance").unwrap();
-  let (current_url, current_username, current_password) = match config.boxes.get(key) {
-    Some(current_box) => (
-      Some(current_box.url.clone()),
-      Some(current_box.client.clone()),
-      Some(current_box.secret.clone()),
-    ),
-    None => (None, None, None),
-  };
-
-  let url = prompt::<String>("Aidbox URL", current_url);
+  let current = config.boxes.get(key).cloned();
 
-  let username = prompt::<String>("ClientID", current_username);
+  let url = prompt::<String>("Aidbox URL", current.as_ref().map(|it| it.url.clone()));
 
-  let password = Password::with_theme(&ColorfulTheme::default())
-    .allow_empty_password(current_password.is_some())
-    .report(true)
-    .with_prompt("Client secret")
+  let current_auth = current.as_ref().map(|it| it.auth).unwrap_or_default();
+  let auth = AuthMethod::ALL[Select::with_theme(&ColorfulTheme::default())
+    .with_prompt("Auth method")
+    .items(&AuthMethod::ALL.map(|it| it.title()))
+    .default(
+      AuthMethod::ALL
+        .iter()
+        .position(|it| *it == current_auth)
+        .unwrap_or(0),
+    )
     .interact()
-    .unwrap();
+    .unwrap()];
 
-  let target_password = if password.is_empty() {
-    current_password.unwrap()
-  } else {
-    password
+  let (username, target_password) = match auth {
+    AuthMethod::Bearer => (
+      current
+        .as_ref()
+        .map(|it| it.client.clone())
+        .unwrap_or_default(),
+      current
+        .as_ref()
+        .map(|it| it.secret.clone())
+        .unwrap_or_default(),
+    ),
+    _ => (
+      prompt::<String>("ClientID", current.as_ref().map(|it| it.client.clone())),
+      secret_prompt(
+        "Client secret",
+        current.as_ref().map(|it| it.secret.clone()),
+      ),
+    ),
+  };
+
+  let token = match auth {
+    AuthMethod::Bearer => Some(secret_prompt(
+      "Bearer token",
+      current.as_ref().and_then(|it| it.token.clone()),
+    )),
+    _ => None,
+  };
+
+  let (user, user_password) = match auth {
+    AuthMethod::Password => (
+      Some(prompt::<String>(
+        "Username",
+        current.as_ref().and_then(|it| it.username.clone()),
+      )),
+      Some(secret_prompt(
+        "User password",
+        current.as_ref().and_then(|it| it.password.clone()),
+      )),
+    ),
+    _ => (None, None),
   };
 
   let box_check = create_box(BoxConfig {
@@ -49,6 +82,10 @@ pub async fn configure(sub_matches: &ArgMatches) {
     url: url.clone(),
     client: username.clone(),
     secret: target_password.clone(),
+    auth,
+    token: token.clone(),
+    username: user.clone(),
+    password: user_password.clone(),
     tags: None,
   })
   .await;
@@ -76,6 +113,10 @@ pub async fn configure(sub_matches: &ArgMatches) {
         url,
         client: username,
         secret: target_password,
+        auth,
+        token,
+        username: user,
+        password: user_password,
         status,
         last_checked: Some(chrono::offset::Utc::now()),
         status_message: message,
@@ -125,14 +166,7 @@ pub async fn get_box_info(sub_matches: &ArgMatches) {
   {
     let box_config = config.boxes.get(key).unwrap();
 
-    let box_check = create_box(BoxConfig {
-      key: key.to_string(),
-      url: box_config.url.clone(),
-      client: box_config.client.clone(),
-      secret: box_config.secret.clone(),
-      tags: None,
-    })
-    .await;
+    let box_check = create_box(box_config.clone().to_box_config(key.to_string())).await;
 
     match box_check {
       Ok(instance) => match instance.get_user_info().await {
@@ -184,15 +218,7 @@ pub async fn instance_list(sub_matches: &ArgMatches) {
     for (key, mut value) in config.boxes.clone() {
       if need_check {
         println!("Check {} instance...", style(key.clone()).cyan().bold());
-        match create_box(BoxConfig {
-          key: key.to_string(),
-          url: value.url.clone(),
-          client: value.client.clone(),
-          secret: value.secret.clone(),
-          tags: None,
-        })
-        .await
-        {
+        match create_box(value.clone().to_box_config(key.to_string())).await {
           Ok(instance) => match instance.get_user_info().await {
             Ok(_) => {
               value.last_checked = Some(chrono::offset::Utc::now());
@@ -254,13 +280,13 @@ pub async fn instance_list(sub_matches: &ArgMatches) {
     }
   } else {
     println!(
-      "You instance list is empty! Please run {}",
+      "Your instance list is empty! Please run {}",
       style("box configure").cyan().bold()
     );
   }
 }
 
-fn prompt<T>(prompt: &str, default: Option<T>) -> T
+pub fn prompt<T>(prompt: &str, default: Option<T>) -> T
 where
   T: Clone + ToString + FromStr,
   <T as FromStr>::Err: Debug + ToString,
@@ -275,3 +301,19 @@ where
 
   builder.interact_text().unwrap()
 }
+
+/// Hidden input. Empty input keeps the current value when there is one
+pub fn secret_prompt(prompt: &str, current: Option<String>) -> String {
+  let value = Password::with_theme(&ColorfulTheme::default())
+    .allow_empty_password(current.is_some())
+    .report(true)
+    .with_prompt(prompt)
+    .interact()
+    .unwrap();
+
+  if value.is_empty() {
+    current.unwrap_or_default()
+  } else {
+    value
+  }
+}
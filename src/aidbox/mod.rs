@@ -1,4 +1,25 @@
+pub mod apply;
+pub mod attachment;
+pub mod bundle;
+pub mod clients;
+pub mod diff;
+pub mod export;
+pub mod fhirpath;
+pub mod graphql;
+pub mod health;
+pub mod helpers;
+pub mod import;
 pub mod matches;
+pub mod policy;
+pub mod resource;
+pub mod rpc;
+pub mod search;
+pub mod sql;
+pub mod sync;
+pub mod terminology;
+pub mod users;
+pub mod validate;
+pub mod zen;
 
 use clap::{Arg, ArgAction, ArgMatches, Command, ValueHint};
 
@@ -25,6 +46,26 @@ pub fn commands() -> Command {
           .help("Check if the configuration of each instance is correct")]),
     )
     .subcommand(Command::new("info").about("Show box info based on $version endpoint"))
+    .subcommand(resource::commands())
+    .subcommand(search::commands())
+    .subcommand(import::commands())
+    .subcommand(export::commands())
+    .subcommand(bundle::commands())
+    .subcommand(sql::commands())
+    .subcommand(rpc::commands())
+    .subcommand(zen::commands())
+    .subcommand(terminology::commands())
+    .subcommand(validate::commands())
+    .subcommand(sync::commands())
+    .subcommand(apply::commands())
+    .subcommand(diff::commands())
+    .subcommand(policy::commands())
+    .subcommand(users::commands())
+    .subcommand(clients::commands())
+    .subcommand(health::commands())
+    .subcommand(attachment::commands())
+    .subcommand(graphql::commands())
+    .subcommand(fhirpath::commands())
 }
 
 pub async fn sub_matches(sub_matches: &ArgMatches) {
@@ -35,6 +76,26 @@ pub async fn sub_matches(sub_matches: &ArgMatches) {
     ("info", sub_matches) => matches::get_box_info(sub_matches).await,
     ("list", sub_matches) => matches::instance_list(sub_matches).await,
     ("open", sub_matches) => matches::open_ui(sub_matches),
+    ("resource", sub_matches) => resource::sub_matches(sub_matches).await,
+    ("search", sub_matches) => search::sub_matches(sub_matches).await,
+    ("import", sub_matches) => import::sub_matches(sub_matches).await,
+    ("export", sub_matches) => export::sub_matches(sub_matches).await,
+    ("bundle", sub_matches) => bundle::sub_matches(sub_matches).await,
+    ("sql", sub_matches) => sql::sub_matches(sub_matches).await,
+    ("rpc", sub_matches) => rpc::sub_matches(sub_matches).await,
+    ("zen", sub_matches) => zen::sub_matches(sub_matches).await,
+    ("terminology", sub_matches) => terminology::sub_matches(sub_matches).await,
+    ("validate", sub_matches) => validate::sub_matches(sub_matches).await,
+    ("sync", sub_matches) => sync::sub_matches(sub_matches).await,
+    ("apply", sub_matches) => apply::sub_matches(sub_matches).await,
+    ("diff", sub_matches) => diff::sub_matches(sub_matches).await,
+    ("policy", sub_matches) => policy::sub_matches(sub_matches).await,
+    ("users", sub_matches) => users::sub_matches(sub_matches).await,
+    ("clients", sub_matches) => clients::sub_matches(sub_matches).await,
+    ("health", sub_matches) => health::sub_matches(sub_matches).await,
+    ("attachment", sub_matches) => attachment::sub_matches(sub_matches).await,
+    ("graphql", sub_matches) => graphql::sub_matches(sub_matches).await,
+    ("fhirpath", sub_matches) => fhirpath::sub_matches(sub_matches).await,
     (name, _) => {
       unreachable!("Unsupported subcommand `{}`", name)
     },
@@ -0,0 +1,534 @@
+use crate::aidbox::helpers::{collect_files, connect, print_json, read_value_file};
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use log::error;
+use reqwest::Method;
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use tool_aidbox::BoxClient;
+
+pub fn commands() -> Command {
+  Command::new("policy")
+    .about("Check AccessPolicies: would a request be allowed, and which policy matched")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .subcommand(
+      Command::new("check")
+        .about("Evaluate one request")
+        .args(vec![
+          Arg::new("method")
+            .required(true)
+            .help("HTTP method. Example: GET"),
+          Arg::new("uri")
+            .required(true)
+            .help("Request URI with query. Example: /Patient?name=john"),
+          Arg::new("user")
+            .long("user")
+            .help("User id the request is made by"),
+          Arg::new("client")
+            .long("client")
+            .help("Client id the request is made by"),
+          Arg::new("header")
+            .short('H')
+            .long("header")
+            .action(clap::ArgAction::Append)
+            .help("Request header as key=value. Repeatable"),
+          Arg::new("body")
+            .long("body")
+            .value_hint(ValueHint::FilePath)
+            .help("JSON/YAML file with the request body"),
+          via_arg(),
+          execute_arg(),
+          Arg::new("format")
+            .long("format")
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .help("Output format"),
+        ]),
+    )
+    .subcommand(
+      Command::new("test")
+        .about("Run a suite of requests with expected allow/deny. Exits with 1 when a case fails")
+        .args(vec![
+          Arg::new("files")
+            .required(true)
+            .num_args(1..)
+            .value_hint(ValueHint::AnyPath)
+            .help("JSON/YAML suites or directories of them"),
+          via_arg(),
+          execute_arg(),
+        ]),
+    )
+}
+
+fn via_arg() -> Arg {
+  Arg::new("via")
+    .long("via")
+    .value_parser(["auto", "policy-debug", "debug"])
+    .default_value("auto")
+    .help("$policy-debug simulates the user and client. debug sends the request with __debug=policy as the configured client. auto tries $policy-debug first")
+}
+
+fn execute_arg() -> Arg {
+  Arg::new("execute")
+    .long("execute")
+    .action(clap::ArgAction::SetTrue)
+    .help("Allow __debug=policy to send requests other than GET/HEAD. They run for real and may change data")
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("check", sub_matches) => check(sub_matches).await,
+    ("test", sub_matches) => test(sub_matches).await,
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  match result {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Via {
+  Auto,
+  PolicyDebug,
+  Debug,
+}
+
+impl Via {
+  fn parse(value: &str) -> Via {
+    match value {
+      "policy-debug" => Via::PolicyDebug,
+      "debug" => Via::Debug,
+      _ => Via::Auto,
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct PolicyRequest {
+  method: String,
+  uri: String,
+  user: Option<String>,
+  client: Option<String>,
+  headers: Map<String, Value>,
+  body: Option<Value>,
+}
+
+impl PolicyRequest {
+  fn label(&self) -> String {
+    let mut label = format!("{} {}", self.method, self.uri);
+    if let Some(user) = self.user.as_ref() {
+      label = format!("{} as User/{}", label, user);
+    }
+    if let Some(client) = self.client.as_ref() {
+      label = format!("{} via Client/{}", label, client);
+    }
+    label
+  }
+
+  /// Ring like request map taken by `$policy-debug`
+  fn to_debug_request(&self) -> Value {
+    let (path, query) = match self.uri.split_once('?') {
+      Some((path, query)) => (path, Some(query)),
+      None => (self.uri.as_str(), None),
+    };
+    let mut request = json!({
+      "request-method": self.method.to_lowercase(),
+      "uri": path,
+      "headers": self.headers,
+    });
+    if let Some(query) = query {
+      request["query-string"] = json!(query);
+    }
+    if let Some(body) = self.body.as_ref() {
+      request["body"] = body.clone();
+    }
+    if let Some(user) = self.user.as_ref() {
+      request["user"] = json!({"resourceType": "User", "id": user});
+    }
+    if let Some(client) = self.client.as_ref() {
+      request["client"] = json!({"resourceType": "Client", "id": client});
+    }
+    request
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Decision {
+  allowed: bool,
+  /// Evaluated policies with their result
+  policies: Vec<(String, bool)>,
+}
+
+impl Decision {
+  fn matched(&self) -> Vec<&str> {
+    self
+      .policies
+      .iter()
+      .filter(|(_, allowed)| *allowed)
+      .map(|(id, _)| id.as_str())
+      .collect()
+  }
+}
+
+fn eval_result(value: &Value) -> Option<bool> {
+  ["eval-result", "allowed", "result"]
+    .iter()
+    .find_map(|key| value.get(*key).and_then(Value::as_bool))
+}
+
+/// Evaluated policies of a debug body: `policies` as a list or a map by id
+fn debug_policies(body: &Value) -> Vec<(String, bool)> {
+  match body.get("policies") {
+    Some(Value::Array(items)) => items
+      .iter()
+      .filter_map(|item| {
+        let id = item
+          .get("id")
+          .or_else(|| item.get("policy-id"))
+          .and_then(Value::as_str)?;
+        Some((id.to_string(), eval_result(item).unwrap_or(false)))
+      })
+      .collect(),
+    Some(Value::Object(map)) => map
+      .iter()
+      .map(|(id, item)| (id.to_string(), eval_result(item).unwrap_or(false)))
+      .collect(),
+    _ => vec![],
+  }
+}
+
+/// `$policy-debug` result. Without an explicit result the request is allowed when a policy matched
+fn parse_decision(body: &Value) -> Decision {
+  let policies = debug_policies(body);
+  let allowed = eval_result(body).unwrap_or_else(|| policies.iter().any(|(_, allowed)| *allowed));
+  Decision { allowed, policies }
+}
+
+/// Methods `__debug=policy` may send without `--execute`, as they don't change data
+const SAFE_METHODS: [&str; 2] = ["GET", "HEAD"];
+
+async fn evaluate(
+  client: &BoxClient,
+  request: &PolicyRequest,
+  via: Via,
+  execute: bool,
+) -> Result<Decision, String> {
+  if via != Via::Debug {
+    let response = client.policy_debug(&request.to_debug_request()).await?;
+    match response.status {
+      200..=299 => return Ok(parse_decision(&response.body)),
+      404 if via == Via::Auto => {},
+      status => {
+        return Err(format!(
+          "$policy-debug failed with HTTP {}: {}",
+          status, response.body
+        ))
+      },
+    }
+  }
+
+  if request.user.is_some() || request.client.is_some() {
+    return Err(
+      "$policy-debug is not available, and __debug=policy can't act as another user or client"
+        .to_string(),
+    );
+  }
+  // Unlike $policy-debug, the box runs the request itself
+  if !execute && !SAFE_METHODS.contains(&request.method.as_str()) {
+    return Err(format!(
+      "$policy-debug is not available, and __debug=policy would run {} for real. Use --execute to send it",
+      request.method
+    ));
+  }
+  let method = Method::from_bytes(request.method.to_uppercase().as_bytes())
+    .map_err(|_| format!("Invalid method {}", request.method))?;
+  let mut builder = client
+    .request(method, &request.uri)
+    .await?
+    .query(&[("__debug", "policy")]);
+  for (key, value) in request.headers.iter() {
+    builder = builder.header(key.as_str(), value.as_str().unwrap_or_default());
+  }
+  if let Some(body) = request.body.as_ref() {
+    builder = builder.json(body);
+  }
+  let response = client.send(builder).await?;
+  debug_decision(response.status, &response.body)
+}
+
+/// Decision of a `__debug=policy` response. Denied requests come back as 401/403, any other
+/// failure is an error rather than a verdict
+fn debug_decision(status: u16, body: &Value) -> Result<Decision, String> {
+  if !(200..=299).contains(&status) && status != 401 && status != 403 {
+    return Err(format!("Request failed with HTTP {}: {}", status, body));
+  }
+  if eval_result(body).is_none() && debug_policies(body).is_empty() {
+    return Err(format!(
+      "HTTP {} without policy details. Is __debug=policy enabled on the box?",
+      status
+    ));
+  }
+  Ok(parse_decision(body))
+}
+
+fn print_decision(request: &PolicyRequest, decision: &Decision) {
+  let verdict = match decision.allowed {
+    true => style("ALLOW").green().bold(),
+    false => style("DENY").red().bold(),
+  };
+  println!("{}  {}", verdict, request.label());
+  for (id, allowed) in decision.policies.iter() {
+    let mark = match allowed {
+      true => style("matched").green(),
+      false => style("no match").dim(),
+    };
+    println!("  AccessPolicy/{}  {}", id, mark);
+  }
+}
+
+fn parse_header(value: &str) -> Result<(String, Value), String> {
+  match value.split_once('=') {
+    Some((key, value)) if !key.is_empty() => Ok((key.to_string(), json!(value))),
+    _ => Err(format!(
+      "Expected header in form key=value, got '{}'",
+      value
+    )),
+  }
+}
+
+async fn check(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let via = Via::parse(sub_matches.get_one::<String>("via").unwrap());
+  let request = PolicyRequest {
+    method: sub_matches
+      .get_one::<String>("method")
+      .unwrap()
+      .to_uppercase(),
+    uri: sub_matches.get_one::<String>("uri").unwrap().to_string(),
+    user: sub_matches.get_one::<String>("user").cloned(),
+    client: sub_matches.get_one::<String>("client").cloned(),
+    headers: sub_matches
+      .get_many::<String>("header")
+      .unwrap_or_default()
+      .map(|it| parse_header(it))
+      .collect::<Result<Map<String, Value>, String>>()?,
+    body: match sub_matches.get_one::<String>("body") {
+      Some(path) => Some(read_value_file(path)?),
+      None => None,
+    },
+  };
+
+  let client = connect(instance).await?;
+  let decision = evaluate(&client, &request, via, sub_matches.get_flag("execute")).await?;
+  match sub_matches.get_one::<String>("format").unwrap().as_str() {
+    "json" => print_json(&json!({
+      "request": request.label(),
+      "allowed": decision.allowed,
+      "matched": decision.matched(),
+      "policies": decision
+        .policies
+        .iter()
+        .map(|(id, allowed)| json!({"id": id, "allowed": allowed}))
+        .collect::<Vec<_>>(),
+    })),
+    _ => print_decision(&request, &decision),
+  }
+  Ok(true)
+}
+
+/// Case of a suite: a request, `expect: allow|deny` and optionally the policy that must match
+#[derive(Clone, Debug, PartialEq)]
+struct TestCase {
+  name: String,
+  request: PolicyRequest,
+  allow: bool,
+  policy: Option<String>,
+}
+
+fn text(value: &Value, key: &str) -> Option<String> {
+  value.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+fn parse_case(value: &Value) -> Result<TestCase, String> {
+  let uri = text(value, "uri").ok_or("uri is missing")?;
+  let method = text(value, "method")
+    .unwrap_or_else(|| "GET".to_string())
+    .to_uppercase();
+  let allow = match text(value, "expect").as_deref() {
+    Some("allow") => true,
+    Some("deny") => false,
+    _ => return Err("expect must be allow or deny".to_string()),
+  };
+  let headers = match value.get("headers") {
+    Some(Value::Object(map)) => map.clone(),
+    Some(..) => return Err("headers must be a map".to_string()),
+    None => Map::new(),
+  };
+  Ok(TestCase {
+    name: text(value, "name").unwrap_or_else(|| format!("{} {}", method, uri)),
+    request: PolicyRequest {
+      method,
+      uri,
+      user: text(value, "user"),
+      client: text(value, "client"),
+      headers,
+      body: value.get("body").cloned(),
+    },
+    allow,
+    policy: text(value, "policy"),
+  })
+}
+
+/// Cases of a suite file: a list, or a map with `tests`
+fn parse_suite(value: &Value) -> Result<Vec<TestCase>, String> {
+  let cases = match value {
+    Value::Array(items) => items,
+    other => match other.get("tests").and_then(Value::as_array) {
+      Some(items) => items,
+      None => return Err("expected a list of cases or a map with tests".to_string()),
+    },
+  };
+  cases
+    .iter()
+    .enumerate()
+    .map(|(index, case)| parse_case(case).map_err(|err| format!("case #{}: {}", index, err)))
+    .collect()
+}
+
+/// Why a case failed, if it did
+fn verify(case: &TestCase, decision: &Decision) -> Option<String> {
+  let verdict = |allowed: bool| match allowed {
+    true => "allow",
+    false => "deny",
+  };
+  if case.allow != decision.allowed {
+    return Some(format!(
+      "expected {}, got {}",
+      verdict(case.allow),
+      verdict(decision.allowed)
+    ));
+  }
+  match case.policy.as_ref() {
+    Some(policy) if !decision.matched().contains(&policy.as_str()) => Some(format!(
+      "expected AccessPolicy/{} to match, matched: {}",
+      policy,
+      match decision.matched().is_empty() {
+        true => "none".to_string(),
+        false => decision.matched().join(", "),
+      }
+    )),
+    _ => None,
+  }
+}
+
+async fn test(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let via = Via::parse(sub_matches.get_one::<String>("via").unwrap());
+  let execute = sub_matches.get_flag("execute");
+
+  let mut suites = vec![];
+  for path in sub_matches.get_many::<String>("files").unwrap() {
+    for file in collect_files(Path::new(path), &["json", "yaml", "yml"])? {
+      let file = file.display().to_string();
+      let cases =
+        parse_suite(&read_value_file(&file)?).map_err(|err| format!("{}: {}", file, err))?;
+      suites.push((file, cases));
+    }
+  }
+
+  let client = connect(instance).await?;
+  let (mut passed, mut failed) = (0, 0);
+  for (file, cases) in suites.iter() {
+    println!("{}", style(file).bold());
+    for case in cases {
+      let failure = match evaluate(&client, &case.request, via, execute).await {
+        Ok(decision) => verify(case, &decision),
+        Err(err) => Some(err),
+      };
+      match failure {
+        None => {
+          passed += 1;
+          println!("  {}  {}", style("PASS").green().bold(), case.name);
+        },
+        Some(reason) => {
+          failed += 1;
+          println!("  {}  {}", style("FAIL").red().bold(), case.name);
+          println!("        {}", style(case.request.label()).dim());
+          println!("        {}", reason);
+        },
+      }
+    }
+  }
+
+  println!(
+    "{} {} passed, {} failed",
+    style("Policy tests:").bold(),
+    passed,
+    failed
+  );
+  Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_suite() {
+    let cases = parse_suite(&json!({"tests": [
+      {"uri": "/Patient?name=a", "user": "u1", "expect": "allow", "policy": "read"},
+      {"name": "no delete", "method": "delete", "uri": "/Patient/1", "expect": "deny"}
+    ]}))
+    .unwrap();
+    assert_eq!(cases[0].name, "GET /Patient?name=a");
+    assert_eq!(cases[1].request.method, "DELETE");
+    assert!(!cases[1].allow);
+
+    let request = cases[0].request.to_debug_request();
+    assert_eq!(request["uri"], "/Patient");
+    assert_eq!(request["query-string"], "name=a");
+    assert_eq!(request["user"]["id"], "u1");
+
+    assert!(parse_suite(&json!([{"uri": "/Patient", "expect": "maybe"}])).is_err());
+  }
+
+  #[test]
+  fn test_verify() {
+    let decision = parse_decision(&json!({
+      "policies": [{"id": "read", "eval-result": false}, {"id": "admin", "eval-result": true}]
+    }));
+    assert!(decision.allowed);
+    let case =
+      parse_case(&json!({"uri": "/Patient", "expect": "allow", "policy": "read"})).unwrap();
+    assert_eq!(
+      verify(&case, &decision),
+      Some("expected AccessPolicy/read to match, matched: admin".to_string())
+    );
+    let case = parse_case(&json!({"uri": "/Patient", "expect": "deny"})).unwrap();
+    assert_eq!(
+      verify(&case, &decision),
+      Some("expected deny, got allow".to_string())
+    );
+  }
+
+  #[test]
+  fn test_debug_decision() {
+    let denied = json!({"policies": {"read": {"eval-result": false}}});
+    assert!(!debug_decision(403, &denied).unwrap().allowed);
+    let allowed = json!({"policies": [{"id": "read", "eval-result": true}]});
+    assert!(debug_decision(200, &allowed).unwrap().allowed);
+    // Not found or a server error is not an allow
+    assert!(debug_decision(404, &denied).is_err());
+    assert!(debug_decision(500, &json!({})).is_err());
+    assert!(debug_decision(200, &json!({"resourceType": "Bundle"})).is_err());
+  }
+}
@@ -0,0 +1,188 @@
+use crate::aidbox::helpers::{connect, print_response, read_value_file};
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use log::error;
+use serde_json::Value;
+use tool_aidbox::rest::{parse_reference, ApiFormat, PatchKind};
+
+fn reference_arg() -> Arg {
+  Arg::new("reference")
+    .required(true)
+    .help("Resource reference. Example: Patient/123")
+}
+
+fn file_arg() -> Arg {
+  Arg::new("file")
+    .short('f')
+    .long("file")
+    .required(true)
+    .value_hint(ValueHint::FilePath)
+    .help("JSON or YAML file. Use - for stdin")
+}
+
+fn if_match_arg() -> Arg {
+  Arg::new("if-match")
+    .long("if-match")
+    .help("Expected version id. Request fails if the resource was changed")
+}
+
+pub fn commands() -> Command {
+  Command::new("resource")
+    .about("Read and modify resources")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .args(vec![Arg::new("fhir")
+      .long("fhir")
+      .global(true)
+      .action(SetTrue)
+      .help("Use FHIR format via /fhir instead of Aidbox format")])
+    .subcommand(
+      Command::new("get")
+        .about("Read resource")
+        .args(vec![reference_arg()]),
+    )
+    .subcommand(
+      Command::new("create")
+        .about("Create resource. Type is taken from resourceType unless provided")
+        .args(vec![
+          file_arg(),
+          Arg::new("type").help("Resource type. Example: Patient"),
+        ]),
+    )
+    .subcommand(
+      Command::new("update")
+        .about("Create or replace resource with PUT")
+        .args(vec![reference_arg(), file_arg(), if_match_arg()]),
+    )
+    .subcommand(
+      Command::new("patch")
+        .about("Patch resource. Merge patch by default")
+        .args(vec![
+          reference_arg(),
+          file_arg(),
+          if_match_arg(),
+          Arg::new("json-patch")
+            .long("json-patch")
+            .action(SetTrue)
+            .help("File is a JSON Patch (RFC 6902) operation list"),
+        ]),
+    )
+    .subcommand(
+      Command::new("delete")
+        .about("Delete resource")
+        .args(vec![reference_arg(), if_match_arg()]),
+    )
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let format = match sub_matches.get_flag("fhir") {
+    true => ApiFormat::Fhir,
+    false => ApiFormat::Aidbox,
+  };
+
+  let client = match connect(instance).await {
+    Ok(it) => it,
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  };
+
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("get", sub_matches) => match reference(sub_matches) {
+      Ok((resource_type, id)) => client.read_resource(format, &resource_type, &id).await,
+      Err(err) => Err(err),
+    },
+    ("create", sub_matches) => match file(sub_matches) {
+      Ok(resource) => match sub_matches
+        .get_one::<String>("type")
+        .map(String::to_string)
+        .or_else(|| {
+          resource
+            .get("resourceType")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+        }) {
+        Some(resource_type) => {
+          client
+            .create_resource(format, &resource_type, &resource)
+            .await
+        },
+        None => Err("Resource type is missing. Provide it or set resourceType".to_string()),
+      },
+      Err(err) => Err(err),
+    },
+    ("update", sub_matches) => match (reference(sub_matches), file(sub_matches)) {
+      (Ok((resource_type, id)), Ok(resource)) => {
+        client
+          .update_resource(
+            format,
+            &resource_type,
+            &id,
+            &resource,
+            sub_matches
+              .get_one::<String>("if-match")
+              .map(String::as_str),
+          )
+          .await
+      },
+      (Err(err), _) | (_, Err(err)) => Err(err),
+    },
+    ("patch", sub_matches) => match (reference(sub_matches), file(sub_matches)) {
+      (Ok((resource_type, id)), Ok(patch)) => {
+        let kind = match sub_matches.get_flag("json-patch") {
+          true => PatchKind::JsonPatch,
+          false => PatchKind::MergePatch,
+        };
+        client
+          .patch_resource(
+            format,
+            &resource_type,
+            &id,
+            &patch,
+            kind,
+            sub_matches
+              .get_one::<String>("if-match")
+              .map(String::as_str),
+          )
+          .await
+      },
+      (Err(err), _) | (_, Err(err)) => Err(err),
+    },
+    ("delete", sub_matches) => match reference(sub_matches) {
+      Ok((resource_type, id)) => {
+        client
+          .delete_resource(
+            format,
+            &resource_type,
+            &id,
+            sub_matches
+              .get_one::<String>("if-match")
+              .map(String::as_str),
+          )
+          .await
+      },
+      Err(err) => Err(err),
+    },
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  match result {
+    Ok(response) => print_response(response),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+fn reference(sub_matches: &ArgMatches) -> Result<(String, String), String> {
+  parse_reference(sub_matches.get_one::<String>("reference").unwrap())
+}
+
+fn file(sub_matches: &ArgMatches) -> Result<Value, String> {
+  read_value_file(sub_matches.get_one::<String>("file").unwrap())
+}
@@ -0,0 +1,190 @@
+use crate::aidbox::helpers::{connect, format_outcome, print_json};
+use clap::{Arg, ArgMatches, Command};
+use console::style;
+use dialoguer::theme::ColorfulTheme;
+use dialoguer::Input;
+use log::error;
+use serde_json::Value;
+use tool_aidbox::rest::RestResponse;
+use tool_aidbox::rpc::RpcParams;
+use tool_generator::cache::Cache;
+
+pub fn commands() -> Command {
+  Command::new("rpc")
+    .about("Call an RPC method. Without a method prompts for one, Tab completes cached symbols")
+    .args(vec![
+      Arg::new("method").help("RPC method. Example: aidbox.zen/namespaces"),
+      Arg::new("params")
+        .short('p')
+        .long("params")
+        .default_value("{}")
+        .help("EDN or JSON params. Use @<file> to read them from a file. Example: {:ns aidbox}"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  if let Err(err) = rpc(sub_matches).await {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+/// Symbols cached by `generate types`
+fn cached_methods(instance: &str) -> Vec<String> {
+  match Cache::default(instance) {
+    Ok(cache) => std::fs::read_to_string(cache.cache_path.join("symbols.json"))
+      .ok()
+      .and_then(|json| serde_json::from_str::<Vec<String>>(&json).ok())
+      .unwrap_or_default(),
+    Err(..) => vec![],
+  }
+}
+
+struct MethodCompletion {
+  methods: Vec<String>,
+}
+
+impl dialoguer::Completion for MethodCompletion {
+  /// Longest common prefix of the matches, or the first match once it can't grow
+  fn get(&self, input: &str) -> Option<String> {
+    let matches: Vec<&String> = self
+      .methods
+      .iter()
+      .filter(|it| it.starts_with(input))
+      .collect();
+    let first = matches.first()?;
+    let prefix = matches
+      .iter()
+      .skip(1)
+      .fold(first.to_string(), |prefix, it| {
+        prefix
+          .chars()
+          .zip(it.chars())
+          .take_while(|(a, b)| a == b)
+          .map(|(a, _)| a)
+          .collect()
+      });
+    match prefix.len() > input.len() {
+      true => Some(prefix),
+      false => Some(first.to_string()),
+    }
+  }
+}
+
+fn suggestions<'a>(methods: &'a [String], method: &str) -> Vec<&'a String> {
+  let name = method.rsplit('/').next().unwrap_or(method);
+  methods
+    .iter()
+    .filter(|it| it.ends_with(&format!("/{}", name)) || it.starts_with(method))
+    .take(5)
+    .collect()
+}
+
+fn read_params(source: &str) -> Result<RpcParams, String> {
+  match source.strip_prefix('@') {
+    Some(path) => match std::fs::read_to_string(path) {
+      Ok(it) => Ok(RpcParams::parse(&it)),
+      Err(err) => Err(format!("Cannot read {}: {}", path, err)),
+    },
+    None => Ok(RpcParams::parse(source)),
+  }
+}
+
+/// Print `result`, or the error and exit with a non-zero code
+fn print_rpc_response(response: RestResponse) {
+  if let Some(result) = response.body.get("result") {
+    if response.is_success() {
+      print_json(result);
+      return;
+    }
+  }
+
+  let error = response.body.get("error").unwrap_or(&response.body);
+  eprintln!(
+    "{} HTTP {}",
+    style("RPC error").red().bold(),
+    style(response.status).red().bold()
+  );
+  match error.get("resourceType").and_then(Value::as_str) {
+    Some("OperationOutcome") => format_outcome(error)
+      .iter()
+      .for_each(|line| eprintln!("{}", line)),
+    _ => match error.get("message").and_then(Value::as_str) {
+      Some(message) => eprintln!("{}", message),
+      None => eprintln!(
+        "{}",
+        serde_json::to_string_pretty(error).unwrap_or_default()
+      ),
+    },
+  }
+  std::process::exit(1);
+}
+
+async fn rpc(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let params = read_params(sub_matches.get_one::<String>("params").unwrap())?;
+  let methods = cached_methods(instance);
+
+  let method = match sub_matches.get_one::<String>("method") {
+    Some(it) => it.to_string(),
+    None => {
+      if methods.is_empty() {
+        log::warn!("No cached symbols for completion. Run `generate types` once to cache them");
+      }
+      let completion = MethodCompletion {
+        methods: methods.clone(),
+      };
+      let theme = ColorfulTheme::default();
+      let input = Input::<String>::with_theme(&theme)
+        .with_prompt("Method")
+        .completion_with(&completion)
+        .interact_text();
+      match input {
+        Ok(it) => it,
+        Err(err) => return Err(err.to_string()),
+      }
+    },
+  };
+
+  if !methods.is_empty() && !methods.contains(&method) {
+    let similar = suggestions(&methods, &method);
+    if !similar.is_empty() {
+      log::warn!(
+        "{} is not in cached symbols. Similar: {}",
+        method,
+        similar
+          .iter()
+          .map(|it| it.as_str())
+          .collect::<Vec<_>>()
+          .join(", ")
+      );
+    }
+  }
+
+  let client = connect(instance).await?;
+  print_rpc_response(client.rpc(&method, params).await?);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use dialoguer::Completion;
+
+  #[test]
+  fn test_method_completion() {
+    let completion = MethodCompletion {
+      methods: vec![
+        "aidbox.zen/namespaces".to_string(),
+        "aidbox.zen/symbol".to_string(),
+        "aidbox.zen/symbols".to_string(),
+      ],
+    };
+    assert_eq!(completion.get("aidbox.z"), Some("aidbox.zen/".to_string()));
+    assert_eq!(
+      completion.get("aidbox.zen/sym"),
+      Some("aidbox.zen/symbol".to_string())
+    );
+    assert_eq!(completion.get("sql"), None);
+  }
+}
@@ -0,0 +1,186 @@
+use crate::aidbox::helpers::{connect, print_response};
+use clap::ArgAction::SetTrue;
+use clap::{value_parser, Arg, ArgMatches, Command, ValueHint};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::error;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use tool_aidbox::rest::{bundle_resources, ApiFormat};
+
+pub fn commands() -> Command {
+  Command::new("search")
+    .about("Search resources and stream them as NDJSON, following all pages")
+    .args(vec![
+      Arg::new("type")
+        .required(true)
+        .help("Resource type. Example: Patient"),
+      Arg::new("params")
+        .num_args(0..)
+        .help("Search parameters as key=value. Example: name=john birthdate=gt2000"),
+      Arg::new("count")
+        .long("count")
+        .value_parser(value_parser!(usize))
+        .default_value("100")
+        .help("Page size (_count)"),
+      Arg::new("elements")
+        .long("elements")
+        .help("Only return listed elements (_elements). Example: id,name"),
+      Arg::new("max")
+        .long("max")
+        .value_parser(value_parser!(usize))
+        .help("Stop after this many resources"),
+      Arg::new("output")
+        .short('o')
+        .long("output")
+        .value_hint(ValueHint::FilePath)
+        .help("Output file. Stdout by default"),
+      Arg::new("fhir")
+        .long("fhir")
+        .action(SetTrue)
+        .help("Use FHIR format via /fhir instead of Aidbox format"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  if let Err(err) = search(sub_matches).await {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+/// `name=john` -> (`name`, `john`)
+fn parse_params(params: &[String]) -> Result<Vec<(String, String)>, String> {
+  params
+    .iter()
+    .map(|param| match param.split_once('=') {
+      Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+      _ => Err(format!(
+        "Expected search parameter in form key=value, got '{}'",
+        param
+      )),
+    })
+    .collect()
+}
+
+async fn search(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let resource_type = sub_matches.get_one::<String>("type").unwrap();
+  let format = match sub_matches.get_flag("fhir") {
+    true => ApiFormat::Fhir,
+    false => ApiFormat::Aidbox,
+  };
+  let max = sub_matches.get_one::<usize>("max").copied();
+
+  let mut params = parse_params(
+    &sub_matches
+      .get_many::<String>("params")
+      .unwrap_or_default()
+      .cloned()
+      .collect::<Vec<_>>(),
+  )?;
+  let count = match params.iter().find(|(key, _)| key == "_count") {
+    Some((_, value)) => value
+      .parse::<usize>()
+      .map_err(|_| format!("Invalid _count '{}'", value))?,
+    None => {
+      let count = *sub_matches.get_one::<usize>("count").unwrap();
+      params.push(("_count".to_string(), count.to_string()));
+      count
+    },
+  };
+  if let Some(elements) = sub_matches.get_one::<String>("elements") {
+    params.push(("_elements".to_string(), elements.to_string()));
+  }
+
+  let mut output: Box<dyn Write> = match sub_matches.get_one::<String>("output") {
+    Some(path) => match File::create(path) {
+      Ok(file) => Box::new(BufWriter::new(file)),
+      Err(err) => return Err(format!("Cannot create {}: {}", path, err)),
+    },
+    None => Box::new(BufWriter::new(std::io::stdout().lock())),
+  };
+
+  let client = connect(instance).await?;
+
+  let pb = ProgressBar::new_spinner();
+  pb.set_style(ProgressStyle::with_template("{spinner:.cyan} {pos} resources {msg}").unwrap());
+
+  let mut page = 0usize;
+  let mut written = 0usize;
+  let mut write_error: Option<std::io::Error> = None;
+  let failed = client
+    .search_pages(format, resource_type, &params, count, |body| {
+      page += 1;
+      if page == 1 {
+        if let Some(total) = body.get("total").and_then(Value::as_u64) {
+          let total = max.map_or(total, |max| total.min(max as u64));
+          pb.set_length(total);
+          pb.set_style(
+            ProgressStyle::with_template("{spinner:.cyan} [{bar:50.cyan/white}] {pos}/{len} {msg}")
+              .unwrap()
+              .progress_chars("=>-"),
+          );
+        }
+      }
+
+      let resources = bundle_resources(body);
+      if resources.is_empty() {
+        return false;
+      }
+      for resource in resources {
+        if max.is_some_and(|max| written >= max) {
+          return false;
+        }
+        if let Err(err) = writeln!(output, "{}", resource) {
+          write_error = Some(err);
+          return false;
+        }
+        written += 1;
+        pb.inc(1);
+      }
+      pb.set_message(format!("page {}", page));
+      max.is_none_or(|max| written < max)
+    })
+    .await?;
+
+  if let Some(response) = failed {
+    pb.abandon();
+    drop(output);
+    print_response(response);
+    return Ok(());
+  }
+  if let Some(err) = write_error {
+    pb.abandon();
+    return match err.kind() {
+      // Reader is gone, e.g. `| head`
+      ErrorKind::BrokenPipe => Ok(()),
+      _ => Err(format!("Cannot write output: {}", err)),
+    };
+  }
+
+  if let Err(err) = output.flush() {
+    if err.kind() != ErrorKind::BrokenPipe {
+      return Err(format!("Cannot write output: {}", err));
+    }
+  }
+
+  pb.finish_with_message(format!(
+    "written in {:?}s",
+    (pb.elapsed().as_secs_f64() * 100f64).floor() / 100f64
+  ));
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_params() {
+    let params = parse_params(&["name=john".to_string(), "_sort=-birthdate".to_string()]).unwrap();
+    assert_eq!(params[0], ("name".to_string(), "john".to_string()));
+    assert_eq!(params[1], ("_sort".to_string(), "-birthdate".to_string()));
+    assert!(parse_params(&["name".to_string()]).is_err());
+  }
+}
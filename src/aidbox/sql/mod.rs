@@ -0,0 +1,311 @@
+use crate::aidbox::helpers::{cell, connect, csv_line, format_outcome, print_json, render_table};
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use log::error;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::Value;
+use tool_aidbox::BoxClient;
+use tool_config::get_config_or_error;
+
+/// Longest cell in table output. JSON and CSV are never cut
+const MAX_CELL_WIDTH: usize = 80;
+
+pub fn commands() -> Command {
+  Command::new("sql")
+    .about("Run SQL with $psql. Starts a console without a query")
+    .args(vec![
+      Arg::new("query").help("SQL query. Example: \"select count(*) from patient\""),
+      Arg::new("file")
+        .short('f')
+        .long("file")
+        .conflicts_with("query")
+        .value_hint(ValueHint::FilePath)
+        .help("Run SQL script from file"),
+      Arg::new("format")
+        .long("format")
+        .value_parser(["table", "json", "csv"])
+        .default_value("table")
+        .help("Result rows format"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  if let Err(err) = sql(sub_matches).await {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OutputFormat {
+  Table,
+  Json,
+  Csv,
+}
+
+impl OutputFormat {
+  fn parse(value: &str) -> Option<OutputFormat> {
+    match value {
+      "table" => Some(OutputFormat::Table),
+      "json" => Some(OutputFormat::Json),
+      "csv" => Some(OutputFormat::Csv),
+      _ => None,
+    }
+  }
+}
+
+/// Column names in order of first appearance
+fn columns(rows: &[Value]) -> Vec<String> {
+  let mut result: Vec<String> = vec![];
+  for row in rows {
+    if let Some(object) = row.as_object() {
+      for key in object.keys() {
+        if !result.contains(key) {
+          result.push(key.to_string());
+        }
+      }
+    }
+  }
+  result
+}
+
+fn truncate(value: String) -> String {
+  let value = value.replace('\n', " ");
+  match value.chars().count() > MAX_CELL_WIDTH {
+    true => format!(
+      "{}…",
+      value.chars().take(MAX_CELL_WIDTH - 1).collect::<String>()
+    ),
+    false => value,
+  }
+}
+
+/// `$psql` puts the message into `error`, either a string or an object with `message`
+fn statement_error(statement: &Value) -> Option<String> {
+  let error =
+    statement
+      .get("error")
+      .or_else(|| match statement.get("status").and_then(Value::as_str) {
+        Some("error") => statement.get("result"),
+        _ => None,
+      })?;
+  Some(match error {
+    Value::String(text) => text.to_string(),
+    other => other
+      .get("message")
+      .and_then(Value::as_str)
+      .map(str::to_string)
+      .unwrap_or_else(|| other.to_string()),
+  })
+}
+
+/// Returns `false` if the query failed
+fn print_result(body: &Value, format: OutputFormat) -> bool {
+  let statements = match body {
+    Value::Array(items) => items.clone(),
+    other => vec![other.clone()],
+  };
+  let mut ok = true;
+
+  for statement in statements.iter() {
+    if let Some(message) = statement_error(statement) {
+      ok = false;
+      eprintln!("{} {}", style("ERROR").red().bold(), message);
+      continue;
+    }
+
+    let rows = match statement.get("result") {
+      Some(Value::Array(rows)) => rows.clone(),
+      Some(other) => {
+        print_json(other);
+        continue;
+      },
+      None => {
+        print_json(statement);
+        continue;
+      },
+    };
+
+    match format {
+      OutputFormat::Json => print_json(&Value::Array(rows)),
+      OutputFormat::Csv => {
+        let headers = columns(&rows);
+        println!("{}", csv_line(&headers));
+        for row in rows.iter() {
+          let cells: Vec<String> = headers
+            .iter()
+            .map(|key| cell(row.get(key).unwrap_or(&Value::Null)))
+            .collect();
+          println!("{}", csv_line(&cells));
+        }
+      },
+      OutputFormat::Table => {
+        let headers = columns(&rows);
+        if !headers.is_empty() {
+          let cells: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| {
+              headers
+                .iter()
+                .map(|key| truncate(cell(row.get(key).unwrap_or(&Value::Null))))
+                .collect()
+            })
+            .collect();
+          for line in render_table(&headers, &cells) {
+            println!("{}", line);
+          }
+        }
+        let duration = statement
+          .get("duration")
+          .and_then(Value::as_u64)
+          .map(|it| format!(", {} ms", it))
+          .unwrap_or_default();
+        println!(
+          "{}",
+          style(format!("({} rows{})", rows.len(), duration)).dim()
+        );
+      },
+    }
+  }
+  ok
+}
+
+async fn run(client: &BoxClient, query: &str, format: OutputFormat) -> Result<bool, String> {
+  let response = client.psql(query).await?;
+  if !response.is_success() && response.is_operation_outcome() {
+    for line in format_outcome(&response.body) {
+      eprintln!("{}", line);
+    }
+    return Ok(false);
+  }
+  Ok(print_result(&response.body, format) && response.is_success())
+}
+
+async fn sql(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let format = OutputFormat::parse(sub_matches.get_one::<String>("format").unwrap())
+    .unwrap_or(OutputFormat::Table);
+
+  let query = match sub_matches.get_one::<String>("file") {
+    Some(path) => match std::fs::read_to_string(path) {
+      Ok(it) => Some(it),
+      Err(err) => return Err(format!("Cannot read {}: {}", path, err)),
+    },
+    None => sub_matches.get_one::<String>("query").cloned(),
+  };
+
+  let client = connect(instance).await?;
+
+  match query {
+    Some(query) => match run(&client, &query, format).await? {
+      true => Ok(()),
+      false => std::process::exit(1),
+    },
+    None => console_loop(&client, instance, format).await,
+  }
+}
+
+const HELP: &str = "End statements with ; to run them. Commands:
+  \\f table|json|csv  switch output format
+  \\q                 quit
+  \\?                 this help";
+
+/// Interactive console. Statements may span lines and run on `;`
+async fn console_loop(
+  client: &BoxClient,
+  instance: &str,
+  format: OutputFormat,
+) -> Result<(), String> {
+  let mut editor = match DefaultEditor::new() {
+    Ok(it) => it,
+    Err(err) => return Err(err.to_string()),
+  };
+  let history = get_config_or_error(instance)
+    .ok()
+    .map(|(config, _)| config.config_dir.join("sql_history"));
+  if let Some(path) = history.as_ref() {
+    let _ = editor.load_history(path);
+  }
+
+  println!(
+    "Connected to {}. {}",
+    style(client.url()).cyan().bold(),
+    style("\\? for help").dim()
+  );
+
+  let mut format = format;
+  let mut buffer = String::new();
+  loop {
+    let prompt = match buffer.is_empty() {
+      true => format!("{}> ", instance),
+      false => format!("{}-> ", " ".repeat(instance.len().saturating_sub(1))),
+    };
+    let line = match editor.readline(&prompt) {
+      Ok(it) => it,
+      // Ctrl-C drops the statement being typed
+      Err(ReadlineError::Interrupted) => {
+        buffer.clear();
+        continue;
+      },
+      Err(ReadlineError::Eof) => break,
+      Err(err) => return Err(err.to_string()),
+    };
+    let trimmed = line.trim();
+
+    if buffer.is_empty() && trimmed.starts_with('\\') {
+      let _ = editor.add_history_entry(trimmed);
+      let mut parts = trimmed.split_whitespace();
+      match (parts.next(), parts.next()) {
+        (Some("\\q"), _) => break,
+        (Some("\\f"), Some(name)) => match OutputFormat::parse(name) {
+          Some(it) => format = it,
+          None => eprintln!("Unknown format {}. Use table, json or csv", name),
+        },
+        _ => println!("{}", HELP),
+      }
+      continue;
+    }
+
+    if trimmed.is_empty() && buffer.is_empty() {
+      continue;
+    }
+    if !buffer.is_empty() {
+      buffer.push('\n');
+    }
+    buffer.push_str(&line);
+
+    if trimmed.ends_with(';') {
+      let statement = std::mem::take(&mut buffer);
+      let _ = editor.add_history_entry(statement.as_str());
+      if let Err(err) = run(client, &statement, format).await {
+        eprintln!("{} {}", style("ERROR").red().bold(), err);
+      }
+    }
+  }
+
+  if let Some(path) = history.as_ref() {
+    let _ = editor.save_history(path);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_statement_error() {
+    assert_eq!(
+      statement_error(
+        &json!({"status": "error", "result": {"message": "relation does not exist"}})
+      ),
+      Some("relation does not exist".to_string())
+    );
+    assert_eq!(
+      statement_error(&json!({"status": "success", "result": []})),
+      None
+    );
+  }
+}
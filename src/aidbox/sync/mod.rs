@@ -0,0 +1,328 @@
+use crate::aidbox::helpers::{confirm, connect, content_hash, format_outcome, resource_content};
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command};
+use console::style;
+use log::error;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tool_aidbox::rest::{ApiFormat, RestResponse};
+
+/// Configuration resources synced when `--types` is not given
+pub const DEFAULT_TYPES: &str = "AccessPolicy,Client,SearchParameter,App,Mapping,SubscriptionTopic";
+
+/// Page size used to read every resource of a type
+const PAGE_SIZE: usize = 1000;
+
+pub fn commands() -> Command {
+  Command::new("sync")
+    .about("Copy configuration resources from one instance to another by id and content hash")
+    .args(vec![
+      Arg::new("from")
+        .long("from")
+        .required(true)
+        .help("Source instance. Example: dev"),
+      Arg::new("to")
+        .long("to")
+        .required(true)
+        .help("Target instance. Example: stage"),
+      Arg::new("types")
+        .long("types")
+        .value_delimiter(',')
+        .default_value(DEFAULT_TYPES)
+        .help("Comma separated resource types"),
+      Arg::new("prune")
+        .long("prune")
+        .action(SetTrue)
+        .help("Delete target resources missing in the source"),
+      Arg::new("dry-run")
+        .long("dry-run")
+        .action(SetTrue)
+        .help("Print the plan without applying it"),
+      Arg::new("yes")
+        .short('y')
+        .long("yes")
+        .action(SetTrue)
+        .help("Apply without confirmation"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  match sync(sub_matches).await {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Action {
+  Create,
+  Update,
+  Unchanged,
+  Delete,
+  /// Only in the target, kept without `--prune`
+  Extra,
+}
+
+impl Action {
+  fn label(&self) -> String {
+    match self {
+      Action::Create => style("+ create").green().to_string(),
+      Action::Update => style("~ update").yellow().to_string(),
+      Action::Unchanged => style("= same").dim().to_string(),
+      Action::Delete => style("- delete").red().to_string(),
+      Action::Extra => style("? extra").dim().to_string(),
+    }
+  }
+}
+
+#[derive(Debug)]
+struct Change {
+  action: Action,
+  resource_type: String,
+  id: String,
+  /// Source resource without `meta` for creates and updates
+  resource: Option<Value>,
+}
+
+fn by_id(resources: &[Value]) -> BTreeMap<String, &Value> {
+  resources
+    .iter()
+    .filter_map(|it| Some((it.get("id")?.as_str()?.to_string(), it)))
+    .collect()
+}
+
+/// Changes that make the target match the source, ordered by id
+fn plan(resource_type: &str, source: &[Value], target: &[Value], prune: bool) -> Vec<Change> {
+  let source = by_id(source);
+  let target = by_id(target);
+  let change = |action: Action, id: &str, resource: Option<Value>| Change {
+    action,
+    resource_type: resource_type.to_string(),
+    id: id.to_string(),
+    resource,
+  };
+
+  let mut changes: Vec<Change> = source
+    .iter()
+    .map(|(id, resource)| match target.get(id) {
+      None => change(Action::Create, id, Some(resource_content(resource))),
+      Some(existing) if content_hash(existing) == content_hash(resource) => {
+        change(Action::Unchanged, id, None)
+      },
+      Some(..) => change(Action::Update, id, Some(resource_content(resource))),
+    })
+    .collect();
+  changes.extend(
+    target
+      .keys()
+      .filter(|id| !source.contains_key(*id))
+      .map(|id| match prune {
+        true => change(Action::Delete, id, None),
+        false => change(Action::Extra, id, None),
+      }),
+  );
+  changes.sort_by(|a, b| a.id.cmp(&b.id));
+  changes
+}
+
+fn count(changes: &[Change], action: Action) -> usize {
+  changes.iter().filter(|it| it.action == action).count()
+}
+
+/// Returns `false` when some change failed
+async fn sync(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let from = sub_matches.get_one::<String>("from").unwrap();
+  let to = sub_matches.get_one::<String>("to").unwrap();
+  if from == to {
+    return Err("--from and --to must be different instances".to_string());
+  }
+  let types: Vec<&String> = sub_matches
+    .get_many::<String>("types")
+    .unwrap()
+    .filter(|it| !it.is_empty())
+    .collect();
+  let prune = sub_matches.get_flag("prune");
+
+  let source = connect(from).await?;
+  let target = connect(to).await?;
+
+  let mut changes = vec![];
+  for resource_type in types {
+    let source_resources = source
+      .search_all(ApiFormat::Aidbox, resource_type, PAGE_SIZE)
+      .await
+      .map_err(|err| format!("{}: {}", from, err))?;
+    let target_resources = target
+      .search_all(ApiFormat::Aidbox, resource_type, PAGE_SIZE)
+      .await
+      .map_err(|err| format!("{}: {}", to, err))?;
+    let without_id = source_resources
+      .iter()
+      .filter(|it| it.get("id").and_then(Value::as_str).is_none())
+      .count();
+    if without_id > 0 {
+      log::warn!(
+        "Skip {} {} without id in {}",
+        without_id,
+        resource_type,
+        from
+      );
+    }
+
+    let type_changes = plan(resource_type, &source_resources, &target_resources, prune);
+    println!(
+      "{} {}",
+      style(resource_type).bold(),
+      style(format!(
+        "{} in {}, {} in {}",
+        source_resources.len(),
+        from,
+        target_resources.len(),
+        to
+      ))
+      .dim()
+    );
+    for change in type_changes
+      .iter()
+      .filter(|it| it.action != Action::Unchanged)
+    {
+      println!(
+        "  {}  {}/{}",
+        change.action.label(),
+        change.resource_type,
+        change.id
+      );
+    }
+    changes.extend(type_changes);
+  }
+
+  let pending = changes
+    .iter()
+    .filter(|it| matches!(it.action, Action::Create | Action::Update | Action::Delete))
+    .count();
+  println!(
+    "{} {} to create, {} to update, {} to delete, {} unchanged",
+    style("Plan:").bold(),
+    count(&changes, Action::Create),
+    count(&changes, Action::Update),
+    count(&changes, Action::Delete),
+    count(&changes, Action::Unchanged)
+  );
+  let extra = count(&changes, Action::Extra);
+  if extra > 0 {
+    println!(
+      "{}",
+      style(format!(
+        "{} only in {}. Use --prune to delete them",
+        extra, to
+      ))
+      .dim()
+    );
+  }
+
+  if pending == 0 {
+    println!("Nothing to sync");
+    return Ok(true);
+  }
+  if sub_matches.get_flag("dry-run") {
+    return Ok(true);
+  }
+  if !sub_matches.get_flag("yes") && !confirm(&format!("Apply {} changes to {}?", pending, to))? {
+    return Ok(true);
+  }
+
+  let mut failed = 0;
+  for change in changes.iter() {
+    let response: RestResponse = match (change.action, change.resource.as_ref()) {
+      (Action::Create | Action::Update, Some(resource)) => {
+        target
+          .update_resource(
+            ApiFormat::Aidbox,
+            &change.resource_type,
+            &change.id,
+            resource,
+            None,
+          )
+          .await?
+      },
+      (Action::Delete, _) => {
+        target
+          .delete_resource(ApiFormat::Aidbox, &change.resource_type, &change.id, None)
+          .await?
+      },
+      _ => continue,
+    };
+
+    let status = match response.is_success() {
+      true => style(response.status.to_string()).green(),
+      false => {
+        failed += 1;
+        style(response.status.to_string()).red().bold()
+      },
+    };
+    println!(
+      "  {}  {}/{}  {}",
+      change.action.label(),
+      change.resource_type,
+      change.id,
+      status
+    );
+    if !response.is_success() {
+      match response.is_operation_outcome() {
+        true => format_outcome(&response.body)
+          .iter()
+          .for_each(|line| println!("      {}", line)),
+        false => println!("      {}", response.body),
+      }
+    }
+  }
+
+  println!(
+    "{} {} of {} changes applied to {}",
+    style("Sync").bold(),
+    pending - failed,
+    pending,
+    to
+  );
+  Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_plan() {
+    let source = vec![
+      json!({"resourceType": "Client", "id": "a", "secret": "1", "meta": {"versionId": "3"}}),
+      json!({"resourceType": "Client", "id": "b", "secret": "2"}),
+      json!({"resourceType": "Client", "id": "c"}),
+    ];
+    let target = vec![
+      json!({"resourceType": "Client", "id": "a", "secret": "1", "meta": {"versionId": "9"}}),
+      json!({"resourceType": "Client", "id": "b", "secret": "old"}),
+      json!({"resourceType": "Client", "id": "d"}),
+    ];
+    let actions = |prune| {
+      plan("Client", &source, &target, prune)
+        .iter()
+        .map(|it| (it.id.clone(), it.action))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      actions(false),
+      vec![
+        ("a".to_string(), Action::Unchanged),
+        ("b".to_string(), Action::Update),
+        ("c".to_string(), Action::Create),
+        ("d".to_string(), Action::Extra),
+      ]
+    );
+    assert_eq!(actions(true)[3].1, Action::Delete);
+  }
+}
@@ -0,0 +1,481 @@
+use crate::aidbox::helpers::{cell, connect, format_outcome, print_json, render_table};
+use clap::{value_parser, Arg, ArgMatches, Command};
+use console::style;
+use log::error;
+use reqwest::Method;
+use serde_json::{json, Map, Value};
+use tool_aidbox::rest::RestResponse;
+use tool_aidbox::BoxClient;
+
+fn common_args() -> Vec<Arg> {
+  vec![
+    Arg::new("format")
+      .long("format")
+      .value_parser(["table", "json"])
+      .default_value("table")
+      .help("Output format"),
+    Arg::new("source")
+      .long("source")
+      .value_parser(["auto", "fhir", "sql"])
+      .default_value("auto")
+      .help("auto tries the FHIR operation and falls back to the concept table via $psql"),
+  ]
+}
+
+fn valueset_arg() -> Arg {
+  Arg::new("valueset")
+    .required(true)
+    .help("ValueSet url or zen value set symbol. Example: http://hl7.org/fhir/ValueSet/administrative-gender")
+}
+
+pub fn commands() -> Command {
+  Command::new("terminology")
+    .about("Expand value sets, look up and validate codes")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .subcommand(
+      Command::new("expand")
+        .about("List codes of a value set ($expand)")
+        .args(vec![
+          valueset_arg(),
+          Arg::new("filter")
+            .long("filter")
+            .help("Only codes with code or display containing the text"),
+          Arg::new("count")
+            .long("count")
+            .value_parser(value_parser!(usize))
+            .default_value("1000")
+            .help("Maximum number of codes"),
+        ])
+        .args(common_args()),
+    )
+    .subcommand(
+      Command::new("lookup")
+        .about("Show concept details ($lookup)")
+        .args(vec![
+          Arg::new("system")
+            .required(true)
+            .help("Code system url. Example: http://loinc.org"),
+          Arg::new("code")
+            .required(true)
+            .help("Code. Example: 8867-4"),
+        ])
+        .args(common_args()),
+    )
+    .subcommand(
+      Command::new("validate-code")
+        .about("Check that a code is in a value set ($validate-code). Exits with 1 if not")
+        .args(vec![
+          valueset_arg(),
+          Arg::new("code").required(true).help("Code"),
+          Arg::new("system").long("system").help("Code system url"),
+        ])
+        .args(common_args()),
+    )
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("expand", sub_matches) => expand(sub_matches).await,
+    ("lookup", sub_matches) => lookup(sub_matches).await,
+    ("validate-code", sub_matches) => validate_code(sub_matches).await,
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  match result {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+/// SQL string literal
+fn quote(value: &str) -> String {
+  format!("'{}'", value.replace('\'', "''"))
+}
+
+/// `ilike` pattern matching `value` anywhere, with `\`, `%` and `_` taken literally.
+/// Use with `escape '\'`
+fn contains_pattern(value: &str) -> String {
+  let escaped = value
+    .replace('\\', "\\\\")
+    .replace('%', "\\%")
+    .replace('_', "\\_");
+  format!("%{}%", escaped)
+}
+
+/// jsonb literal of a JSON string, for `@>` against `concept.resource->'valueset'`
+fn jsonb_string(value: &str) -> String {
+  format!(
+    "{}::jsonb",
+    quote(&Value::String(value.to_string()).to_string())
+  )
+}
+
+/// Value set url from a url or a zen value set symbol
+async fn valueset_url(client: &BoxClient, valueset: &str) -> Result<String, String> {
+  if valueset.contains("://") || valueset.starts_with("urn:") || !valueset.contains('/') {
+    return Ok(valueset.to_string());
+  }
+  match client.get_symbol(valueset).await {
+    Ok(definition) => match definition.get("uri").and_then(Value::as_str) {
+      Some(uri) => Ok(uri.to_string()),
+      None => Err(format!("{} has no uri. Is it a value set?", valueset)),
+    },
+    Err(err) => Err(format!("Cannot read {}: {}", valueset, err)),
+  }
+}
+
+/// FHIR operation result, or `None` when the box doesn't support it and `$psql` should be used
+async fn fhir_operation(
+  client: &BoxClient,
+  source: &str,
+  path: &str,
+  params: &[(&str, String)],
+) -> Result<Option<RestResponse>, String> {
+  if source == "sql" {
+    return Ok(None);
+  }
+  let response = client
+    .send(client.request(Method::GET, path).await?.query(params))
+    .await?;
+  match response.status {
+    404 | 405 | 501 if source == "auto" => {
+      log::debug!(
+        "{} is not available ({}), using $psql",
+        path,
+        response.status
+      );
+      Ok(None)
+    },
+    _ if response.is_success() => Ok(Some(response)),
+    status => match response.is_operation_outcome() {
+      true => Err(format!(
+        "HTTP {}\n{}",
+        status,
+        format_outcome(&response.body).join("\n")
+      )),
+      false => Err(format!("HTTP {} {}", status, response.body)),
+    },
+  }
+}
+
+/// Rows of the first `$psql` statement
+async fn psql_rows(client: &BoxClient, query: &str) -> Result<Vec<Value>, String> {
+  let response = client.psql(query).await?;
+  let statement = match &response.body {
+    Value::Array(items) => items.first().cloned().unwrap_or(Value::Null),
+    other => other.clone(),
+  };
+  match statement.get("result") {
+    Some(Value::Array(rows)) if response.is_success() => Ok(rows.clone()),
+    _ => Err(format!("$psql failed: {}", statement)),
+  }
+}
+
+/// `Parameters.parameter` as name -> value. Parts (e.g. designation) become objects
+fn parameters(resource: &Value) -> Map<String, Value> {
+  let mut result = Map::new();
+  for parameter in resource
+    .get("parameter")
+    .and_then(Value::as_array)
+    .cloned()
+    .unwrap_or_default()
+  {
+    let name = match parameter.get("name").and_then(Value::as_str) {
+      Some(it) => it.to_string(),
+      None => continue,
+    };
+    let value = match parameter.get("part") {
+      Some(part) => Value::Object(parameters(&json!({ "parameter": part }))),
+      None => parameter
+        .as_object()
+        .and_then(|it| {
+          it.iter()
+            .find(|(key, _)| key.starts_with("value"))
+            .map(|(_, value)| value.clone())
+        })
+        .unwrap_or(Value::Null),
+    };
+    match result.get_mut(&name) {
+      Some(Value::Array(items)) => items.push(value),
+      Some(existing) => *existing = Value::Array(vec![existing.clone(), value]),
+      None => {
+        result.insert(name, value);
+      },
+    }
+  }
+  result
+}
+
+fn print_rows(rows: &[Value], columns: &[&str], format: &str) {
+  match format {
+    "json" => print_json(&Value::Array(rows.to_vec())),
+    _ => {
+      let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+          columns
+            .iter()
+            .map(|key| cell(row.get(*key).unwrap_or(&Value::Null)))
+            .collect()
+        })
+        .collect();
+      let headers: Vec<String> = columns.iter().map(|it| it.to_string()).collect();
+      for line in render_table(&headers, &cells) {
+        println!("{}", line);
+      }
+      println!("{}", style(format!("({} codes)", rows.len())).dim());
+    },
+  }
+}
+
+async fn expand(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let source = sub_matches.get_one::<String>("source").unwrap();
+  let format = sub_matches.get_one::<String>("format").unwrap();
+  let filter = sub_matches.get_one::<String>("filter");
+  let count = *sub_matches.get_one::<usize>("count").unwrap();
+
+  let client = connect(instance).await?;
+  let url = valueset_url(&client, sub_matches.get_one::<String>("valueset").unwrap()).await?;
+
+  let mut params = vec![("url", url.clone()), ("count", count.to_string())];
+  if let Some(filter) = filter {
+    params.push(("filter", filter.to_string()));
+  }
+
+  let rows: Vec<Value> = match fhir_operation(&client, source, "/fhir/ValueSet/$expand", &params)
+    .await?
+  {
+    Some(response) => response
+      .body
+      .get("expansion")
+      .and_then(|it| it.get("contains"))
+      .and_then(Value::as_array)
+      .map(|contains| {
+        contains
+          .iter()
+          .map(|it| {
+            json!({
+              "system": it.get("system"),
+              "code": it.get("code"),
+              "display": it.get("display"),
+            })
+          })
+          .collect()
+      })
+      .unwrap_or_default(),
+    None => {
+      let filter_sql = match filter {
+        Some(filter) => format!(
+          " and (resource->>'code' ilike {0} escape '\\' or resource->>'display' ilike {0} escape '\\')",
+          quote(&contains_pattern(filter))
+        ),
+        None => String::new(),
+      };
+      psql_rows(
+        &client,
+        &format!(
+          "select resource->>'system' as system, resource->>'code' as code, resource->>'display' as display from concept where resource->'valueset' @> {}{} order by 1, 2 limit {}",
+          jsonb_string(&url),
+          filter_sql,
+          count
+        ),
+      )
+      .await?
+    },
+  };
+
+  print_rows(&rows, &["system", "code", "display"], format);
+  Ok(true)
+}
+
+async fn lookup(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let source = sub_matches.get_one::<String>("source").unwrap();
+  let format = sub_matches.get_one::<String>("format").unwrap();
+  let system = sub_matches.get_one::<String>("system").unwrap();
+  let code = sub_matches.get_one::<String>("code").unwrap();
+
+  let client = connect(instance).await?;
+  let params = [("system", system.to_string()), ("code", code.to_string())];
+
+  let details: Map<String, Value> = match fhir_operation(
+    &client,
+    source,
+    "/fhir/CodeSystem/$lookup",
+    &params,
+  )
+  .await?
+  {
+    Some(response) => parameters(&response.body),
+    None => {
+      let rows = psql_rows(
+          &client,
+          &format!(
+            "select resource from concept where resource->>'system' = {} and resource->>'code' = {} limit 1",
+            quote(system),
+            quote(code)
+          ),
+        )
+        .await?;
+      match rows
+        .first()
+        .and_then(|it| it.get("resource"))
+        .and_then(Value::as_object)
+      {
+        Some(resource) => resource.clone(),
+        None => Map::new(),
+      }
+    },
+  };
+
+  if details.is_empty() {
+    eprintln!(
+      "{} {} not found in {}",
+      style("Not found").red().bold(),
+      code,
+      system
+    );
+    return Ok(false);
+  }
+
+  match format.as_str() {
+    "json" => print_json(&Value::Object(details)),
+    _ => {
+      let rows: Vec<Vec<String>> = details
+        .iter()
+        .map(|(key, value)| vec![key.to_string(), cell(value)])
+        .collect();
+      for line in render_table(&["name".to_string(), "value".to_string()], &rows) {
+        println!("{}", line);
+      }
+    },
+  }
+  Ok(true)
+}
+
+async fn validate_code(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let source = sub_matches.get_one::<String>("source").unwrap();
+  let format = sub_matches.get_one::<String>("format").unwrap();
+  let code = sub_matches.get_one::<String>("code").unwrap();
+  let system = sub_matches.get_one::<String>("system");
+
+  let client = connect(instance).await?;
+  let url = valueset_url(&client, sub_matches.get_one::<String>("valueset").unwrap()).await?;
+
+  let mut params = vec![("url", url.clone()), ("code", code.to_string())];
+  if let Some(system) = system {
+    params.push(("system", system.to_string()));
+  }
+
+  let result: Map<String, Value> = match fhir_operation(
+    &client,
+    source,
+    "/fhir/ValueSet/$validate-code",
+    &params,
+  )
+  .await?
+  {
+    Some(response) => parameters(&response.body),
+    None => {
+      let system_sql = match system {
+        Some(system) => format!(" and resource->>'system' = {}", quote(system)),
+        None => String::new(),
+      };
+      let rows = psql_rows(
+        &client,
+        &format!(
+          "select resource->>'system' as system, resource->>'display' as display from concept where resource->'valueset' @> {} and resource->>'code' = {}{} limit 1",
+          jsonb_string(&url),
+          quote(code),
+          system_sql
+        ),
+      )
+      .await?;
+      let mut result = Map::new();
+      match rows.first() {
+        Some(row) => {
+          result.insert("result".to_string(), Value::Bool(true));
+          result.insert(
+            "display".to_string(),
+            row.get("display").cloned().unwrap_or(Value::Null),
+          );
+          result.insert(
+            "system".to_string(),
+            row.get("system").cloned().unwrap_or(Value::Null),
+          );
+        },
+        None => {
+          result.insert("result".to_string(), Value::Bool(false));
+          result.insert(
+            "message".to_string(),
+            Value::String(format!("Code {} is not in {}", code, url)),
+          );
+        },
+      }
+      result
+    },
+  };
+
+  let valid = result
+    .get("result")
+    .and_then(Value::as_bool)
+    .unwrap_or(false);
+  match format.as_str() {
+    "json" => print_json(&Value::Object(result)),
+    _ => {
+      let label = match valid {
+        true => style("valid").green().bold(),
+        false => style("invalid").red().bold(),
+      };
+      let details: Vec<String> = ["display", "system", "message"]
+        .iter()
+        .filter_map(|key| result.get(*key).and_then(Value::as_str))
+        .map(str::to_string)
+        .collect();
+      println!("{} {} {}", label, code, style(details.join(" | ")).dim());
+    },
+  }
+  Ok(valid)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parameters() {
+    let result = parameters(&json!({
+      "resourceType": "Parameters",
+      "parameter": [
+        {"name": "display", "valueString": "Heart rate"},
+        {"name": "designation", "part": [{"name": "value", "valueString": "HR"}]},
+        {"name": "designation", "part": [{"name": "value", "valueString": "Pulse"}]}
+      ]
+    }));
+    assert_eq!(result["display"], json!("Heart rate"));
+    assert_eq!(
+      result["designation"],
+      json!([{"value": "HR"}, {"value": "Pulse"}])
+    );
+  }
+
+  #[test]
+  fn test_contains_pattern() {
+    assert_eq!(contains_pattern("heart"), "%heart%");
+    assert_eq!(contains_pattern(r"100%_a\b"), r"%100\%\_a\\b%");
+  }
+
+  #[test]
+  fn test_jsonb_string() {
+    assert_eq!(jsonb_string("http://x/it's"), "'\"http://x/it''s\"'::jsonb");
+  }
+}
@@ -0,0 +1,273 @@
+use crate::aidbox::helpers::{
+  cell, confirm, connect, generate_secret, print_json, print_response, render_table,
+};
+use crate::aidbox::matches::{prompt, secret_prompt};
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command};
+use console::style;
+use log::error;
+use serde_json::{json, Value};
+use tool_aidbox::rest::{ApiFormat, PatchKind};
+
+/// Page size used to read every user
+const PAGE_SIZE: usize = 1000;
+
+/// Length of generated passwords
+const PASSWORD_LENGTH: usize = 24;
+
+pub fn commands() -> Command {
+  Command::new("users")
+    .about("Manage User resources")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .subcommand(
+      Command::new("list").about("List users").arg(
+        Arg::new("format")
+          .long("format")
+          .value_parser(["text", "json"])
+          .default_value("text")
+          .help("Output format"),
+      ),
+    )
+    .subcommand(
+      Command::new("create")
+        .about("Create a user. Missing values are prompted for")
+        .args(vec![
+          Arg::new("id").help("User id. Generated by Aidbox when omitted"),
+          Arg::new("email").long("email").help("User email"),
+          Arg::new("password")
+            .long("password")
+            .help("User password. Generated when omitted without a terminal"),
+        ]),
+    )
+    .subcommand(
+      Command::new("rotate-secret")
+        .about("Set a new password. A generated one is printed once")
+        .args(vec![
+          Arg::new("id").required(true).help("User id"),
+          Arg::new("password")
+            .long("password")
+            .help("New password instead of a generated one"),
+        ]),
+    )
+    .subcommand(
+      Command::new("disable")
+        .about("Mark a user inactive")
+        .args(vec![
+          Arg::new("id").required(true).help("User id"),
+          Arg::new("yes")
+            .short('y')
+            .long("yes")
+            .action(SetTrue)
+            .help("Disable without confirmation"),
+        ]),
+    )
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("list", sub_matches) => list(sub_matches).await,
+    ("create", sub_matches) => create(sub_matches).await,
+    ("rotate-secret", sub_matches) => rotate_secret(sub_matches).await,
+    ("disable", sub_matches) => disable(sub_matches).await,
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  if let Err(err) = result {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+/// Table row: id, email, name, status and last update
+fn row(user: &Value) -> Vec<String> {
+  let name = user
+    .get("name")
+    .map(|name| {
+      ["givenName", "familyName"]
+        .iter()
+        .filter_map(|key| name.get(*key).and_then(Value::as_str))
+        .collect::<Vec<_>>()
+        .join(" ")
+    })
+    .unwrap_or_default();
+  let inactive = user.get("inactive").and_then(Value::as_bool) == Some(true);
+  vec![
+    cell(user.get("id").unwrap_or(&Value::Null)),
+    cell(user.get("email").unwrap_or(&Value::Null)),
+    name,
+    match inactive {
+      true => style("inactive").red().to_string(),
+      false => style("active").green().to_string(),
+    },
+    cell(user.pointer("/meta/lastUpdated").unwrap_or(&Value::Null)),
+  ]
+}
+
+async fn list(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let client = connect(instance).await?;
+  let users = client
+    .search_all(ApiFormat::Aidbox, "User", PAGE_SIZE)
+    .await?;
+
+  if sub_matches.get_one::<String>("format").unwrap() == "json" {
+    // Password hashes stay on the server
+    let users: Vec<Value> = users
+      .into_iter()
+      .map(|mut it| {
+        if let Some(map) = it.as_object_mut() {
+          map.remove("password");
+        }
+        it
+      })
+      .collect();
+    print_json(&Value::Array(users));
+    return Ok(());
+  }
+  let headers: Vec<String> = ["id", "email", "name", "status", "lastUpdated"]
+    .iter()
+    .map(|it| style(it).bold().to_string())
+    .collect();
+  let rows: Vec<Vec<String>> = users.iter().map(row).collect();
+  for line in render_table(&headers, &rows) {
+    println!("{}", line);
+  }
+  println!("{}", style(format!("{} users", users.len())).dim());
+  Ok(())
+}
+
+/// Password from the flag, the terminal, or a generated one which is printed
+fn password(given: Option<&String>) -> (String, bool) {
+  match given {
+    Some(password) => (password.to_string(), false),
+    None if console::user_attended() => (secret_prompt("Password", None), false),
+    None => (generate_secret(PASSWORD_LENGTH), true),
+  }
+}
+
+async fn create(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let email = match sub_matches.get_one::<String>("email") {
+    Some(email) => Some(email.to_string()),
+    None if console::user_attended() => Some(prompt::<String>("Email", None)),
+    None => None,
+  };
+  let (password, generated) = password(sub_matches.get_one::<String>("password"));
+
+  let mut user = json!({"resourceType": "User", "password": password});
+  if let Some(email) = email {
+    user["email"] = json!(email);
+  }
+
+  if let Some(id) = sub_matches.get_one::<String>("id") {
+    user["id"] = json!(id);
+  }
+
+  let client = connect(instance).await?;
+  // POST fails on an existing id instead of replacing the user like PUT would
+  let response = client
+    .create_resource(ApiFormat::Aidbox, "User", &user)
+    .await?;
+  if response.status == 409 {
+    return Err(format!(
+      "User/{} already exists. Use rotate-secret to change its password",
+      cell(&user["id"])
+    ));
+  }
+  if !response.is_success() {
+    print_response(response);
+    return Ok(());
+  }
+
+  println!(
+    "{} User/{}",
+    style("Created").green().bold(),
+    cell(response.body.get("id").unwrap_or(&Value::Null))
+  );
+  if generated {
+    println!("Password: {}", password);
+  }
+  Ok(())
+}
+
+async fn rotate_secret(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let id = sub_matches.get_one::<String>("id").unwrap();
+  let (password, generated) = match sub_matches.get_one::<String>("password") {
+    Some(password) => (password.to_string(), false),
+    None => (generate_secret(PASSWORD_LENGTH), true),
+  };
+
+  let client = connect(instance).await?;
+  let response = client
+    .patch_resource(
+      ApiFormat::Aidbox,
+      "User",
+      id,
+      &json!({ "password": password }),
+      PatchKind::MergePatch,
+      None,
+    )
+    .await?;
+  if !response.is_success() {
+    print_response(response);
+    return Ok(());
+  }
+
+  println!(
+    "{} password of User/{}",
+    style("Rotated").green().bold(),
+    id
+  );
+  if generated {
+    println!("Password: {}", password);
+  }
+  Ok(())
+}
+
+async fn disable(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let id = sub_matches.get_one::<String>("id").unwrap();
+  if !sub_matches.get_flag("yes") && !confirm(&format!("Disable User/{}?", id))? {
+    return Ok(());
+  }
+
+  let client = connect(instance).await?;
+  let response = client
+    .patch_resource(
+      ApiFormat::Aidbox,
+      "User",
+      id,
+      &json!({"inactive": true}),
+      PatchKind::MergePatch,
+      None,
+    )
+    .await?;
+  if !response.is_success() {
+    print_response(response);
+    return Ok(());
+  }
+  println!("{} User/{}", style("Disabled").yellow().bold(), id);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_row() {
+    let row = row(&json!({
+      "id": "u1",
+      "email": "a@b.c",
+      "name": {"givenName": "Ann", "familyName": "Lee"},
+      "inactive": true,
+    }));
+    assert_eq!(row[0], "u1");
+    assert_eq!(row[2], "Ann Lee");
+    assert!(row[3].contains("inactive"));
+  }
+}
@@ -0,0 +1,807 @@
+use crate::aidbox::helpers::{collect_files, connect, print_json};
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command, ValueHint};
+use console::style;
+use log::error;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tool_aidbox::rest::ApiFormat;
+use tool_generator::cache::Cache;
+use tool_generator::common::{Element, ElementSchema};
+
+/// Keys every resource may have, whether the schema lists them or not
+const BASE_KEYS: [&str; 5] = [
+  "resourceType",
+  "id",
+  "meta",
+  "extension",
+  "modifierExtension",
+];
+
+/// Longest chain of primitive aliases (`code` -> `string`) followed offline
+const MAX_ALIAS_DEPTH: usize = 10;
+
+/// Value set codes listed in an issue before the rest are counted
+const MAX_LISTED_CODES: usize = 10;
+
+pub fn commands() -> Command {
+  Command::new("validate")
+    .about("Validate local JSON, YAML or NDJSON resources with $validate or the cached types schema")
+    .args(vec![
+      Arg::new("files")
+        .required(true)
+        .num_args(1..)
+        .value_hint(ValueHint::AnyPath)
+        .help("Resource files or directories of them"),
+      Arg::new("offline")
+        .long("offline")
+        .action(SetTrue)
+        .help("Check required keys, value sets, arrays and unknown keys against the schema cached by `generate types`"),
+      Arg::new("fhir")
+        .long("fhir")
+        .action(SetTrue)
+        .conflicts_with("offline")
+        .help("Validate with /fhir instead of Aidbox endpoint"),
+      Arg::new("format")
+        .long("format")
+        .value_parser(["text", "json"])
+        .default_value("text")
+        .help("Print issues as text or as an OperationOutcome"),
+    ])
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  match validate(sub_matches).await {
+    Ok(true) => {},
+    Ok(false) => std::process::exit(1),
+    Err(err) => {
+      error!("{}", err);
+      std::process::exit(1);
+    },
+  }
+}
+
+/// Resource read from a file
+struct Document {
+  file: String,
+  /// Line of the file the resource starts on
+  line: usize,
+  /// Source of the resource, to point issues at lines
+  text: String,
+  resource: Value,
+}
+
+impl Document {
+  fn label(&self) -> String {
+    format!("{}:{}", self.file, self.line)
+  }
+
+  /// File line of the key at `path`, or of the resource start when it is not found
+  fn line_of(&self, path: &[String]) -> usize {
+    self.line + locate(&self.text, path).unwrap_or(1) - 1
+  }
+
+  /// Issue expression. Files that failed to parse have none
+  fn expression(&self, path: &[String]) -> Option<String> {
+    resource_type(&self.resource).map(|it| expression(it, path))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Issue {
+  severity: String,
+  code: String,
+  /// Keys and `[index]` items from the resource root
+  path: Vec<String>,
+  message: String,
+}
+
+impl Issue {
+  fn error(code: &str, path: &[String], message: String) -> Issue {
+    Issue {
+      severity: "error".to_string(),
+      code: code.to_string(),
+      path: path.to_vec(),
+      message,
+    }
+  }
+
+  fn is_error(&self) -> bool {
+    self.severity == "error" || self.severity == "fatal"
+  }
+}
+
+/// FHIR choice key: `valueQuantity` is the `Quantity` branch of the `value` map,
+/// `effectiveDateTime` the `dateTime` branch of `effective`
+fn choice<'f>(fields: &'f HashMap<String, ElementSchema>, key: &str) -> Option<&'f ElementSchema> {
+  key
+    .char_indices()
+    .filter(|(index, char)| *index > 0 && char.is_ascii_uppercase())
+    .find_map(|(index, _)| {
+      let branches = fields.get(&key[..index])?.sub_type.as_ref()?;
+      let branch = &key[index..];
+      branches.get(branch).or_else(|| {
+        let mut chars = branch.chars();
+        let first = chars.next()?.to_ascii_lowercase();
+        branches.get(&std::iter::once(first).chain(chars).collect::<String>())
+      })
+    })
+}
+
+fn child(path: &[String], key: &str) -> Vec<String> {
+  let mut result = path.to_vec();
+  result.push(key.to_string());
+  result
+}
+
+/// FHIRPath like expression: `Patient.name[0].given`
+fn expression(resource_type: &str, path: &[String]) -> String {
+  path.iter().fold(resource_type.to_string(), |result, it| {
+    match it.starts_with('[') {
+      true => format!("{}{}", result, it),
+      false => format!("{}.{}", result, it),
+    }
+  })
+}
+
+/// Path of an `$validate` issue expression. The resource type prefix is dropped
+fn parse_expression(expression: &str, resource_type: &str) -> Vec<String> {
+  let mut path = vec![];
+  for (index, part) in expression.split('.').enumerate() {
+    if index == 0 && part == resource_type {
+      continue;
+    }
+    let mut items = part.split('[');
+    if let Some(key) = items.next().filter(|it| !it.is_empty()) {
+      path.push(key.to_string());
+    }
+    path.extend(items.map(|it| format!("[{}", it)));
+  }
+  path
+}
+
+/// 1-based line of the last key of `path` in JSON or YAML source. Keys are searched in
+/// order, so the line is approximate when a key repeats before the one meant
+fn locate(text: &str, path: &[String]) -> Option<usize> {
+  let mut start = 0;
+  let mut found = None;
+  for key in path.iter().filter(|it| !it.starts_with('[')) {
+    let needles = [format!("\"{}\"", key), format!("{}:", key)];
+    let position = needles
+      .iter()
+      .filter_map(|needle| {
+        text[start..]
+          .find(needle.as_str())
+          .map(|it| (it, needle.len()))
+      })
+      .min()?;
+    found = Some(start + position.0);
+    start += position.0 + position.1;
+  }
+  found.map(|offset| text[..offset].matches('\n').count() + 1)
+}
+
+fn parse_error(file: &str, line: usize, message: String) -> (Document, Vec<Issue>) {
+  (
+    Document {
+      file: file.to_string(),
+      line,
+      text: String::new(),
+      resource: Value::Null,
+    },
+    vec![Issue {
+      severity: "fatal".to_string(),
+      code: "structure".to_string(),
+      path: vec![],
+      message,
+    }],
+  )
+}
+
+/// Resources of a file with the issues found while parsing it
+fn read_documents(path: &Path) -> Result<Vec<(Document, Vec<Issue>)>, String> {
+  let file = path.display().to_string();
+  let text = match std::fs::read_to_string(path) {
+    Ok(it) => it,
+    Err(err) => return Err(format!("Cannot read {}: {}", file, err)),
+  };
+
+  let parsed = match path.extension().and_then(|it| it.to_str()) {
+    Some("ndjson") => {
+      return Ok(
+        text
+          .lines()
+          .enumerate()
+          .filter(|(_, line)| !line.trim().is_empty())
+          .map(|(index, line)| match serde_json::from_str::<Value>(line) {
+            Ok(resource) => (
+              Document {
+                file: file.clone(),
+                line: index + 1,
+                text: line.to_string(),
+                resource,
+              },
+              vec![],
+            ),
+            Err(err) => parse_error(&file, index + 1, err.to_string()),
+          })
+          .collect(),
+      )
+    },
+    Some("yaml") | Some("yml") => serde_yaml::from_str::<Value>(&text).map_err(|err| {
+      let line = err.location().map(|it| it.line()).unwrap_or(1);
+      (line, err.to_string())
+    }),
+    _ => serde_json::from_str::<Value>(&text).map_err(|err| (err.line(), err.to_string())),
+  };
+
+  Ok(vec![match parsed {
+    Ok(resource) => (
+      Document {
+        file,
+        line: 1,
+        text,
+        resource,
+      },
+      vec![],
+    ),
+    Err((line, message)) => parse_error(&file, line, message),
+  }])
+}
+
+fn resource_type(resource: &Value) -> Option<&str> {
+  resource.get("resourceType").and_then(Value::as_str)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Primitive {
+  String,
+  Number,
+  Integer,
+  Boolean,
+}
+
+fn primitive(name: &str) -> Option<Primitive> {
+  match name {
+    "string" | "code" | "uri" | "url" | "canonical" | "id" | "oid" | "uuid" | "markdown"
+    | "base64Binary" | "date" | "dateTime" | "instant" | "time" | "xhtml" | "keyword" => {
+      Some(Primitive::String)
+    },
+    "number" | "decimal" => Some(Primitive::Number),
+    "integer" | "integer64" | "positiveInt" | "unsignedInt" => Some(Primitive::Integer),
+    "boolean" => Some(Primitive::Boolean),
+    _ => None,
+  }
+}
+
+/// Offline checks against the `Element` IR saved by `generate types`
+struct Checker<'a> {
+  types: &'a HashMap<String, Element>,
+  issues: Vec<Issue>,
+}
+
+impl<'a> Checker<'a> {
+  fn new(types: &'a HashMap<String, Element>) -> Self {
+    Checker {
+      types,
+      issues: vec![],
+    }
+  }
+
+  fn check_resource(mut self, resource: &Value) -> Vec<Issue> {
+    match resource_type(resource) {
+      None => self.issues.push(Issue::error(
+        "required",
+        &[],
+        "Missing required key `resourceType`".to_string(),
+      )),
+      Some(name) if !self.types.contains_key(name) => self.issues.push(Issue {
+        severity: "warning".to_string(),
+        code: "not-supported".to_string(),
+        path: vec![],
+        message: format!(
+          "No cached schema for {}. Run `generate types` to refresh the cache",
+          name
+        ),
+      }),
+      Some(name) => self.check_type(name, resource, &[]),
+    }
+    self.issues
+  }
+
+  /// Schema of a type together with the types it extends. Own keys win
+  fn fields(&self, name: &str) -> HashMap<String, ElementSchema> {
+    let mut result = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![name.to_string()];
+    while let Some(name) = pending.pop() {
+      if !seen.insert(name.clone()) {
+        continue;
+      }
+      if let Some(element) = self.types.get(&name) {
+        for (key, schema) in element.schema.iter().flatten() {
+          result
+            .entry(key.to_string())
+            .or_insert_with(|| schema.clone());
+        }
+        pending.extend(element.extends.iter().flatten().rev().cloned());
+      }
+    }
+    result
+  }
+
+  /// Types missing in the cache are not checked
+  fn check_type(&mut self, name: &str, value: &Value, path: &[String]) {
+    let mut name = name.to_string();
+    for _ in 0..MAX_ALIAS_DEPTH {
+      let element = match self.types.get(&name) {
+        Some(it) => it,
+        None => return,
+      };
+      if let Some(values) = element.values.as_ref() {
+        return self.check_code(values, value, path);
+      }
+      match element.plain.as_ref() {
+        Some(plain) => match primitive(plain) {
+          Some(kind) => return self.check_primitive(kind, value, path),
+          None if *plain == name => return,
+          None => name = plain.to_string(),
+        },
+        None => {
+          let fields = self.fields(&name);
+          return self.check_object(&fields, value, path);
+        },
+      }
+    }
+  }
+
+  fn check_object(
+    &mut self,
+    fields: &HashMap<String, ElementSchema>,
+    value: &Value,
+    path: &[String],
+  ) {
+    let object = match value.as_object() {
+      Some(it) => it,
+      None => {
+        return self.issues.push(Issue::error(
+          "structure",
+          path,
+          format!("Expected an object, got {}", value),
+        ))
+      },
+    };
+
+    let mut required: Vec<&String> = fields
+      .iter()
+      .filter(|(key, schema)| schema.require && !object.contains_key(*key))
+      // A required choice is set through one of its typed keys
+      .filter(|(key, _)| {
+        !object
+          .keys()
+          .any(|it| it.starts_with(key.as_str()) && choice(fields, it).is_some())
+      })
+      .map(|(key, _)| key)
+      .collect();
+    required.sort();
+    for key in required {
+      self.issues.push(Issue::error(
+        "required",
+        path,
+        format!("Missing required key `{}`", key),
+      ));
+    }
+
+    // `__` marks a map open to any key
+    let open = fields.contains_key("__");
+    for (key, item) in object {
+      match fields.get(key).or_else(|| choice(fields, key)) {
+        Some(schema) => self.check_field(schema, item, &child(path, key)),
+        None if open || key.starts_with('_') || BASE_KEYS.contains(&key.as_str()) => {},
+        None => self.issues.push(Issue::error(
+          "structure",
+          &child(path, key),
+          format!("Unknown key `{}`", key),
+        )),
+      }
+    }
+  }
+
+  fn check_field(&mut self, schema: &ElementSchema, value: &Value, path: &[String]) {
+    match (schema.is_array, value) {
+      (true, Value::Array(items)) => {
+        for (index, item) in items.iter().enumerate() {
+          self.check_item(schema, item, &child(path, &format!("[{}]", index)));
+        }
+      },
+      (true, _) => self.issues.push(Issue::error(
+        "structure",
+        path,
+        "Expected an array".to_string(),
+      )),
+      (false, Value::Array(..)) => self.issues.push(Issue::error(
+        "structure",
+        path,
+        "Expected a single value, got an array".to_string(),
+      )),
+      (false, _) => self.check_item(schema, value, path),
+    }
+  }
+
+  /// Same precedence as the TypeScript writer: references, value sets, nested maps,
+  /// plain types, then extended types
+  fn check_item(&mut self, schema: &ElementSchema, value: &Value, path: &[String]) {
+    if schema.is_reference {
+      if !value.is_object() {
+        self.issues.push(Issue::error(
+          "structure",
+          path,
+          "Expected a reference object".to_string(),
+        ));
+      }
+    } else if let Some(values) = schema.values.as_ref() {
+      self.check_code(values, value, path);
+    } else if let Some(sub_type) = schema.sub_type.as_ref() {
+      self.check_object(sub_type, value, path);
+    } else if let Some(plain) = schema.plain_type.as_ref() {
+      match primitive(plain) {
+        Some(kind) => self.check_primitive(kind, value, path),
+        None => self.check_type(plain, value, path),
+      }
+    } else if let Some(extends) = schema.extends.as_ref() {
+      match extends.as_slice() {
+        [] => {},
+        [name] => self.check_type(name, value, path),
+        names => {
+          let mut fields = HashMap::new();
+          for name in names {
+            for (key, schema) in self.fields(name) {
+              fields.entry(key).or_insert(schema);
+            }
+          }
+          if !fields.is_empty() {
+            self.check_object(&fields, value, path);
+          }
+        },
+      }
+    }
+  }
+
+  fn check_code(&mut self, values: &[String], value: &Value, path: &[String]) {
+    if value
+      .as_str()
+      .is_some_and(|it| values.iter().any(|code| code == it))
+    {
+      return;
+    }
+    let mut listed = values
+      .iter()
+      .take(MAX_LISTED_CODES)
+      .cloned()
+      .collect::<Vec<_>>()
+      .join(", ");
+    if values.len() > MAX_LISTED_CODES {
+      listed = format!("{} and {} more", listed, values.len() - MAX_LISTED_CODES);
+    }
+    self.issues.push(Issue::error(
+      "code-invalid",
+      path,
+      format!("{} is not one of {}", value, listed),
+    ));
+  }
+
+  fn check_primitive(&mut self, kind: Primitive, value: &Value, path: &[String]) {
+    let (valid, expected) = match kind {
+      Primitive::String => (value.is_string(), "a string"),
+      Primitive::Number => (value.is_number(), "a number"),
+      Primitive::Integer => (value.is_i64() || value.is_u64(), "an integer"),
+      Primitive::Boolean => (value.is_boolean(), "a boolean"),
+    };
+    if !valid {
+      self.issues.push(Issue::error(
+        "value",
+        path,
+        format!("Expected {}, got {}", expected, value),
+      ));
+    }
+  }
+}
+
+fn load_types(instance: &str) -> Result<HashMap<String, Element>, String> {
+  let cache = Cache::default(instance)?;
+  let path = cache.cache_path.join("types_schema.json");
+  let source = match std::fs::read_to_string(&path) {
+    Ok(it) => it,
+    Err(..) => {
+      return Err(format!(
+        "No cached types schema for {}. Run `generate types` first",
+        instance
+      ))
+    },
+  };
+  serde_json::from_str(&source).map_err(|err| format!("Cannot parse {}: {}", path.display(), err))
+}
+
+/// `$validate` issues of a resource. Informational issues (`All OK`) are dropped
+fn outcome_issues(outcome: &Value, resource_type: &str) -> Vec<Issue> {
+  outcome
+    .get("issue")
+    .and_then(Value::as_array)
+    .cloned()
+    .unwrap_or_default()
+    .iter()
+    .filter(|issue| issue.get("severity").and_then(Value::as_str) != Some("information"))
+    .map(|issue| {
+      let text = |key: &str| issue.get(key).and_then(Value::as_str).map(str::to_string);
+      let path = issue
+        .get("expression")
+        .or_else(|| issue.get("location"))
+        .and_then(Value::as_array)
+        .and_then(|it| it.first())
+        .and_then(Value::as_str)
+        .map(|it| parse_expression(it, resource_type))
+        .unwrap_or_default();
+      Issue {
+        severity: text("severity").unwrap_or_else(|| "error".to_string()),
+        code: text("code").unwrap_or_else(|| "invalid".to_string()),
+        path,
+        message: text("diagnostics")
+          .or_else(|| {
+            issue
+              .get("details")
+              .and_then(|it| it.get("text"))
+              .and_then(Value::as_str)
+              .map(str::to_string)
+          })
+          .unwrap_or_default(),
+      }
+    })
+    .collect()
+}
+
+/// Returns `false` when some resource has errors
+async fn validate(sub_matches: &ArgMatches) -> Result<bool, String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let offline = sub_matches.get_flag("offline");
+  let format = match sub_matches.get_flag("fhir") {
+    true => ApiFormat::Fhir,
+    false => ApiFormat::Aidbox,
+  };
+
+  let mut documents = vec![];
+  for path in sub_matches.get_many::<String>("files").unwrap() {
+    for file in collect_files(Path::new(path), &["json", "yaml", "yml", "ndjson"])? {
+      documents.extend(read_documents(&file)?);
+    }
+  }
+  if documents.is_empty() {
+    return Err("No JSON, YAML or NDJSON files to validate".to_string());
+  }
+
+  let types = match offline {
+    true => load_types(instance)?,
+    false => HashMap::new(),
+  };
+  let client = match offline {
+    true => None,
+    false => Some(connect(instance).await?),
+  };
+
+  for (document, issues) in documents.iter_mut() {
+    if !issues.is_empty() {
+      continue;
+    }
+    let resource = &document.resource;
+    match client.as_ref() {
+      None => issues.extend(Checker::new(&types).check_resource(resource)),
+      Some(client) => match resource_type(resource) {
+        None => issues.push(Issue::error(
+          "required",
+          &[],
+          "Missing required key `resourceType`".to_string(),
+        )),
+        Some(name) => {
+          let response = client.validate_resource(format, name, resource).await?;
+          if response.is_operation_outcome() {
+            issues.extend(outcome_issues(&response.body, name));
+          } else if !response.is_success() {
+            return Err(format!(
+              "$validate of {} failed with HTTP {}: {}",
+              document.label(),
+              response.status,
+              response.body
+            ));
+          }
+        },
+      },
+    }
+  }
+
+  let errors = documents
+    .iter()
+    .flat_map(|(_, issues)| issues.iter())
+    .filter(|it| it.is_error())
+    .count();
+  let warnings = documents
+    .iter()
+    .flat_map(|(_, issues)| issues.iter())
+    .filter(|it| !it.is_error())
+    .count();
+
+  match sub_matches.get_one::<String>("format").unwrap().as_str() {
+    "json" => print_json(&outcome(&documents)),
+    _ => {
+      for (document, issues) in documents.iter() {
+        if issues.is_empty() {
+          println!("{}  {}", style(document.label()).dim(), style("ok").green());
+        }
+        for issue in issues {
+          let severity = match issue.is_error() {
+            true => style(issue.severity.as_str()).red().bold(),
+            false => style(issue.severity.as_str()).yellow().bold(),
+          };
+          println!(
+            "{}:{}  {} [{}] {}{}",
+            document.file,
+            document.line_of(&issue.path),
+            severity,
+            issue.code,
+            issue.message,
+            document
+              .expression(&issue.path)
+              .map(|it| format!(" at {}", it))
+              .unwrap_or_default()
+          );
+        }
+      }
+      println!(
+        "{} resources, {} errors, {} warnings",
+        documents.len(),
+        errors,
+        warnings
+      );
+    },
+  }
+  Ok(errors == 0)
+}
+
+/// All issues in one OperationOutcome, with `file:line` in `location`
+fn outcome(documents: &[(Document, Vec<Issue>)]) -> Value {
+  let mut issues: Vec<Value> = documents
+    .iter()
+    .flat_map(|(document, issues)| {
+      issues.iter().map(move |issue| {
+        json!({
+          "severity": issue.severity,
+          "code": issue.code,
+          "diagnostics": issue.message,
+          "expression": document.expression(&issue.path).into_iter().collect::<Vec<_>>(),
+          "location": [format!("{}:{}", document.file, document.line_of(&issue.path))],
+        })
+      })
+    })
+    .collect();
+  if issues.is_empty() {
+    issues
+      .push(json!({"severity": "information", "code": "informational", "diagnostics": "All OK"}));
+  }
+  json!({"resourceType": "OperationOutcome", "issue": issues})
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn field(require: bool, is_array: bool, plain_type: Option<&str>) -> ElementSchema {
+    ElementSchema {
+      extends: None,
+      is_array,
+      is_reference: false,
+      require,
+      description: None,
+      sub_type: None,
+      plain_type: plain_type.map(str::to_string),
+      values: None,
+      cardinality: None,
+      source: None,
+    }
+  }
+
+  fn types() -> HashMap<String, Element> {
+    let mut status = field(true, false, None);
+    status.values = Some(vec!["final".to_string(), "preliminary".to_string()]);
+    let mut effective = field(true, false, None);
+    effective.sub_type = Some(HashMap::from([
+      (
+        "dateTime".to_string(),
+        field(false, false, Some("dateTime")),
+      ),
+      ("Period".to_string(), field(false, false, Some("Period"))),
+    ]));
+    let schema = HashMap::from([
+      ("status".to_string(), status),
+      ("note".to_string(), field(false, true, Some("string"))),
+      ("value".to_string(), field(false, false, Some("integer"))),
+      ("effective".to_string(), effective),
+    ]);
+    HashMap::from([(
+      "Observation".to_string(),
+      Element {
+        is_rpc: false,
+        rpc_method: None,
+        description: None,
+        profile: false,
+        extends: None,
+        plain: None,
+        schema: Some(schema),
+        values: None,
+        source: None,
+      },
+    )])
+  }
+
+  #[test]
+  fn test_check_resource() {
+    let types = types();
+    let issues = Checker::new(&types).check_resource(&json!({
+      "resourceType": "Observation",
+      "id": "1",
+      "note": "text",
+      "value": "7",
+      "colour": "red"
+    }));
+    let found: Vec<(&str, String)> = issues
+      .iter()
+      .map(|it| (it.code.as_str(), expression("Observation", &it.path)))
+      .collect();
+    assert_eq!(found.len(), 5);
+    assert!(found.contains(&("required", "Observation".to_string())));
+    assert!(found.contains(&("structure", "Observation.note".to_string())));
+    assert!(found.contains(&("value", "Observation.value".to_string())));
+    assert!(found.contains(&("structure", "Observation.colour".to_string())));
+
+    let issues = Checker::new(&types).check_resource(&json!({
+      "resourceType": "Observation",
+      "status": "final",
+      "effectiveDateTime": 2020,
+      "effectivePeriod": {"start": "2020"},
+      "effectiveBoolean": true
+    }));
+    let found: Vec<(&str, String)> = issues
+      .iter()
+      .map(|it| (it.code.as_str(), expression("Observation", &it.path)))
+      .collect();
+    assert_eq!(
+      found,
+      vec![
+        ("value", "Observation.effectiveDateTime".to_string()),
+        ("structure", "Observation.effectiveBoolean".to_string()),
+      ]
+    );
+
+    let issues = Checker::new(&types).check_resource(&json!({
+      "resourceType": "Observation",
+      "status": "done",
+      "effectiveDateTime": "2020-01-01",
+      "note": ["a", 1]
+    }));
+    let codes: Vec<&str> = issues.iter().map(|it| it.code.as_str()).collect();
+    // Issues follow the key order of the resource
+    assert_eq!(codes, vec!["code-invalid", "value"]);
+    assert_eq!(
+      expression("Observation", &issues[1].path),
+      "Observation.note[1]"
+    );
+  }
+
+  #[test]
+  fn test_locate() {
+    let text = "{\n  \"name\": [{\n    \"given\": [\"A\"]\n  }],\n  \"given\": 1\n}";
+    let path = parse_expression("Patient.name[0].given", "Patient");
+    assert_eq!(path, vec!["name", "[0]", "given"]);
+    assert_eq!(locate(text, &path), Some(3));
+    assert_eq!(locate("name:\n  - given: [A]\n", &path), Some(2));
+    assert_eq!(locate(text, &["birthDate".to_string()]), None);
+  }
+}
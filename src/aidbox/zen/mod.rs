@@ -0,0 +1,270 @@
+use crate::aidbox::helpers::{connect, print_json, render_table};
+use clap::ArgAction::SetTrue;
+use clap::{Arg, ArgMatches, Command};
+use log::error;
+use regex::Regex;
+use serde_json::{json, Value};
+use tool_aidbox::internal_namespaces;
+
+/// Widest line of pretty printed EDN before maps and vectors are split
+const EDN_WIDTH: usize = 100;
+
+fn format_arg(values: [&'static str; 2]) -> Arg {
+  Arg::new("format")
+    .long("format")
+    .value_parser(values)
+    .default_value(values[0])
+    .help("Output format")
+}
+
+pub fn commands() -> Command {
+  Command::new("zen")
+    .about("Browse zen namespaces and symbols loaded in the box")
+    .arg_required_else_help(true)
+    .subcommand_required(true)
+    .subcommand(
+      Command::new("namespaces")
+        .about("List namespaces")
+        .args(vec![
+          Arg::new("filter")
+            .long("filter")
+            .help("Only namespaces matching the regex. Example: ^aidbox"),
+          Arg::new("hide-internal")
+            .long("hide-internal")
+            .action(SetTrue)
+            .help("Hide namespaces skipped by type generation (zen, FHIR packages, value sets)"),
+        ]),
+    )
+    .subcommand(
+      Command::new("symbols")
+        .about("List symbols of a namespace with their tags")
+        .args(vec![
+          Arg::new("namespace")
+            .required(true)
+            .help("Namespace. Example: aidbox"),
+          Arg::new("tag")
+            .long("tag")
+            .help("Only symbols with the tag. Example: zen/schema"),
+          format_arg(["table", "json"]),
+        ]),
+    )
+    .subcommand(
+      Command::new("show")
+        .about(
+          "Print symbol definition. EDN is rebuilt from JSON for reading, sets print as vectors",
+        )
+        .args(vec![
+          Arg::new("symbol")
+            .required(true)
+            .help("Symbol. Example: aidbox/Resource"),
+          format_arg(["edn", "json"]),
+        ]),
+    )
+}
+
+pub async fn sub_matches(sub_matches: &ArgMatches) {
+  let result = match sub_matches.subcommand().unwrap_or(("help", sub_matches)) {
+    ("namespaces", sub_matches) => namespaces(sub_matches).await,
+    ("symbols", sub_matches) => symbols(sub_matches).await,
+    ("show", sub_matches) => show(sub_matches).await,
+    (name, _) => {
+      unreachable!("Unsupported subcommand `{}`", name)
+    },
+  };
+
+  if let Err(err) = result {
+    error!("{}", err);
+    std::process::exit(1);
+  }
+}
+
+async fn namespaces(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let filter = match sub_matches.get_one::<String>("filter") {
+    Some(pattern) => match Regex::new(pattern) {
+      Ok(it) => Some(it),
+      Err(err) => return Err(format!("Invalid filter: {}", err)),
+    },
+    None => None,
+  };
+  let internal = internal_namespaces();
+  let hide_internal = sub_matches.get_flag("hide-internal");
+
+  let client = connect(instance).await?;
+  let mut namespaces = client
+    .get_namespaces()
+    .await
+    .map_err(|err| err.to_string())?;
+  namespaces.sort();
+
+  for namespace in namespaces
+    .iter()
+    .filter(|it| filter.as_ref().is_none_or(|filter| filter.is_match(it)))
+    .filter(|it| !hide_internal || !internal.is_match(it))
+  {
+    println!("{}", namespace);
+  }
+  Ok(())
+}
+
+async fn symbols(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let namespace = sub_matches.get_one::<String>("namespace").unwrap();
+  let tag = sub_matches.get_one::<String>("tag");
+
+  let client = connect(instance).await?;
+  let mut items = client
+    .get_namespace_items(namespace)
+    .await
+    .map_err(|err| err.to_string())?;
+  if items.is_empty() {
+    return Err(format!(
+      "No symbols in {}. Check the namespace name",
+      namespace
+    ));
+  }
+  items.retain(|item| tag.is_none_or(|tag| item.tag_names().contains(tag)));
+  items.sort_by(|a, b| a.name.cmp(&b.name));
+
+  match sub_matches.get_one::<String>("format").unwrap().as_str() {
+    "json" => print_json(&Value::Array(
+      items
+        .iter()
+        .map(|item| json!({"symbol": format!("{}/{}", namespace, item.name), "tags": item.tag_names()}))
+        .collect(),
+    )),
+    _ => {
+      let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|item| {
+          vec![
+            format!("{}/{}", namespace, item.name),
+            item.tag_names().join(", "),
+          ]
+        })
+        .collect();
+      for line in render_table(&["symbol".to_string(), "tags".to_string()], &rows) {
+        println!("{}", line);
+      }
+    },
+  }
+  Ok(())
+}
+
+async fn show(sub_matches: &ArgMatches) -> Result<(), String> {
+  let instance = sub_matches.get_one::<String>("instance").unwrap();
+  let symbol = sub_matches.get_one::<String>("symbol").unwrap();
+
+  let client = connect(instance).await?;
+  let model = match client.read_symbol(symbol).await {
+    Ok(it) => Value::Object(it),
+    Err(err) => return Err(format!("Cannot read {}: {}", symbol, err)),
+  };
+
+  match sub_matches.get_one::<String>("format").unwrap().as_str() {
+    "json" => print_json(&model),
+    _ => println!("{}", to_edn(&model, 0)),
+  }
+  Ok(())
+}
+
+fn is_edn_name(value: &str) -> bool {
+  !value.is_empty()
+    && !value.starts_with(|it: char| it.is_ascii_digit())
+    && value
+      .chars()
+      .all(|it| it.is_alphanumeric() || "*+!_?<>=.-/".contains(it))
+}
+
+/// `zen/schema`, `aidbox.rest/op`. JSON loses the symbol/string distinction, so slashed
+/// identifiers are taken as symbols
+fn is_symbol(value: &str) -> bool {
+  match value.split_once('/') {
+    Some((ns, name)) => {
+      !ns.is_empty() && !name.is_empty() && !name.contains('/') && is_edn_name(value)
+    },
+    None => false,
+  }
+}
+
+fn edn_key(key: &str) -> String {
+  match is_edn_name(key) {
+    true => format!(":{}", key),
+    false => Value::String(key.to_string()).to_string(),
+  }
+}
+
+fn edn_compact(value: &Value) -> String {
+  match value {
+    Value::Null => "nil".to_string(),
+    Value::String(text) if is_symbol(text) => text.to_string(),
+    Value::Array(items) => format!(
+      "[{}]",
+      items.iter().map(edn_compact).collect::<Vec<_>>().join(" ")
+    ),
+    Value::Object(map) => format!(
+      "{{{}}}",
+      map
+        .iter()
+        .map(|(key, value)| format!("{} {}", edn_key(key), edn_compact(value)))
+        .collect::<Vec<_>>()
+        .join(" ")
+    ),
+    other => other.to_string(),
+  }
+}
+
+/// EDN with nested maps and vectors split over lines once they don't fit.
+/// The box returns symbols as JSON, so sets like `#{zen/schema}` come out as vectors
+/// and the output can't be loaded back as the same symbol
+fn to_edn(value: &Value, column: usize) -> String {
+  let compact = edn_compact(value);
+  if column + compact.len() <= EDN_WIDTH {
+    return compact;
+  }
+
+  match value {
+    Value::Object(map) if !map.is_empty() => {
+      let lines: Vec<String> = map
+        .iter()
+        .map(|(key, value)| {
+          let key = edn_key(key);
+          let value = to_edn(value, column + 1 + key.len() + 1);
+          format!("{} {}", key, value)
+        })
+        .collect();
+      format!(
+        "{{{}}}",
+        lines.join(&format!("\n{}", " ".repeat(column + 1)))
+      )
+    },
+    Value::Array(items) if !items.is_empty() => {
+      let lines: Vec<String> = items.iter().map(|it| to_edn(it, column + 1)).collect();
+      format!("[{}]", lines.join(&format!("\n{}", " ".repeat(column + 1))))
+    },
+    _ => compact,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_to_edn() {
+    let model = json!({
+      "zen/tags": ["zen/schema"],
+      "type": "zen/map",
+      "keys": {"name": {"type": "zen/string", "zen/desc": "Name of a/b thing"}},
+      "require": null
+    });
+    assert_eq!(
+      edn_compact(&model["keys"]),
+      "{:name {:type zen/string :zen/desc \"Name of a/b thing\"}}"
+    );
+    assert_eq!(
+      to_edn(&model, 0),
+      "{:zen/tags [zen/schema]\n :type zen/map\n :keys {:name {:type zen/string :zen/desc \"Name of a/b thing\"}}\n :require nil}"
+    );
+  }
+}
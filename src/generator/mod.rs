@@ -44,6 +44,7 @@ pub fn commands() -> Command {
                   "schema",
                   "valuesets",
                   "symbols",
+                  "search_symbols",
                   "intermediate_types",
                 ]),
             ]),
@@ -106,7 +107,7 @@ pub async fn sub_matches(sub_matches: &ArgMatches) {
           for item in result.items {
             println!(
               "{0: <30} {1} {2: <10}",
-              item.path.split('/').last().unwrap(),
+              item.path.split('/').next_back().unwrap(),
               Emoji("▶️", "->"),
               HumanBytes(item.size)
             );
@@ -1,5 +1,6 @@
 use itertools::Itertools;
 use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
 use std::path::PathBuf;
 
@@ -12,9 +13,10 @@ use log::error;
 use tool_aidbox::BoxClient;
 use tool_config::read_exclude_config;
 use tool_generator::cache::Cache;
-use tool_generator::common::{deep_merge_element_schema, Element};
-use tool_generator::reader::read_schema;
-use tool_generator::types::typescript::{write_typescript_types, WriterConfig};
+use tool_generator::collisions::{find_collisions, prefix_renames, CollisionStrategy, NameOrigin};
+use tool_generator::common::{deep_merge_element_schema, Element, SearchParam};
+use tool_generator::reader::{read_schema, search_read};
+use tool_generator::types::typescript::{write_typescript_types, PrimitiveMode, WriterConfig};
 
 pub fn commands() -> Command {
   Command::new("types").about("Types generating").args(vec![
@@ -39,7 +41,24 @@ pub fn commands() -> Command {
     Arg::new("max-values").long("max-values").help("Maximum count for values in type like status in Encounter")
         .default_value("10").value_parser(value_parser!(usize)),
     Arg::new("collapse-values").long("collapse-values")
-        .action(SetTrue).help("Collapse big values just into `string`")
+        .action(SetTrue).help("Collapse big values just into `string`"),
+    Arg::new("type-guards").long("type-guards")
+        .action(SetTrue).help("Emit `is<Resource>` type guards, `assertResourceType` and `ResourceTypes`. Output must be a `.ts` file"),
+    Arg::new("search-params").long("search-params")
+        .action(SetTrue).help("Emit `SearchParams<T>` from `zen.fhir/search` symbols"),
+    Arg::new("primitives").long("primitives")
+        .help("How to emit date, dateTime, integer and id primitives")
+        .value_parser(["plain", "branded", "template"])
+        .default_value("plain"),
+    Arg::new("primitive-validators").long("primitive-validators")
+        .action(SetTrue).help("Write `<output>.validators.ts` with parsers for the refined primitives. Needs --primitives branded or template"),
+    Arg::new("on-collision").long("on-collision")
+        .help("What to do when unrelated symbols map to the same type name")
+        .value_parser(["merge", "prefix", "error"])
+        .default_value("merge"),
+    Arg::new("rename").long("rename")
+        .help("JSON map of symbol to type name. Example: {\"my.app/schema\": \"MyApp\"}")
+        .value_hint(ValueHint::FilePath),
   ])
 }
 #[allow(clippy::too_many_lines)]
@@ -49,6 +68,25 @@ pub async fn generate(
   instance_tag: &str,
 ) -> Result<(), String> {
   let output = sub_matches.get_one::<String>("output").unwrap();
+  let primitives = match sub_matches
+    .get_one::<String>("primitives")
+    .unwrap()
+    .as_str()
+  {
+    "branded" => PrimitiveMode::Branded,
+    "template" => PrimitiveMode::Template,
+    _ => PrimitiveMode::Plain,
+  };
+  let primitive_validators = sub_matches.get_flag("primitive-validators");
+  // The validators import FhirDate, FhirDateTime, FhirInteger and Id, which plain doesn't declare
+  if primitive_validators && primitives == PrimitiveMode::Plain {
+    return Err("--primitive-validators needs --primitives branded or template".to_string());
+  }
+  let type_guards = sub_matches.get_flag("type-guards");
+  // Guards are runtime code, a `.d.ts` file can't hold them
+  if type_guards && (!output.ends_with(".ts") || output.ends_with(".d.ts")) {
+    return Err("--type-guards needs a `.ts` output file".to_string());
+  }
 
   if let Err(e) = File::create(PathBuf::from(output.clone())) {
     return Err(format!(
@@ -62,6 +100,26 @@ pub async fn generate(
   let cache_init = Cache::default(instance_tag);
   let collapse = sub_matches.get_one::<bool>("collapse-values").unwrap();
   let max_values = sub_matches.get_one::<usize>("max-values").unwrap();
+  let with_search_params = sub_matches.get_flag("search-params");
+  let on_collision = match sub_matches
+    .get_one::<String>("on-collision")
+    .unwrap()
+    .as_str()
+  {
+    "prefix" => CollisionStrategy::Prefix,
+    "error" => CollisionStrategy::Error,
+    _ => CollisionStrategy::Merge,
+  };
+  let renames = match sub_matches.get_one::<String>("rename") {
+    Some(path) => match fs::read_to_string(path) {
+      Ok(json) => match serde_json::from_str::<HashMap<String, String>>(&json) {
+        Ok(it) => it,
+        Err(err) => return Err(format!("Error while parsing rename map: {}", err)),
+      },
+      Err(err) => return Err(format!("Error while read rename map: {}", err)),
+    },
+    None => HashMap::new(),
+  };
 
   let mut cache = match cache_init {
     Err(err) => return Err(err),
@@ -94,66 +152,111 @@ pub async fn generate(
     pb_for_logger.clone().println(message);
   }));
 
-  {
-    let types = read_schema(
-      symbols.clone(),
-      instance,
-      &mut cache,
-      include_profile.cloned(),
-      exclude_config,
-      log_handler,
-    )
-    .await;
-
-    pin_mut!(types);
-
-    while let Some(res) = types.next().await {
-      let new_element = res.element;
-      let new_element_name = res.name;
-      match result.get(new_element_name.as_str()) {
-        Some(old_element) => {
-          let merged_types = match new_element.clone().schema {
-            Some(el) => match old_element.clone().schema {
-              Some(old) => Some(deep_merge_element_schema(old, el)),
-              None => Some(el),
-            },
-            None => old_element.schema.clone(),
-          };
-          result.insert(
-            new_element_name,
-            Element {
-              is_rpc: new_element.is_rpc,
-              rpc_method: new_element.rpc_method,
-              description: new_element.description.clone(),
-              profile: new_element.profile,
-              extends: match old_element.extends.clone() {
-                Some(it) => match new_element.extends.clone() {
-                  Some(ri) => {
-                    let extends: Vec<_> = [it.as_slice(), ri.as_slice()]
-                      .concat()
-                      .iter()
-                      .unique()
-                      .map(String::to_string)
-                      .collect();
-
-                    Some(extends)
+  cache.renames = renames;
+  let mut prefixed = false;
+
+  loop {
+    let mut origins: HashMap<String, Vec<NameOrigin>> = HashMap::new();
+    {
+      let types = read_schema(
+        symbols.clone(),
+        instance.clone(),
+        &mut cache,
+        include_profile.cloned(),
+        exclude_config.clone(),
+        log_handler,
+      )
+      .await;
+
+      pin_mut!(types);
+
+      while let Some(res) = types.next().await {
+        let new_element = res.element;
+        let new_element_name = res.name;
+        origins
+          .entry(new_element_name.clone())
+          .or_default()
+          .push(NameOrigin {
+            symbol: res.symbol.clone(),
+            explicit: res.explicit_name,
+          });
+        match result.get(new_element_name.as_str()) {
+          Some(old_element) => {
+            let merged_types = match new_element.clone().schema {
+              Some(el) => match old_element.clone().schema {
+                Some(old) => Some(deep_merge_element_schema(old, el)),
+                None => Some(el),
+              },
+              None => old_element.schema.clone(),
+            };
+            result.insert(
+              new_element_name,
+              Element {
+                is_rpc: new_element.is_rpc,
+                rpc_method: new_element.rpc_method,
+                description: new_element.description.clone(),
+                profile: new_element.profile,
+                extends: match old_element.extends.clone() {
+                  Some(it) => match new_element.extends.clone() {
+                    Some(ri) => {
+                      let extends: Vec<_> = [it.as_slice(), ri.as_slice()]
+                        .concat()
+                        .iter()
+                        .unique()
+                        .map(String::to_string)
+                        .collect();
+
+                      Some(extends)
+                    },
+                    None => old_element.extends.clone(),
                   },
                   None => old_element.extends.clone(),
                 },
-                None => old_element.extends.clone(),
+                values: old_element.values.clone(),
+                plain: old_element.plain.clone(),
+                schema: merged_types,
+                source: new_element.source.or_else(|| old_element.source.clone()),
               },
-              values: old_element.values.clone(),
-              plain: old_element.plain.clone(),
-              schema: merged_types,
-            },
-          );
-        },
-        None => {
-          result.insert(new_element_name, new_element);
-        },
+            );
+          },
+          None => {
+            result.insert(new_element_name, new_element);
+          },
+        }
+        pb.inc(1);
+        pb.set_message(format!("{} symbol processed", res.symbol));
       }
-      pb.inc(1);
-      pb.set_message(format!("{} symbol processed", res.symbol));
+    }
+
+    let collisions = find_collisions(&origins);
+    for collision in collisions.iter() {
+      pb.println(collision.report());
+    }
+
+    match on_collision {
+      CollisionStrategy::Error if !collisions.is_empty() => {
+        pb.abandon();
+        return Err(format!(
+          "{} name collision(s) found. Use --rename or --on-collision prefix",
+          collisions.len()
+        ));
+      },
+      CollisionStrategy::Prefix if !collisions.is_empty() && !prefixed => {
+        prefixed = true;
+        cache.renames.extend(prefix_renames(&collisions));
+        result.clear();
+        pb.reset();
+        pb.println("Re-reading symbols with namespace prefixed names");
+      },
+      // Prefixes didn't separate them, merging would mix unrelated types
+      CollisionStrategy::Prefix if !collisions.is_empty() => {
+        pb.abandon();
+        return Err(format!(
+          "{} name collision(s) remain after prefixing namespaces. Use --rename",
+          collisions.len()
+        ));
+      },
+      _ => break,
     }
   }
   pb.finish_with_message(format!(
@@ -163,6 +266,28 @@ pub async fn generate(
     (pb.elapsed().as_secs_f64() * 100f64).floor() / 100f64
   ));
 
+  let mut search_params: Vec<SearchParam> = vec![];
+
+  if with_search_params {
+    let search_symbols = match instance
+      .load_search_symbols(cache.cache_path.clone(), &exclude_config)
+      .await
+    {
+      Ok(it) => it,
+      Err(e) => return Err(e.to_string()),
+    };
+
+    log::info!("Start processing {} search symbols", search_symbols.len());
+
+    for symbol in search_symbols.iter() {
+      match search_read(&instance, &mut cache, symbol).await {
+        Ok(Some(param)) => search_params.push(param),
+        Ok(None) => {},
+        Err(e) => log::warn!("Skip search symbol {}: {}", symbol, e),
+      }
+    }
+  }
+
   match cache.save_types_schema(&result) {
     Ok(..) | Err(..) => {},
   }
@@ -175,11 +300,15 @@ pub async fn generate(
   match sub_matches.get_one::<String>("target").unwrap().as_str() {
     "typescript" => write_typescript_types(
       result,
+      search_params,
       WriterConfig {
         fhir,
         output: output.clone(),
         max_values: max_values.to_owned(),
         collapse_values: collapse.to_owned(),
+        type_guards,
+        primitives,
+        primitive_validators,
       },
     ),
     unknown => {
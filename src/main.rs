@@ -11,7 +11,11 @@ use std::io::Write;
 
 #[tokio::main]
 async fn main() {
-  setup_panic!();
+  // `setup_panic!` still expands to the pre-1.81 `PanicInfo` alias.
+  #[allow(deprecated)]
+  {
+    setup_panic!();
+  }
 
   let mut app = Command::new("aidbox-tool")
     .about("Aidbox mutlitool")